@@ -0,0 +1,294 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use aws_sdk_bedrockruntime::primitives::Blob;
+use aws_sdk_bedrockruntime::types::{ContentBlock, DocumentBlock, DocumentFormat, DocumentSource};
+
+use crate::chat::{ConversationHistory, SerializableContentBlock};
+use crate::constants::{MAX_DOCUMENTS, MAX_DOCUMENT_BYTES};
+
+// NOTE: Documents attached to the chat (`/attach <path>` or `--attach`). They are sent along with
+// the next question as document blocks, and stay in the conversation (and in saved chats) from
+// then on. Bedrock limits how big a document can be and how many of them a conversation can
+// have, so that is checked here, before anything is sent.
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    // the name the model sees, it has to be unique in the conversation
+    pub name: String,
+    pub format: DocumentFormat,
+    pub bytes: Vec<u8>,
+}
+
+impl Attachment {
+    pub fn load(path: &Path) -> Result<Attachment, anyhow::Error> {
+        let format = document_format(path)?;
+        let size = fs::metadata(path)
+            .map_err(|e| anyhow!("Unable to read `{}`: {}", path.display(), e))?
+            .len();
+        if size > MAX_DOCUMENT_BYTES {
+            bail!(
+                "`{}` is {:.1} MB, documents can be at most {:.1} MB",
+                path.display(),
+                size as f64 / 1_000_000.0,
+                MAX_DOCUMENT_BYTES as f64 / 1_000_000.0
+            );
+        }
+        let bytes = fs::read(path)?;
+        if bytes.is_empty() {
+            bail!("`{}` is empty", path.display());
+        }
+        Ok(Attachment {
+            name: document_name(path),
+            format,
+            bytes,
+        })
+    }
+
+    // ie `notes (md, 1.2 KB)`
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ({}, {:.1} KB)",
+            self.name,
+            self.format.as_str(),
+            self.bytes.len() as f64 / 1000.0
+        )
+    }
+
+    pub fn content_block(&self) -> ContentBlock {
+        ContentBlock::Document(
+            DocumentBlock::builder()
+                .format(self.format.clone())
+                .name(&self.name)
+                .source(DocumentSource::Bytes(Blob::new(self.bytes.clone())))
+                .build()
+                .expect("format, name and source are set"),
+        )
+    }
+}
+
+// The documents that go with the next question
+#[derive(Debug, Default)]
+pub struct Attachments {
+    pending: Vec<Attachment>,
+}
+
+impl Attachments {
+    pub fn new() -> Self {
+        Attachments::default()
+    }
+
+    // Loads the document, if the conversation still has room for it
+    pub fn attach(
+        &mut self,
+        path: &Path,
+        conversation_history: &ConversationHistory,
+    ) -> Result<&Attachment, anyhow::Error> {
+        let mut names = document_names(conversation_history);
+        names.extend(self.pending.iter().map(|a| a.name.clone()));
+        if names.len() >= MAX_DOCUMENTS {
+            bail!(
+                "A conversation can have at most {} documents, clear it with /c to attach more",
+                MAX_DOCUMENTS
+            );
+        }
+        let mut attachment = Attachment::load(path)?;
+        // two documents with the same name are refused by Bedrock
+        let name = attachment.name.clone();
+        let mut n = 1;
+        while names.contains(&attachment.name) {
+            n += 1;
+            attachment.name = format!("{} ({})", name, n);
+        }
+        self.pending.push(attachment);
+        Ok(self.pending.last().expect("just pushed"))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn pending(&self) -> &[Attachment] {
+        &self.pending
+    }
+
+    pub fn take(&mut self) -> Vec<Attachment> {
+        std::mem::take(&mut self.pending)
+    }
+
+    // Puts the documents of a question that failed back, so they go with the next one
+    pub fn restore(&mut self, attachments: Vec<Attachment>) {
+        let pending = std::mem::replace(&mut self.pending, attachments);
+        self.pending.extend(pending);
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+// The names of the documents already in the conversation
+fn document_names(conversation_history: &ConversationHistory) -> Vec<String> {
+    conversation_history
+        .messages
+        .iter()
+        .flatten()
+        .flat_map(|m| &m.content)
+        .filter_map(|block| match block {
+            SerializableContentBlock::Document { name, .. } => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn document_format(path: &Path) -> Result<DocumentFormat, anyhow::Error> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let format = match extension.as_str() {
+        "pdf" => DocumentFormat::Pdf,
+        "csv" => DocumentFormat::Csv,
+        "doc" => DocumentFormat::Doc,
+        "docx" => DocumentFormat::Docx,
+        "xls" => DocumentFormat::Xls,
+        "xlsx" => DocumentFormat::Xlsx,
+        "html" | "htm" => DocumentFormat::Html,
+        "txt" => DocumentFormat::Txt,
+        "md" | "markdown" => DocumentFormat::Md,
+        _ => bail!(
+            "`{}` is not a pdf, csv, doc, docx, xls, xlsx, html, txt or md document",
+            path.display()
+        ),
+    };
+    Ok(format)
+}
+
+// NOTE: Document names can only have letters, numbers, single spaces, hyphens, parentheses and
+// square brackets. So `q3_report.final.pdf` is sent as `q3-report-final`.
+fn document_name(path: &Path) -> String {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default();
+    let name: String = stem
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || "-()[]".contains(c) => c,
+            c if c.is_whitespace() => ' ',
+            _ => '-',
+        })
+        .collect();
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+    if name.is_empty() {
+        "document".to_string()
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::SerializableMessage;
+    use crate::utils::test_dir;
+    use aws_sdk_bedrockruntime::types::{ConversationRole, Message};
+    use std::path::PathBuf;
+
+    fn write_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let path = test_dir(&format!("attach-{}", name)).join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn documents_are_named_and_checked() {
+        assert_eq!(
+            document_name(Path::new("q3_report.final.pdf")),
+            "q3-report-final"
+        );
+        assert_eq!(
+            document_name(Path::new("My  Notes [v2].md")),
+            "My Notes [v2]"
+        );
+        assert_eq!(document_name(Path::new("ünï.csv")), "-n-");
+        assert_eq!(
+            document_format(Path::new("a.DOCX")).unwrap(),
+            DocumentFormat::Docx
+        );
+        assert!(document_format(Path::new("image.png")).is_err());
+        assert!(document_format(Path::new("README")).is_err());
+
+        let empty = write_file("empty.txt", b"");
+        assert!(Attachment::load(&empty).is_err());
+        let big = write_file("big.csv", &vec![b'a'; MAX_DOCUMENT_BYTES as usize + 1]);
+        assert!(Attachment::load(&big)
+            .unwrap_err()
+            .to_string()
+            .contains("at most 4.5 MB"));
+        assert!(Attachment::load(Path::new("/nope/missing.pdf")).is_err());
+    }
+
+    #[test]
+    fn attachments_respect_the_conversation() {
+        let notes = write_file("notes.md", b"# Notes\n- one");
+        let mut attachments = Attachments::new();
+        let mut history = ConversationHistory::new(None, None, None, None);
+
+        // the same file twice gets two names
+        attachments.attach(&notes, &history).unwrap();
+        let second = attachments.attach(&notes, &history).unwrap();
+        assert_eq!(second.name, "notes (2)");
+        assert_eq!(second.describe(), "notes (2) (md, 0.0 KB)");
+
+        // once sent, the documents count against the conversation
+        let content: Vec<ContentBlock> = attachments
+            .take()
+            .iter()
+            .map(Attachment::content_block)
+            .chain([ContentBlock::Text("Summarize these".into())])
+            .collect();
+        let message = Message::builder()
+            .role(ConversationRole::User)
+            .set_content(Some(content))
+            .build()
+            .unwrap();
+        let message = SerializableMessage::from(message);
+        match &message.content[0] {
+            SerializableContentBlock::Document { format, name, .. } => {
+                assert_eq!((format.as_str(), name.as_str()), ("md", "notes"))
+            }
+            other => panic!("expected a document, got {:?}", other),
+        }
+        history.messages = Some(vec![message]);
+        assert!(attachments.is_empty());
+        assert_eq!(
+            attachments.attach(&notes, &history).unwrap().name,
+            "notes (3)"
+        );
+        attachments.attach(&notes, &history).unwrap();
+        attachments.attach(&notes, &history).unwrap();
+        let err = attachments.attach(&notes, &history).unwrap_err();
+        assert!(err.to_string().contains("at most 5 documents"));
+
+        // a failed question puts its documents back in front
+        let taken = attachments.take();
+        attachments
+            .attach(&notes, &ConversationHistory::new(None, None, None, None))
+            .unwrap();
+        attachments.restore(taken);
+        let names: Vec<&str> = attachments
+            .pending()
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(names, ["notes (3)", "notes (4)", "notes (5)", "notes"]);
+
+        // clearing the chat with /c makes room for new documents
+        let history = history.clear();
+        attachments.clear();
+        assert_eq!(attachments.attach(&notes, &history).unwrap().name, "notes");
+    }
+}
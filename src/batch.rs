@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use aws_sdk_bedrockruntime::types::ContentBlock;
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::attachments::Attachments;
+use crate::chat::ConversationHistory;
+use crate::models::backend::ModelBackend;
+use crate::models::catalog::resolve_model_id;
+use crate::models::converse::call_converse_with_usage;
+use crate::models::converse_stream::system_blocks;
+use crate::models::params::ModelParams;
+use crate::usage::{SessionUsage, TurnRecord};
+use crate::utils::BedrustConfig;
+
+// NOTE: Batch mode (`--batch <FILE>`). Every line of the file is a JSON prompt, ie
+// `{"id": "q1", "prompt": "Explain IAM roles", "model": "sonnet", "params": {"temperature": 0.2}}`.
+// The prompts are sent a few at a time with Converse, and every answer (or error) is written to
+// the results file as soon as it is done, so Ctrl-C loses at most the prompts that were running.
+// Running the same batch again skips the prompts that already have an answer, which picks up an
+// interrupted batch and retries the ones that failed.
+
+// One line of the batch file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchRecord {
+    // what the result is matched with, `line-<n>` when not set
+    #[serde(default)]
+    pub id: Option<String>,
+    pub prompt: String,
+    #[serde(default)]
+    pub system: Option<String>,
+    // model id or alias, when not set the one from `-m` (or `default_model`)
+    #[serde(default)]
+    pub model: Option<String>,
+    // on top of the parameters configured for the model
+    #[serde(default)]
+    pub params: Option<ModelParams>,
+    // documents sent with the prompt, relative to the batch file
+    #[serde(default)]
+    pub attachments: Vec<PathBuf>,
+}
+
+// One line of the results file. The results are written as they come, not in the batch order.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct BatchResult {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub answer: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TurnRecord>,
+}
+
+// The id of a line, with its prompt (or why it could not be read)
+type BatchLine = (String, Result<BatchRecord, String>);
+
+// How the batch went
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    pub answered: usize,
+    pub failed: usize,
+    // answered by an earlier run
+    pub skipped: usize,
+    pub usage: SessionUsage,
+}
+
+// `prompts.jsonl` -> `prompts.results.jsonl`
+pub fn default_output(input: &Path) -> PathBuf {
+    input.with_extension("results.jsonl")
+}
+
+pub async fn run_batch(
+    backend: &dyn ModelBackend,
+    config: &BedrustConfig,
+    default_model: Option<&str>,
+    input: &Path,
+    output: &Path,
+    concurrency: usize,
+) -> Result<BatchSummary, anyhow::Error> {
+    let records = read_batch(input)?;
+    let answered = answered_ids(output)?;
+    let total = records.len();
+    let pending: Vec<BatchLine> = records
+        .into_iter()
+        .filter(|(id, _)| !answered.contains(id))
+        .collect();
+    let mut summary = BatchSummary {
+        skipped: total - pending.len(),
+        ..Default::default()
+    };
+    if summary.skipped > 0 {
+        println!(
+            "⏭️  | Skipping {} prompt(s) already answered in {}",
+            summary.skipped,
+            output.display()
+        );
+    }
+    if pending.is_empty() {
+        return Ok(summary);
+    }
+
+    let concurrency = concurrency.max(1);
+    println!(
+        "⏳ | Running {} prompt(s), {} at a time",
+        pending.len(),
+        concurrency
+    );
+    let base_dir = input.parent().unwrap_or(Path::new("."));
+    let mut file = open_results(output)?;
+    let mut results = stream::iter(pending)
+        .map(|(id, record)| async move {
+            match record {
+                Ok(record) => {
+                    run_prompt(backend, config, default_model, base_dir, id, record).await
+                }
+                Err(error) => BatchResult {
+                    id,
+                    error: Some(error),
+                    ..Default::default()
+                },
+            }
+        })
+        .buffer_unordered(concurrency);
+    while let Some(result) = results.next().await {
+        writeln!(file, "{}", serde_json::to_string(&result)?)?;
+        file.flush()?;
+        match &result.error {
+            Some(error) => {
+                eprintln!("🔴 | {}: {}", result.id, error);
+                summary.failed += 1;
+            }
+            None => {
+                println!("✅ | {}", result.id);
+                summary.answered += 1;
+            }
+        }
+        if let Some(usage) = result.usage {
+            summary.usage.add(usage);
+        }
+    }
+    Ok(summary)
+}
+
+async fn run_prompt(
+    backend: &dyn ModelBackend,
+    config: &BedrustConfig,
+    default_model: Option<&str>,
+    base_dir: &Path,
+    id: String,
+    record: BatchRecord,
+) -> BatchResult {
+    let Some(model) = record.model.as_deref().or(default_model) else {
+        return BatchResult {
+            id,
+            error: Some("No model for this prompt, set `model` on the line or pass -m".into()),
+            ..Default::default()
+        };
+    };
+    let model_id = resolve_model_id(model, &config.model_aliases);
+    match ask(backend, config, &model_id, base_dir, &record).await {
+        Ok((answer, usage)) => BatchResult {
+            id,
+            model_id: Some(model_id),
+            answer: Some(answer),
+            usage: Some(usage),
+            ..Default::default()
+        },
+        Err(e) => BatchResult {
+            id,
+            model_id: Some(model_id),
+            error: Some(e.to_string()),
+            ..Default::default()
+        },
+    }
+}
+
+async fn ask(
+    backend: &dyn ModelBackend,
+    config: &BedrustConfig,
+    model_id: &str,
+    base_dir: &Path,
+    record: &BatchRecord,
+) -> Result<(String, TurnRecord), anyhow::Error> {
+    // the same checks (and names) as `/attach`
+    let mut attachments = Attachments::new();
+    let empty = ConversationHistory::new(None, None, None, None);
+    for path in &record.attachments {
+        attachments.attach(&base_dir.join(path), &empty)?;
+    }
+    let mut content: Vec<ContentBlock> = attachments
+        .take()
+        .iter()
+        .map(|attachment| attachment.content_block())
+        .collect();
+    content.push(ContentBlock::Text(record.prompt.clone()));
+
+    let mut params =
+        ModelParams::for_model(&config.inference_params, &config.model_params, model_id);
+    if let Some(overrides) = &record.params {
+        params.merge(overrides);
+    }
+    let system = record.system.as_deref().map(|s| system_blocks(s, false));
+    let output = backend
+        .retry_policy()
+        .run("Batch prompt", || {
+            call_converse_with_usage(
+                backend,
+                model_id.to_string(),
+                params.inference_config(),
+                params.additional_fields(),
+                content.clone(),
+                system.clone(),
+            )
+        })
+        .await?;
+    Ok(output)
+}
+
+// The prompts with their ids. A line we can not read is kept, it ends up as an error in the
+// results, so it is not lost in the output of a long batch.
+fn read_batch(path: &Path) -> Result<Vec<BatchLine>, anyhow::Error> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Unable to read `{}`: {}", path.display(), e))?;
+    let mut records = Vec::new();
+    let mut ids = HashSet::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_id = format!("line-{}", i + 1);
+        let (id, record) = match serde_json::from_str::<BatchRecord>(line) {
+            Ok(record) => (record.id.clone().unwrap_or(line_id), Ok(record)),
+            Err(e) => (line_id, Err(format!("Unable to read the prompt: {}", e))),
+        };
+        if !ids.insert(id.clone()) {
+            bail!(
+                "The id `{}` is used more than once in {}",
+                id,
+                path.display()
+            );
+        }
+        records.push((id, record));
+    }
+    Ok(records)
+}
+
+// The prompts an earlier run already answered. A line cut short by Ctrl-C is not an answer.
+fn answered_ids(path: &Path) -> Result<HashSet<String>, anyhow::Error> {
+    if !path.exists() {
+        return Ok(HashSet::new());
+    }
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter_map(|line| serde_json::from_str::<BatchResult>(line).ok())
+        .filter(|result| result.answer.is_some())
+        .map(|result| result.id)
+        .collect())
+}
+
+fn open_results(path: &Path) -> Result<File, anyhow::Error> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    // a line cut short by Ctrl-C must not take the next result with it
+    let cut_short = fs::read(path).is_ok_and(|content| content.last().is_some_and(|c| *c != b'\n'));
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    if cut_short {
+        writeln!(file)?;
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants;
+    use crate::models::retry::RetryPolicy;
+    use crate::models::scripted::{ScriptedBackend, ScriptedTurn};
+    use crate::utils::test_dir;
+
+    fn results(path: &Path) -> Vec<BatchResult> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn batches_write_results_and_resume() {
+        let dir = test_dir("batch");
+        fs::write(dir.join("notes.md"), "# Notes").unwrap();
+        let input = dir.join("prompts.jsonl");
+        let lines = [
+            r#"{"id": "q1", "prompt": "Hi", "system": "Be brief", "model": "sonnet", "params": {"temperature": 0.1}}"#,
+            "",
+            r#"{"prompt": "Summarize", "attachments": ["notes.md"]}"#,
+            r#"{"prompt": "typo", "modle": "sonnet"}"#,
+        ];
+        fs::write(&input, lines.join("\n")).unwrap();
+        let output = default_output(&input);
+        assert_eq!(output, dir.join("prompts.results.jsonl"));
+
+        let config: BedrustConfig = ron::from_str(constants::BEDRUST_CONFIG_FILE).unwrap();
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Text("Hello".into()),
+            ScriptedTurn::Error("Model is not ready".into()),
+            // reasoning models think before the answer
+            ScriptedTurn::Reasoning("The notes are short".into(), "A summary".into()),
+        ])
+        .with_retry_policy(policy);
+
+        let summary = run_batch(&backend, &config, Some("nova-micro"), &input, &output, 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.answered, summary.failed, summary.skipped),
+            (1, 2, 0)
+        );
+        assert_eq!(summary.usage.turns.len(), 1);
+        let first = results(&output);
+        assert_eq!(first[0].id, "q1");
+        assert_eq!(first[0].answer.as_deref(), Some("Hello"));
+        assert_eq!(
+            first[0].model_id.as_deref(),
+            Some("us.anthropic.claude-3-7-sonnet-20250219-v1:0")
+        );
+        assert!(first[0].usage.as_ref().unwrap().output_tokens > 0);
+        assert_eq!(first[1].id, "line-3");
+        assert!(first[1]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("Model is not ready"));
+        assert_eq!(first[2].id, "line-4");
+        assert!(first[2]
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("unknown field `modle`"));
+
+        let request = &backend.requests()[0];
+        assert_eq!(
+            request.inference_config.as_ref().unwrap().temperature,
+            Some(0.1)
+        );
+        assert_eq!(request.system.as_ref().unwrap().len(), 1);
+
+        // a line cut short by Ctrl-C, then the failed prompts are asked again
+        let mut file = OpenOptions::new().append(true).open(&output).unwrap();
+        write!(file, r#"{{"id": "line-3", "answ"#).unwrap();
+        let summary = run_batch(&backend, &config, Some("nova-micro"), &input, &output, 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.answered, summary.failed, summary.skipped),
+            (1, 1, 1)
+        );
+        let second = results(&output);
+        assert_eq!(second.len(), 5);
+        let retried = second.iter().rfind(|r| r.id == "line-3").unwrap();
+        assert_eq!(retried.answer.as_deref(), Some("A summary"));
+        let content = backend.requests()[1].messages[0].content().to_vec();
+        assert!(matches!(content[0], ContentBlock::Document(_)));
+        assert_eq!(backend.remaining(), 0);
+
+        // without `-m`, a prompt that does not pick a model fails on its own
+        let no_model = dir.join("no-model.jsonl");
+        fs::write(&no_model, r#"{"prompt": "Hi"}"#).unwrap();
+        let output = default_output(&no_model);
+        let summary = run_batch(&backend, &config, None, &no_model, &output, 1)
+            .await
+            .unwrap();
+        assert_eq!((summary.answered, summary.failed), (0, 1));
+        assert!(results(&output)[0]
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("No model for this prompt"));
+        assert_eq!(backend.requests().len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,15 +5,17 @@ use std::{fs, io::Read, path::PathBuf};
 use anyhow::anyhow;
 use aws_sdk_bedrockruntime::primitives::Blob;
 use aws_sdk_bedrockruntime::types::{
-    ContentBlock, ImageBlock, ImageFormat, ImageSource, InferenceConfiguration, SystemContentBlock,
+    ContentBlock, ImageBlock, ImageFormat, ImageSource, SystemContentBlock,
 };
 
 use indicatif::{ProgressBar, ProgressStyle};
 use quick_xml::se;
 use serde::Serialize;
 
+use crate::models::backend::ModelBackend;
 use crate::models::check_model_features;
 use crate::models::converse::call_converse;
+use crate::models::params::ModelParams;
 use crate::models::ModelFeatures;
 use crate::utils::BedrustConfig;
 
@@ -58,13 +60,12 @@ impl Image {
 // This is for the sole reason of moving this out of the main.rs function
 pub async fn caption_process(
     model_id: &str,
-    bedrock_client: &aws_sdk_bedrock::Client,
-    bedrockruntime_client: &aws_sdk_bedrockruntime::Client,
+    backend: &dyn ModelBackend,
     images_path: Option<PathBuf>,
     bedrust_config: &BedrustConfig,
     xml: bool,
 ) -> Result<(), anyhow::Error> {
-    match check_model_features(model_id, bedrock_client, ModelFeatures::Images).await {
+    match check_model_features(model_id, backend, ModelFeatures::Images).await {
         Ok(b) => {
             match b {
                 true => {
@@ -83,12 +84,17 @@ pub async fn caption_process(
                         images.push(Image::new(file)?);
                     }
 
+                    let params = ModelParams::for_model(
+                        &bedrust_config.inference_params,
+                        &bedrust_config.model_params,
+                        model_id,
+                    );
                     caption_image(
                         &mut images,
                         model_id,
                         &bedrust_config.caption_prompt,
-                        bedrockruntime_client,
-                        bedrock_client,
+                        &params,
+                        backend,
                     )
                     .await?;
 
@@ -157,15 +163,11 @@ pub async fn caption_image(
     i: &mut Vec<crate::captioner::Image>,
     model: &str,
     prompt: &str,
-    runtime_client: &aws_sdk_bedrockruntime::Client,
-    _bedrock_client: &aws_sdk_bedrock::Client,
+    params: &ModelParams,
+    backend: &dyn ModelBackend,
 ) -> Result<(), anyhow::Error> {
-    // Needs to be hardcoded for images
-    let inference_parameters: InferenceConfiguration = InferenceConfiguration::builder()
-        .max_tokens(2048)
-        .top_p(0.8)
-        .temperature(0.5)
-        .build();
+    let inference_parameters = params.inference_config();
+    let additional_fields = params.additional_fields();
 
     // FIX: Remove the clone
     let system_prompt = Some(vec![SystemContentBlock::Text(prompt.to_owned())]);
@@ -206,17 +208,22 @@ pub async fn caption_image(
         //     bedrock_client,
         // )
         // .await?;
-        let caption = call_converse(
-            runtime_client,
-            model.to_string(),
-            // FIX: Avoid the clone
-            inference_parameters.clone(),
-            content,
-            // FIX: Avoid the clone
-            system_prompt.clone(),
-            false,
-        )
-        .await?;
+        let caption = backend
+            .retry_policy()
+            .run("Captioning", || {
+                call_converse(
+                    backend,
+                    model.to_string(),
+                    // FIX: Avoid the clone
+                    inference_parameters.clone(),
+                    additional_fields.clone(),
+                    content.clone(),
+                    // FIX: Avoid the clone
+                    system_prompt.clone(),
+                    false,
+                )
+            })
+            .await?;
         progress_bar.inc(1);
         image.caption = Some(caption);
     }
@@ -1,6 +1,18 @@
+use crate::compaction::Compaction;
+use crate::models::backend::ModelBackend;
+use crate::models::cache_point;
 use crate::models::converse::call_converse;
-use anyhow::anyhow;
-use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, Message};
+use crate::models::document::{document_to_json, json_to_document};
+use crate::usage::TurnRecord;
+use aws_sdk_bedrockruntime::primitives::Blob;
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ConversationRole, DocumentBlock, DocumentFormat, DocumentSource,
+    GuardrailConverseContentBlock, GuardrailConverseContentQualifier, GuardrailConverseTextBlock,
+    ImageBlock, ImageFormat, ImageSource, Message, ReasoningContentBlock, ReasoningTextBlock,
+    ToolResultBlock, ToolResultContentBlock, ToolResultStatus, ToolUseBlock, VideoBlock,
+    VideoFormat, VideoSource,
+};
+use base64::{engine::general_purpose, Engine as _};
 use dialoguer::Confirm;
 
 use rand::distr::Alphanumeric;
@@ -12,6 +24,7 @@ use std::{
     fmt::Display,
     fs,
     io::{self, Write},
+    path::PathBuf,
 };
 
 use regex::Regex;
@@ -34,20 +47,410 @@ use chrono::prelude::*;
 use dirs::home_dir;
 
 use crate::constants;
+use crate::markdown::render_markdown;
 
 // TODO:
 // - Print the summary when recalling the chat- [DONE] ✅
 // - Make sure that the filename is correct when saving - enforce chekcks and fallbacks
 // - Consider other locations for saving the conversations
-// - Produce the print with some syntax highlighting
+// - Produce the print with some syntax highlighting - [DONE] ✅
 // - Distinguish between user and computer input in the json
 // - Run checks for model support for the hardcoded models
 //
-// --- TEST Seriazible message ---
-#[derive(Serialize, Deserialize, Clone, Debug)]
+// --- Seriazible message ---
+// The SDK `Message` (and its `ContentBlock`s) do not implement Serialize/Deserialize, so this is
+// our own copy of it. Every block type the Converse API can return is kept, binary data (images,
+// documents, videos, redacted reasoning) is stored as base64.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SerializableMessage {
     pub role: String,
-    pub content: Vec<String>,
+    // Older chat files store the content as plain strings, those are loaded as text blocks
+    #[serde(deserialize_with = "deserialize_content")]
+    pub content: Vec<SerializableContentBlock>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SerializableContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        format: String,
+        data: String,
+    },
+    Document {
+        format: String,
+        name: String,
+        data: String,
+    },
+    Video {
+        format: String,
+        data: String,
+    },
+    Reasoning {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        redacted: Option<String>,
+    },
+    ToolUse {
+        tool_use_id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<String>,
+        content: Vec<SerializableToolResultContent>,
+    },
+    GuardContent {
+        text: String,
+        #[serde(default)]
+        qualifiers: Vec<String>,
+    },
+    // Marks where Bedrock can cache the prompt, it has no content of its own
+    CachePoint,
+}
+
+// What a tool result can contain
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SerializableToolResultContent {
+    Text {
+        text: String,
+    },
+    Json {
+        json: serde_json::Value,
+    },
+    Image {
+        format: String,
+        data: String,
+    },
+    Document {
+        format: String,
+        name: String,
+        data: String,
+    },
+    Video {
+        format: String,
+        data: String,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredContentBlock {
+    Legacy(String),
+    Block(SerializableContentBlock),
+}
+
+fn deserialize_content<'de, D>(deserializer: D) -> Result<Vec<SerializableContentBlock>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let blocks = Vec::<StoredContentBlock>::deserialize(deserializer)?;
+    Ok(blocks
+        .into_iter()
+        .map(|block| match block {
+            StoredContentBlock::Legacy(text) => SerializableContentBlock::Text { text },
+            StoredContentBlock::Block(block) => block,
+        })
+        .collect())
+}
+
+fn blob_to_base64(blob: &Blob) -> String {
+    general_purpose::STANDARD.encode(blob.as_ref())
+}
+
+fn base64_to_blob(data: &str) -> Blob {
+    // a broken base64 string in a chat file should not stop the whole chat from loading
+    Blob::new(general_purpose::STANDARD.decode(data).unwrap_or_default())
+}
+
+fn image_bytes(image: &ImageBlock) -> Option<String> {
+    image.source()?.as_bytes().ok().map(blob_to_base64)
+}
+
+fn document_bytes(document: &DocumentBlock) -> Option<String> {
+    document.source()?.as_bytes().ok().map(blob_to_base64)
+}
+
+fn video_bytes(video: &VideoBlock) -> Option<String> {
+    video.source()?.as_bytes().ok().map(blob_to_base64)
+}
+
+fn build_image(format: &str, data: &str) -> Option<ImageBlock> {
+    ImageBlock::builder()
+        .format(ImageFormat::from(format))
+        .source(ImageSource::Bytes(base64_to_blob(data)))
+        .build()
+        .ok()
+}
+
+fn build_document(format: &str, name: &str, data: &str) -> Option<DocumentBlock> {
+    DocumentBlock::builder()
+        .format(DocumentFormat::from(format))
+        .name(name)
+        .source(DocumentSource::Bytes(base64_to_blob(data)))
+        .build()
+        .ok()
+}
+
+fn build_video(format: &str, data: &str) -> Option<VideoBlock> {
+    VideoBlock::builder()
+        .format(VideoFormat::from(format))
+        .source(VideoSource::Bytes(base64_to_blob(data)))
+        .build()
+        .ok()
+}
+
+impl SerializableContentBlock {
+    // Blocks we do not know how to store (or that have no data) are dropped
+    pub fn from_content_block(block: &ContentBlock) -> Option<Self> {
+        Some(match block {
+            ContentBlock::Text(text) => SerializableContentBlock::Text { text: text.clone() },
+            ContentBlock::Image(image) => SerializableContentBlock::Image {
+                format: image.format().as_str().to_string(),
+                data: image_bytes(image)?,
+            },
+            ContentBlock::Document(document) => SerializableContentBlock::Document {
+                format: document.format().as_str().to_string(),
+                name: document.name().to_string(),
+                data: document_bytes(document)?,
+            },
+            ContentBlock::Video(video) => SerializableContentBlock::Video {
+                format: video.format().as_str().to_string(),
+                data: video_bytes(video)?,
+            },
+            ContentBlock::ReasoningContent(ReasoningContentBlock::ReasoningText(reasoning)) => {
+                SerializableContentBlock::Reasoning {
+                    text: Some(reasoning.text().to_string()),
+                    signature: reasoning.signature().map(|s| s.to_string()),
+                    redacted: None,
+                }
+            }
+            ContentBlock::ReasoningContent(ReasoningContentBlock::RedactedContent(blob)) => {
+                SerializableContentBlock::Reasoning {
+                    text: None,
+                    signature: None,
+                    redacted: Some(blob_to_base64(blob)),
+                }
+            }
+            ContentBlock::ToolUse(tool_use) => SerializableContentBlock::ToolUse {
+                tool_use_id: tool_use.tool_use_id().to_string(),
+                name: tool_use.name().to_string(),
+                input: document_to_json(tool_use.input()),
+            },
+            ContentBlock::ToolResult(result) => SerializableContentBlock::ToolResult {
+                tool_use_id: result.tool_use_id().to_string(),
+                status: result.status().map(|s| s.as_str().to_string()),
+                content: result
+                    .content()
+                    .iter()
+                    .filter_map(SerializableToolResultContent::from_tool_result_content)
+                    .collect(),
+            },
+            ContentBlock::GuardContent(GuardrailConverseContentBlock::Text(guard)) => {
+                SerializableContentBlock::GuardContent {
+                    text: guard.text().to_string(),
+                    qualifiers: guard
+                        .qualifiers()
+                        .iter()
+                        .map(|q| q.as_str().to_string())
+                        .collect(),
+                }
+            }
+            ContentBlock::CachePoint(_) => SerializableContentBlock::CachePoint,
+            _ => return None,
+        })
+    }
+
+    pub fn to_content_block(&self) -> Option<ContentBlock> {
+        Some(match self {
+            SerializableContentBlock::Text { text } => ContentBlock::Text(text.clone()),
+            SerializableContentBlock::Image { format, data } => {
+                ContentBlock::Image(build_image(format, data)?)
+            }
+            SerializableContentBlock::Document { format, name, data } => {
+                ContentBlock::Document(build_document(format, name, data)?)
+            }
+            SerializableContentBlock::Video { format, data } => {
+                ContentBlock::Video(build_video(format, data)?)
+            }
+            SerializableContentBlock::Reasoning {
+                redacted: Some(redacted),
+                ..
+            } => ContentBlock::ReasoningContent(ReasoningContentBlock::RedactedContent(
+                base64_to_blob(redacted),
+            )),
+            SerializableContentBlock::Reasoning {
+                text, signature, ..
+            } => ContentBlock::ReasoningContent(ReasoningContentBlock::ReasoningText(
+                ReasoningTextBlock::builder()
+                    .text(text.clone().unwrap_or_default())
+                    .set_signature(signature.clone())
+                    .build()
+                    .ok()?,
+            )),
+            SerializableContentBlock::ToolUse {
+                tool_use_id,
+                name,
+                input,
+            } => ContentBlock::ToolUse(
+                ToolUseBlock::builder()
+                    .tool_use_id(tool_use_id)
+                    .name(name)
+                    .input(json_to_document(input))
+                    .build()
+                    .ok()?,
+            ),
+            SerializableContentBlock::ToolResult {
+                tool_use_id,
+                status,
+                content,
+            } => ContentBlock::ToolResult(
+                ToolResultBlock::builder()
+                    .tool_use_id(tool_use_id)
+                    .set_status(status.as_deref().map(ToolResultStatus::from))
+                    .set_content(Some(
+                        content
+                            .iter()
+                            .filter_map(|c| c.to_tool_result_content())
+                            .collect(),
+                    ))
+                    .build()
+                    .ok()?,
+            ),
+            SerializableContentBlock::GuardContent { text, qualifiers } => {
+                ContentBlock::GuardContent(GuardrailConverseContentBlock::Text(
+                    GuardrailConverseTextBlock::builder()
+                        .text(text)
+                        .set_qualifiers(Some(
+                            qualifiers
+                                .iter()
+                                .map(|q| GuardrailConverseContentQualifier::from(q.as_str()))
+                                .collect(),
+                        ))
+                        .build()
+                        .ok()?,
+                ))
+            }
+            SerializableContentBlock::CachePoint => ContentBlock::CachePoint(cache_point()),
+        })
+    }
+
+    // A short, human readable version of the block. Used for titles, summaries and printing.
+    pub fn to_display_string(&self) -> Option<String> {
+        match self {
+            SerializableContentBlock::Text { text } => Some(text.clone()),
+            SerializableContentBlock::Image { format, .. } => Some(format!("[image: {}]", format)),
+            SerializableContentBlock::Document { name, .. } => {
+                Some(format!("[document: {}]", name))
+            }
+            SerializableContentBlock::Video { format, .. } => Some(format!("[video: {}]", format)),
+            SerializableContentBlock::ToolUse { name, input, .. } => {
+                Some(format!("[tool use: {} {}]", name, input))
+            }
+            SerializableContentBlock::ToolResult { content, .. } => Some(format!(
+                "[tool result: {}]",
+                content
+                    .iter()
+                    .map(|c| match c {
+                        SerializableToolResultContent::Text { text } => text.clone(),
+                        SerializableToolResultContent::Json { json } => json.to_string(),
+                        SerializableToolResultContent::Image { format, .. } => {
+                            format!("[image: {}]", format)
+                        }
+                        SerializableToolResultContent::Document { name, .. } => {
+                            format!("[document: {}]", name)
+                        }
+                        SerializableToolResultContent::Video { format, .. } => {
+                            format!("[video: {}]", format)
+                        }
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            )),
+            SerializableContentBlock::Reasoning { .. } | SerializableContentBlock::CachePoint => {
+                None
+            }
+            SerializableContentBlock::GuardContent { text, .. } => Some(text.clone()),
+        }
+    }
+}
+
+impl SerializableToolResultContent {
+    fn from_tool_result_content(content: &ToolResultContentBlock) -> Option<Self> {
+        Some(match content {
+            ToolResultContentBlock::Text(text) => {
+                SerializableToolResultContent::Text { text: text.clone() }
+            }
+            ToolResultContentBlock::Json(json) => SerializableToolResultContent::Json {
+                json: document_to_json(json),
+            },
+            ToolResultContentBlock::Image(image) => SerializableToolResultContent::Image {
+                format: image.format().as_str().to_string(),
+                data: image_bytes(image)?,
+            },
+            ToolResultContentBlock::Document(document) => SerializableToolResultContent::Document {
+                format: document.format().as_str().to_string(),
+                name: document.name().to_string(),
+                data: document_bytes(document)?,
+            },
+            ToolResultContentBlock::Video(video) => SerializableToolResultContent::Video {
+                format: video.format().as_str().to_string(),
+                data: video_bytes(video)?,
+            },
+            _ => return None,
+        })
+    }
+
+    fn to_tool_result_content(&self) -> Option<ToolResultContentBlock> {
+        Some(match self {
+            SerializableToolResultContent::Text { text } => {
+                ToolResultContentBlock::Text(text.clone())
+            }
+            SerializableToolResultContent::Json { json } => {
+                ToolResultContentBlock::Json(json_to_document(json))
+            }
+            SerializableToolResultContent::Image { format, data } => {
+                ToolResultContentBlock::Image(build_image(format, data)?)
+            }
+            SerializableToolResultContent::Document { format, name, data } => {
+                ToolResultContentBlock::Document(build_document(format, name, data)?)
+            }
+            SerializableToolResultContent::Video { format, data } => {
+                ToolResultContentBlock::Video(build_video(format, data)?)
+            }
+        })
+    }
+}
+
+impl SerializableMessage {
+    // All the text blocks of the message
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                SerializableContentBlock::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    // The message as text, with placeholders for images, documents and tools
+    pub fn to_display_string(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|block| block.to_display_string())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
 }
 
 // Convert Message to SerializableMessage
@@ -56,18 +459,12 @@ impl From<Message> for SerializableMessage {
         SerializableMessage {
             role: message.role().as_str().to_string(),
             // Iterating throught the Vec<ContentBlock> of the Message.content()
-            // And then storing them all as a vector of Strings. Just for text in this case.
-            content: vec![message
+            // And storing every block we know how to store
+            content: message
                 .content()
                 .iter()
-                .find_map(|block| {
-                    if let ContentBlock::Text(text) = block {
-                        Some(text.to_string())
-                    } else {
-                        None
-                    }
-                })
-                .unwrap()],
+                .filter_map(SerializableContentBlock::from_content_block)
+                .collect(),
         }
     }
 }
@@ -81,8 +478,8 @@ impl From<SerializableMessage> for Message {
             .set_content(Some(
                 serializable
                     .content
-                    .into_iter()
-                    .map(ContentBlock::Text)
+                    .iter()
+                    .filter_map(|block| block.to_content_block())
                     .collect(),
             ))
             .build()
@@ -123,11 +520,23 @@ impl Display for ConversationEntity {
 pub struct Conversation {
     pub role: ConversationEntity,
     pub content: String,
+    // Every message produced during this turn (tool calls, tool results and the final answer),
+    // in the order they need to go into the conversation history
+    #[serde(default)]
+    pub messages: Vec<SerializableMessage>,
+    // Tokens and latency of this turn
+    #[serde(default)]
+    pub usage: Option<TurnRecord>,
 }
 
 impl Conversation {
     pub fn new(role: ConversationEntity, content: String) -> Conversation {
-        Conversation { role, content }
+        Conversation {
+            role,
+            content,
+            messages: Vec::new(),
+            usage: None,
+        }
     }
 }
 
@@ -152,6 +561,12 @@ pub struct ConversationHistory {
     // pub history: Option<String>,
     pub messages: Option<Vec<SerializableMessage>>,
     pub timestamp: String,
+    // Token usage of every question asked in this conversation
+    #[serde(default)]
+    pub turns: Vec<TurnRecord>,
+    // the summary that is sent instead of the oldest messages, see `compaction`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compaction: Option<Compaction>,
 }
 
 impl ConversationHistory {
@@ -168,6 +583,8 @@ impl ConversationHistory {
             summary,
             messages,
             timestamp: local.to_string(),
+            turns: Vec::new(),
+            compaction: None,
         }
     }
 
@@ -176,7 +593,7 @@ impl ConversationHistory {
         match &self.messages {
             Some(messages) => messages
                 .iter()
-                .map(|msg| format!("{}:{}", msg.role, msg.content.join("\n")))
+                .map(|msg| format!("{}:{}", msg.role, msg.to_display_string()))
                 .collect::<Vec<String>>()
                 .join("\n\n"),
             None => String::new(),
@@ -198,12 +615,20 @@ impl ConversationHistory {
                  out: &mut dyn handlebars::Output| {
                     if let Some(value) = h.param(0) {
                         let text = if value.value().is_array() {
+                            // message content blocks, non text blocks are shown as placeholders
                             value
                                 .value()
                                 .as_array()
                                 .unwrap()
                                 .iter()
-                                .filter_map(|v| v.as_str())
+                                .filter_map(|v| match v.as_str() {
+                                    Some(text) => Some(text.to_string()),
+                                    None => serde_json::from_value::<SerializableContentBlock>(
+                                        v.clone(),
+                                    )
+                                    .ok()
+                                    .and_then(|block| block.to_display_string()),
+                                })
                                 .collect::<Vec<_>>()
                                 .join("\n")
                         } else {
@@ -333,6 +758,7 @@ impl ConversationHistory {
     }
 
     // Clearing the current chat history - but I feel there is a better way to do this
+    #[must_use]
     pub fn clear(&self) -> Self {
         let local: DateTime<Local> = Local::now(); // e.g. `2014-11-28T21:45:59.324310806+09:00`
         ConversationHistory {
@@ -341,59 +767,35 @@ impl ConversationHistory {
             summary: None,
             messages: None,
             timestamp: local.to_string(),
+            turns: Vec::new(),
+            compaction: None,
         }
     }
 
-    async fn generate_title(
-        &self,
-        client: &aws_sdk_bedrockruntime::Client,
-    ) -> Result<String, anyhow::Error> {
+    async fn generate_title(&self, backend: &dyn ModelBackend) -> Result<String, anyhow::Error> {
         let messages_str = &self.to_messages_string();
         let query = constants::CONVERSATION_TITLE_PROMPT.replace("{}", messages_str);
         let model_id = constants::CONVERSATION_HISTORY_MODEL_ID;
         let content = ContentBlock::Text(query);
         println!("⏳ | Generating a new file name for this conversation... ");
-        // === RETRY MECHANISM ===
-        let max_retries = 3;
-        let mut retry_count = 0;
-        while retry_count < max_retries {
-            match call_converse(
-                client,
-                model_id.to_string(),
-                constants::CONVERSATION_HISTORY_TITLE_INF_PARAMS.clone(),
-                content.clone(),
-                None,
-                false,
-            )
-            .await
-            {
-                Ok(response) => {
-                    println!("✅ | Done ");
-                    return Ok(response);
-                }
-                Err(e) => {
-                    // if an error occurs, print it and retry
-                    println!("🔴 | Error: {}", e);
-                    retry_count += 1;
-                }
-            }
-            // if we have retried max_retries times, return an error
-            if retry_count >= max_retries {
-                return Err(anyhow!(
-                    "Failed to get a response after {} retries",
-                    max_retries
-                ));
-            }
-            // sleep for 2^retry_count seconds - exponential backoff
-            tokio::time::sleep(std::time::Duration::from_secs(2u64.pow(retry_count))).await;
-            // === END RETRY MECHANISM ===
-        }
-        Err(anyhow!("Unexpected error in generate_title"))
+        let response = backend
+            .retry_policy()
+            .run("Generating the file name", || {
+                call_converse(
+                    backend,
+                    model_id.to_string(),
+                    constants::CONVERSATION_HISTORY_TITLE_INF_PARAMS.clone(),
+                    None,
+                    content.clone(),
+                    None,
+                    false,
+                )
+            })
+            .await?;
+        println!("✅ | Done ");
+        Ok(response)
     }
-    async fn generate_summary(
-        &self,
-        client: &aws_sdk_bedrockruntime::Client,
-    ) -> Result<String, anyhow::Error> {
+    async fn generate_summary(&self, backend: &dyn ModelBackend) -> Result<String, anyhow::Error> {
         let messages_str = &self.to_messages_string();
         let query = constants::CONVERSATION_SUMMARY_PROMPT.replace("{}", messages_str);
 
@@ -401,46 +803,28 @@ impl ConversationHistory {
         let content = ContentBlock::Text(query);
         println!("⏳ | Generating a summary for this conversation... ");
         println!();
-        // === RETRY MECHANISM ===
-        let max_retries = 3;
-        let mut retry_count = 0;
-        while retry_count < max_retries {
-            match call_converse(
-                client,
-                model_id.to_string(),
-                constants::CONVERSATION_HISTORY_INF_PARAMS.clone(),
-                content.clone(),
-                None,
-                false,
-            )
-            .await
-            {
-                Ok(response) => return Ok(response),
-                Err(e) => {
-                    // if an error occurs, print it and retry
-                    println!("🔴 | Error: {}", e);
-                    retry_count += 1;
-                }
-            }
-            // if we have retried max_retries times, return an error
-            if retry_count >= max_retries {
-                return Err(anyhow!(
-                    "Failed to get a response after {} retries",
-                    max_retries
-                ));
-            }
-            // sleep for 2^retry_count seconds - exponential backoff
-            tokio::time::sleep(std::time::Duration::from_secs(2u64.pow(retry_count))).await;
-            // === END RETRY MECHANISM ===
-        }
-        Err(anyhow!("Unexpected error in generate_summary"))
+        let response = backend
+            .retry_policy()
+            .run("Generating the summary", || {
+                call_converse(
+                    backend,
+                    model_id.to_string(),
+                    constants::CONVERSATION_HISTORY_INF_PARAMS.clone(),
+                    None,
+                    content.clone(),
+                    None,
+                    false,
+                )
+            })
+            .await?;
+        Ok(response)
     }
 }
 
 // TODO: Name the chat histories somehow
 pub async fn save_chat_history(
     filename: Option<&str>,
-    client: &aws_sdk_bedrockruntime::Client,
+    backend: &dyn ModelBackend,
     ch: &mut ConversationHistory,
 ) -> Result<String, anyhow::Error> {
     let home_dir = home_dir().expect("Failed to get HOME directory");
@@ -448,7 +832,7 @@ pub async fn save_chat_history(
     fs::create_dir_all(&save_dir)?;
 
     // generate the conversation summary
-    ch.summary = Some(ch.generate_summary(client).await?);
+    ch.summary = Some(ch.generate_summary(backend).await?);
 
     // if we pass it Some filename - we keep using that file as history
     let (filename, file_path) = if let Some(existing_filename) = filename {
@@ -457,7 +841,7 @@ pub async fn save_chat_history(
             save_dir.join(existing_filename),
         )
     } else {
-        let title = ch.generate_title(client).await?;
+        let title = ch.generate_title(backend).await?;
         // Generate a random suffix
         let random_string: String = rng()
             .sample_iter(Alphanumeric) // These are ASCII u8
@@ -476,9 +860,35 @@ pub async fn save_chat_history(
     Ok(filename)
 }
 
-pub fn load_chat_history(
-    filename: &str,
-) -> Result<(Vec<SerializableMessage>, String, String, String), anyhow::Error> {
+// messages, filename, title, summary and token usage of a saved chat
+// Saves the conversation as is, without asking the model for a title or a summary. Used when
+// Bedrust is about to exit because of an error, and the model might be the reason why.
+pub fn emergency_save_chat_history(ch: &ConversationHistory) -> Result<String, anyhow::Error> {
+    let home_dir = home_dir().expect("Failed to get HOME directory");
+    let save_dir = home_dir.join(format!(".config/{}/chats", constants::CONFIG_DIR_NAME));
+    fs::create_dir_all(&save_dir)?;
+
+    let filename = format!("emergency-{}.json", Local::now().format("%Y-%m-%d-%H%M%S"));
+    let mut ch = ch.clone();
+    ch.title.get_or_insert_with(|| "Emergency save".to_string());
+    ch.summary
+        .get_or_insert_with(|| "This conversation was saved after an error".to_string());
+    ch.filename = Some(filename.clone());
+    fs::write(save_dir.join(&filename), serde_json::to_string_pretty(&ch)?)?;
+
+    Ok(filename)
+}
+
+pub type LoadedChat = (
+    Vec<SerializableMessage>,
+    String,
+    String,
+    String,
+    Vec<TurnRecord>,
+    Option<Compaction>,
+);
+
+pub fn load_chat_history(filename: &str) -> Result<LoadedChat, anyhow::Error> {
     let home_dir = home_dir().expect("Failed to get HOME directory");
     let chat_dir = home_dir.join(format!(".config/{}/chats", constants::CONFIG_DIR_NAME));
     let file_path = chat_dir.join(filename);
@@ -491,6 +901,8 @@ pub fn load_chat_history(
         filename.to_string(),
         ch.title.expect("NO_TITLE").to_string(),
         ch.summary.expect("NO_SUMMARY"),
+        ch.turns,
+        ch.compaction,
     ))
 }
 
@@ -520,7 +932,7 @@ pub fn print_conversation_history(history: &ConversationHistory) {
             io::stdin().read_line(&mut user_input).unwrap();
 
             if user_input.trim().to_lowercase() == "y" {
-                println!("{}", history.yellow());
+                println!("{}", render_markdown(&history));
             } else {
                 println!(
                     "Displaying first {} characters:",
@@ -530,14 +942,19 @@ pub fn print_conversation_history(history: &ConversationHistory) {
                 println!("... (truncated)");
             }
         } else {
-            println!("{}", history.yellow());
+            println!("{}", render_markdown(&history));
         }
     }
 }
 
-pub fn list_chat_histories() -> Result<Vec<String>, anyhow::Error> {
+// Where the chats are saved
+pub fn chat_history_dir() -> PathBuf {
     let home_dir = home_dir().expect("Failed to get HOME directory");
-    let chat_dir = home_dir.join(format!(".config/{}/chats", constants::CONFIG_DIR_NAME));
+    home_dir.join(format!(".config/{}/chats", constants::CONFIG_DIR_NAME))
+}
+
+pub fn list_chat_histories() -> Result<Vec<String>, anyhow::Error> {
+    let chat_dir = chat_history_dir();
 
     let mut chat_files = Vec::new();
     for entry in fs::read_dir(chat_dir)? {
@@ -582,3 +999,113 @@ fn process_inline_code(text: &str, regex: &Regex) -> String {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_bedrockruntime::types::GuardrailConverseContentQualifier as Qualifier;
+    use serde_json::json;
+
+    fn message(role: ConversationRole, content: Vec<ContentBlock>) -> Message {
+        Message::builder()
+            .role(role)
+            .set_content(Some(content))
+            .build()
+            .unwrap()
+    }
+
+    // every block type we store should come back the same after a save and a load
+    #[test]
+    fn messages_round_trip() {
+        let image = ImageBlock::builder()
+            .format(ImageFormat::Png)
+            .source(ImageSource::Bytes(Blob::new(vec![0x89, 0x50, 0x4e, 0x47])))
+            .build()
+            .unwrap();
+        let tool_use = ToolUseBlock::builder()
+            .tool_use_id("tooluse_1")
+            .name("read_file")
+            .input(json_to_document(&json!({ "path": "src/main.rs" })))
+            .build()
+            .unwrap();
+        let tool_result = ToolResultBlock::builder()
+            .tool_use_id("tooluse_1")
+            .content(ToolResultContentBlock::Text("fn main() {}".into()))
+            .content(ToolResultContentBlock::Json(json_to_document(
+                &json!({ "lines": 1 }),
+            )))
+            .status(ToolResultStatus::Success)
+            .build()
+            .unwrap();
+        let messages = vec![
+            // image only, this used to panic
+            message(ConversationRole::User, vec![ContentBlock::Image(image)]),
+            message(
+                ConversationRole::Assistant,
+                vec![
+                    ContentBlock::ReasoningContent(ReasoningContentBlock::ReasoningText(
+                        ReasoningTextBlock::builder()
+                            .text("Let me look at the file")
+                            .signature("c2lnbmF0dXJl")
+                            .build()
+                            .unwrap(),
+                    )),
+                    ContentBlock::ReasoningContent(ReasoningContentBlock::RedactedContent(
+                        Blob::new("secret"),
+                    )),
+                    ContentBlock::Text("Reading it now".into()),
+                    ContentBlock::ToolUse(tool_use),
+                ],
+            ),
+            message(
+                ConversationRole::User,
+                vec![
+                    ContentBlock::ToolResult(tool_result),
+                    ContentBlock::Document(
+                        DocumentBlock::builder()
+                            .format(DocumentFormat::Md)
+                            .name("notes")
+                            .source(DocumentSource::Bytes(Blob::new("# Notes")))
+                            .build()
+                            .unwrap(),
+                    ),
+                    ContentBlock::GuardContent(GuardrailConverseContentBlock::Text(
+                        GuardrailConverseTextBlock::builder()
+                            .text("check this")
+                            .qualifiers(Qualifier::Query)
+                            .build()
+                            .unwrap(),
+                    )),
+                ],
+            ),
+        ];
+
+        let serializable: Vec<SerializableMessage> = messages
+            .iter()
+            .cloned()
+            .map(SerializableMessage::from)
+            .collect();
+        let saved = serde_json::to_string(&serializable).unwrap();
+        let loaded: Vec<SerializableMessage> = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded, serializable);
+
+        let restored: Vec<Message> = loaded.into_iter().map(Message::from).collect();
+        assert_eq!(restored, messages);
+    }
+
+    // chats saved before content blocks were a thing only have strings
+    #[test]
+    fn load_legacy_messages() {
+        let saved = r#"[{"role":"user","content":["Hello","there"]},{"role":"assistant","content":[{"type":"text","text":"Hi!"}]}]"#;
+        let loaded: Vec<SerializableMessage> = serde_json::from_str(saved).unwrap();
+        assert_eq!(loaded[0].text(), "Hello\nthere");
+        assert_eq!(loaded[1].text(), "Hi!");
+        assert_eq!(
+            Message::from(loaded[0].clone()).content(),
+            &[
+                ContentBlock::Text("Hello".into()),
+                ContentBlock::Text("there".into())
+            ]
+        );
+    }
+}
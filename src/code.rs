@@ -1,8 +1,11 @@
 use crate::constants;
+use crate::models::backend::ModelBackend;
 use crate::models::converse::call_converse;
-use crate::utils::print_warning;
+use crate::models::error::{BedrockError, BedrockErrorKind};
+use crate::models::params::ModelParams;
+use crate::utils::{print_warning, BedrustConfig};
 use anyhow::anyhow;
-use aws_sdk_bedrockruntime::types::{ContentBlock, InferenceConfiguration};
+use aws_sdk_bedrockruntime::types::ContentBlock;
 use ignore::DirEntry;
 use std::fs;
 use std::{collections::HashMap, path::PathBuf};
@@ -19,7 +22,8 @@ use std::{collections::HashMap, path::PathBuf};
 // This starts a process of the code chat. Moved here instead of being in the main.rs file
 pub async fn code_chat_process(
     code_path: PathBuf,
-    bedrock_runtime_client: &aws_sdk_bedrockruntime::Client,
+    backend: &dyn ModelBackend,
+    bedrust_config: &BedrustConfig,
 ) -> Result<String, anyhow::Error> {
     println!("----------------------------------------");
     print_warning("⚠ THIS IS A BETA FEATURE ⚠");
@@ -33,7 +37,7 @@ pub async fn code_chat_process(
     );
     println!("----------------------------------------");
     let mut convo = String::new();
-    let code = code_chat(code_path.clone().to_path_buf(), bedrock_runtime_client).await?;
+    let code = code_chat(code_path.clone().to_path_buf(), backend, bedrust_config).await?;
 
     // NOTE: Here is something stupid for my edge case
     let (p1, p2) = ("<bedrust_be", "gin_source>");
@@ -71,22 +75,21 @@ Here are the files:
 
 pub async fn code_chat(
     p: PathBuf,
-    client: &aws_sdk_bedrockruntime::Client,
+    backend: &dyn ModelBackend,
+    bedrust_config: &BedrustConfig,
 ) -> Result<String, anyhow::Error> {
-    // === DEFAULT INFERENCE PARAMETERS ===
-    // NOTE: Not sure if this is the best way to store this. Maybe also as part of a configuraiton
-    // run
-    // Maybe even have specific parameters for just guessing the code with a low temp value
-    let inference_parameters: InferenceConfiguration = InferenceConfiguration::builder()
-        .max_tokens(2048)
-        .top_p(0.8)
-        .temperature(0.2)
-        .build();
+    // === INFERENCE PARAMETERS ===
+    // the ones configured for the model that guesses the project type
+    let params = ModelParams::for_model(
+        &bedrust_config.inference_params,
+        &bedrust_config.model_params,
+        constants::PROJECT_GUESS_MODEL_ID,
+    );
 
     // FIGURE OUT PROJECT
     // FIX: Seems to return hidden files too
     let all_files = get_all_files(&p, None, 3)?;
-    let extn = guess_code_type(all_files, client, inference_parameters).await?;
+    let extn = guess_code_type(all_files, backend, &params).await?;
 
     // get all files with the extensions from above, and go 2 levels deep
     let files = get_all_files(&p, Some(extn), 3)?;
@@ -134,7 +137,7 @@ fn get_all_files(
         .filter_map(Result::ok)
         .filter(|entry| {
             let is_file = entry.file_type().is_some_and(|ft| ft.is_file());
-            let matches_extension = ext.as_ref().map_or(true, |extensions| {
+            let matches_extension = ext.as_ref().is_none_or(|extensions| {
                 entry
                     .path()
                     .extension()
@@ -153,8 +156,8 @@ fn get_all_files(
 
 async fn guess_code_type(
     files: Vec<PathBuf>,
-    client: &aws_sdk_bedrockruntime::Client,
-    inf_param: InferenceConfiguration,
+    backend: &dyn ModelBackend,
+    params: &ModelParams,
 ) -> Result<Vec<String>, anyhow::Error> {
     // question
     let mut query = String::new();
@@ -169,48 +172,29 @@ async fn guess_code_type(
     // This println! is here to just make it look nice
     println!("Including the following file extensions in this run: ");
     let content = ContentBlock::Text(query);
-    // === RETRY MECHANISM ===
-    let max_retries = 3;
-    let mut retry_count = 0;
-    while retry_count < max_retries {
-        match call_converse(
-            client,
-            model_id.to_string(),
-            inf_param.clone(),
-            content.clone(),
-            None,
-            true,
-        )
-        .await
-        {
-            Ok(response) => {
-                // check if the response is a valid array
-                match serde_json::from_str::<Vec<String>>(&response) {
-                    Ok(extensions) => return Ok(extensions),
-                    Err(_) => {
-                        println!("🔴 | Response from `guess_code_type` is not a valid array. Retrying ...");
-                        retry_count += 1;
-                    }
-                }
-            }
-            Err(e) => {
-                // if an error occurs, print it and retry
-                println!("🔴 | Error: {}", e);
-                retry_count += 1;
-            }
-        }
-        // if we have retried max_retries times, return an error
-        if retry_count >= max_retries {
-            return Err(anyhow!(
-                "Failed to get a response after {} retries",
-                max_retries
-            ));
-        }
-        // sleep for 2^retry_count seconds - exponential backoff
-        tokio::time::sleep(std::time::Duration::from_secs(2u64.pow(retry_count))).await;
-        // === END RETRY MECHANISM ===
-    }
-    Err(anyhow!("Unexpected error in guess_code_type"))
+    let extensions = backend
+        .retry_policy()
+        .run("Guessing the code type", || async {
+            let response = call_converse(
+                backend,
+                model_id.to_string(),
+                params.inference_config(),
+                params.additional_fields(),
+                content.clone(),
+                None,
+                true,
+            )
+            .await?;
+            // check if the response is a valid array, the model can try again if it is not
+            serde_json::from_str::<Vec<String>>(&response).map_err(|_| {
+                BedrockError::new(
+                    BedrockErrorKind::InvalidResponse,
+                    "The response is not a valid array",
+                )
+            })
+        })
+        .await?;
+    Ok(extensions)
 }
 
 fn get_file_contents(files: Vec<PathBuf>) -> Result<HashMap<PathBuf, String>, anyhow::Error> {
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, Message, SystemContentBlock};
+use serde::{Deserialize, Serialize};
+
+use crate::chat::ConversationHistory;
+use crate::constants;
+use crate::models::backend::ModelBackend;
+use crate::models::catalog::context_window;
+use crate::models::context::{
+    estimate_system_tokens, estimate_tokens, starts_a_turn, ContextGuard, ContextPolicy,
+};
+use crate::models::converse::call_converse;
+use crate::models::fallback::ChatModel;
+
+// NOTE: Rolling summarization (`compaction` in the config). Once the conversation takes up
+// `threshold` of the context window, the older messages are summarized with the helper model, and
+// from then on the summary is sent in their place (as a question and an answer at the start of
+// the conversation). The next time, the summary and the messages after it are summarized again.
+// The messages themselves stay in the conversation, so the saved chat has all of them. The older
+// messages have to fit in the context window of the helper model too, what does not fit is
+// summarized in another round, along with the summary so far.
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CompactionConfig {
+    // how much of the context window the conversation can take up before it is summarized
+    #[serde(default = "_default_threshold")]
+    pub threshold: f64,
+    // the newest messages are always sent as they are
+    #[serde(default = "_default_keep_messages")]
+    pub keep_messages: usize,
+    // the helper model that writes the summaries
+    #[serde(default = "_default_model_id")]
+    pub model_id: String,
+}
+
+fn _default_threshold() -> f64 {
+    constants::DEFAULT_COMPACTION_THRESHOLD
+}
+
+fn _default_keep_messages() -> usize {
+    constants::DEFAULT_COMPACTION_KEEP_MESSAGES
+}
+
+fn _default_model_id() -> String {
+    constants::CONVERSATION_HISTORY_MODEL_ID.to_string()
+}
+
+// The summary of the oldest messages of a conversation
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct Compaction {
+    pub summary: String,
+    // how many messages, from the start of the conversation, the summary stands for
+    pub messages: usize,
+}
+
+// The messages as they are sent: the summary, then the messages it does not cover
+pub fn compacted_messages(messages: &[Message], compaction: Option<&Compaction>) -> Vec<Message> {
+    let Some(compaction) = compaction.filter(|c| c.messages > 0 && c.messages <= messages.len())
+    else {
+        return messages.to_vec();
+    };
+    let preamble = [
+        (
+            ConversationRole::User,
+            format!(
+                "{}\n\n{}",
+                constants::COMPACTION_PREAMBLE,
+                compaction.summary
+            ),
+        ),
+        (
+            ConversationRole::Assistant,
+            constants::COMPACTION_ACKNOWLEDGEMENT.to_string(),
+        ),
+    ];
+    preamble
+        .into_iter()
+        .map(|(role, text)| {
+            Message::builder()
+                .role(role)
+                .content(ContentBlock::Text(text))
+                .build()
+                .expect("role and content are set")
+        })
+        .chain(messages[compaction.messages..].iter().cloned())
+        .collect()
+}
+
+// The summary and the messages go in once each, the braces in them (ie code) are left alone
+fn compaction_prompt(summary: &str, messages: &str) -> String {
+    let (before, after) = constants::COMPACTION_PROMPT
+        .split_once("{messages}")
+        .expect("the prompt has a place for the messages");
+    format!(
+        "{}{}{}",
+        before.replace("{summary}", summary),
+        messages,
+        after
+    )
+}
+
+// Summarizes the older messages, when the conversation gets too close to the context window of
+// the model. Returns whether it did.
+pub async fn compact(
+    backend: &dyn ModelBackend,
+    conversation_history: &mut ConversationHistory,
+    config: &CompactionConfig,
+    model: &ChatModel,
+    context_windows: &HashMap<String, usize>,
+) -> Result<bool, anyhow::Error> {
+    let mut compacted = false;
+    while compact_once(
+        backend,
+        conversation_history,
+        config,
+        model,
+        context_windows,
+    )
+    .await?
+    {
+        compacted = true;
+    }
+    Ok(compacted)
+}
+
+// One round of summarizing, as much as the helper model can take
+async fn compact_once(
+    backend: &dyn ModelBackend,
+    conversation_history: &mut ConversationHistory,
+    config: &CompactionConfig,
+    model: &ChatModel,
+    context_windows: &HashMap<String, usize>,
+) -> Result<bool, anyhow::Error> {
+    let Some(guard) = model.options.context else {
+        return Ok(false);
+    };
+    let history = conversation_history.messages.clone().unwrap_or_default();
+    let messages: Vec<Message> = history.iter().cloned().map(Message::from).collect();
+    let previous = conversation_history.compaction.clone().unwrap_or_default();
+    let tokens: usize = compacted_messages(&messages, Some(&previous))
+        .iter()
+        .map(estimate_tokens)
+        .sum::<usize>()
+        + estimate_system_tokens(&model.system);
+    if (tokens as f64) < guard.window as f64 * config.threshold {
+        return Ok(false);
+    }
+
+    // the messages after the summary have to start with a question
+    let mut end = messages.len().saturating_sub(config.keep_messages.max(1));
+    while end > previous.messages && !starts_a_turn(&messages[end]) {
+        end -= 1;
+    }
+    if end <= previous.messages {
+        return Ok(false);
+    }
+
+    // as many questions (with their answers) as fit next to the prompt and the summary
+    let window = context_window(&config.model_id, context_windows);
+    let prompt = [SystemContentBlock::Text(compaction_prompt(
+        &previous.summary,
+        "",
+    ))];
+    let max_output = constants::COMPACTION_INF_PARAMS
+        .max_tokens()
+        .unwrap_or_default() as usize;
+    let budget = ContextGuard::new(window, ContextPolicy::default()).budget(&prompt, max_output);
+    let mut tokens = 0;
+    let mut fits = previous.messages;
+    for i in previous.messages..end {
+        tokens += estimate_tokens(&messages[i]);
+        if tokens > budget {
+            break;
+        }
+        if i + 1 == end || starts_a_turn(&messages[i + 1]) {
+            fits = i + 1;
+        }
+    }
+    // a question that does not fit on its own is cut short
+    let end = if fits > previous.messages {
+        fits
+    } else {
+        (previous.messages + 1..end)
+            .find(|&i| starts_a_turn(&messages[i]))
+            .unwrap_or(end)
+    };
+    let older: String = history[previous.messages..end]
+        .iter()
+        .map(|msg| format!("{}:{}", msg.role, msg.to_display_string()))
+        .collect::<Vec<String>>()
+        .join("\n\n")
+        .chars()
+        .take(budget * 4)
+        .collect();
+    let content = ContentBlock::Text(compaction_prompt(&previous.summary, &older));
+    println!("⏳ | Summarizing the older messages, the conversation is getting long");
+    let summary = backend
+        .retry_policy()
+        .run("Summarizing the older messages", || {
+            call_converse(
+                backend,
+                config.model_id.clone(),
+                constants::COMPACTION_INF_PARAMS.clone(),
+                None,
+                content.clone(),
+                None,
+                false,
+            )
+        })
+        .await?;
+    conversation_history.compaction = Some(Compaction {
+        summary: summary.trim().to_string(),
+        messages: end,
+    });
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::SerializableMessage;
+    use crate::models::converse_stream::call_converse_stream;
+    use crate::models::scripted::{ScriptedBackend, ScriptedTurn};
+    use crate::utils::BedrustConfig;
+
+    fn message(role: ConversationRole, text: &str) -> SerializableMessage {
+        let message = Message::builder()
+            .role(role)
+            .content(ContentBlock::Text(format!("{} {}", text, "a".repeat(800))))
+            .build()
+            .unwrap();
+        SerializableMessage::from(message)
+    }
+
+    #[tokio::test]
+    async fn long_conversations_are_summarized() {
+        let mut config: BedrustConfig = ron::from_str(constants::BEDRUST_CONFIG_FILE).unwrap();
+        config
+            .context_windows
+            .insert("amazon.nova-micro-v1:0".into(), 1_000);
+        config.inference_params.max_tokens = 100;
+        let compaction: CompactionConfig =
+            ron::from_str("(threshold: 0.5, keep_messages: 2)").unwrap();
+        assert_eq!(
+            compaction.model_id,
+            constants::CONVERSATION_HISTORY_MODEL_ID
+        );
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Text("They talked about IAM and S3.".into()),
+            ScriptedTurn::Text("Use a bucket policy".into()),
+        ]);
+        let model = ChatModel::new("amazon.nova-micro-v1:0", &backend, &config, "", None).await;
+
+        let mut history = ConversationHistory::new(
+            None,
+            None,
+            None,
+            Some(vec![
+                message(ConversationRole::User, "What is IAM?"),
+                message(ConversationRole::Assistant, "Identity"),
+                message(ConversationRole::User, "And S3?"),
+                message(ConversationRole::Assistant, "Storage"),
+                message(ConversationRole::User, "How do I share a bucket?"),
+            ]),
+        );
+        // an answer can not follow the summary, so it stops at the question before it
+        assert!(compact(
+            &backend,
+            &mut history,
+            &compaction,
+            &model,
+            &config.context_windows
+        )
+        .await
+        .unwrap());
+        let expected = Compaction {
+            summary: "They talked about IAM and S3.".into(),
+            messages: 2,
+        };
+        assert_eq!(history.compaction.as_ref(), Some(&expected));
+        let summarized = backend.requests()[0].messages[0].content()[0].clone();
+        let summarized = summarized.as_text().unwrap();
+        assert!(summarized.contains("What is IAM?") && !summarized.contains("And S3?"));
+
+        // the summary is sent instead of the messages it covers
+        let convo = call_converse_stream(
+            &backend,
+            model.model_id.clone(),
+            &history,
+            &model.options.clone().echo(false),
+            &model.system,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(convo.content, "Use a bucket policy");
+        let sent = &backend.requests()[1].messages;
+        assert_eq!(sent.len(), 5);
+        let preamble = sent[0].content()[0].as_text().unwrap();
+        assert!(preamble.ends_with("They talked about IAM and S3."));
+        assert!(sent[2].content()[0]
+            .as_text()
+            .unwrap()
+            .starts_with("And S3?"));
+
+        // nothing new to summarize yet, and the saved chat keeps every message
+        assert!(!compact(
+            &backend,
+            &mut history,
+            &compaction,
+            &model,
+            &config.context_windows
+        )
+        .await
+        .unwrap());
+        let saved: ConversationHistory =
+            serde_json::from_str(&serde_json::to_string(&history).unwrap()).unwrap();
+        assert_eq!(saved.messages.unwrap().len(), 5);
+        assert_eq!(saved.compaction, Some(expected));
+        assert_eq!(backend.remaining(), 0);
+
+        let prompt = compaction_prompt("fn main() {}", "user:Is {summary} a placeholder?");
+        assert!(prompt.contains("<EARLIER_SUMMARY>\nfn main() {}\n</EARLIER_SUMMARY>"));
+        assert!(prompt.contains("user:Is {summary} a placeholder?\n</CONVERSATON_HISTORY>"));
+    }
+
+    #[tokio::test]
+    async fn summaries_fit_the_helper_model() {
+        let mut config: BedrustConfig = ron::from_str(constants::BEDRUST_CONFIG_FILE).unwrap();
+        config
+            .context_windows
+            .insert("amazon.nova-micro-v1:0".into(), 1_000);
+        // room for about two messages next to the prompt and the summary
+        config
+            .context_windows
+            .insert(constants::CONVERSATION_HISTORY_MODEL_ID.into(), 1_900);
+        config.inference_params.max_tokens = 100;
+        let compaction: CompactionConfig =
+            ron::from_str("(threshold: 0.5, keep_messages: 1)").unwrap();
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Text("IAM".into()),
+            ScriptedTurn::Text("IAM and S3".into()),
+            ScriptedTurn::Text("IAM, S3 and EC2".into()),
+        ]);
+        let model = ChatModel::new("amazon.nova-micro-v1:0", &backend, &config, "", None).await;
+        let questions = ["IAM?", "S3?", "EC2?", "Lambda?"];
+        let mut messages = Vec::new();
+        for (i, question) in questions.iter().enumerate() {
+            messages.push(message(ConversationRole::User, question));
+            if i + 1 < questions.len() {
+                messages.push(message(ConversationRole::Assistant, "Sure"));
+            }
+        }
+        let mut history = ConversationHistory::new(None, None, None, Some(messages));
+
+        // one question and its answer at a time, until the conversation is small enough
+        assert!(compact(
+            &backend,
+            &mut history,
+            &compaction,
+            &model,
+            &config.context_windows
+        )
+        .await
+        .unwrap());
+        let expected = Compaction {
+            summary: "IAM, S3 and EC2".into(),
+            messages: 6,
+        };
+        assert_eq!(history.compaction, Some(expected));
+        let requests = backend.requests();
+        let summarized: Vec<&str> = requests
+            .iter()
+            .map(|r| r.messages[0].content()[0].as_text().unwrap().as_str())
+            .collect();
+        assert!(summarized[0].contains("IAM?") && !summarized[0].contains("S3?"));
+        assert!(summarized[1].contains("<EARLIER_SUMMARY>\nIAM\n"));
+        assert!(summarized[2].contains("EC2?") && !summarized[2].contains("Lambda?"));
+        assert_eq!(backend.remaining(), 0);
+    }
+}
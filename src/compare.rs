@@ -0,0 +1,277 @@
+use colored::*;
+use dialoguer::console::{measure_text_width, pad_str, Alignment, Term};
+use dialoguer::{theme::ColorfulTheme, Select};
+use futures_util::future::join_all;
+
+use crate::chat::{Conversation, ConversationHistory};
+use crate::interrupt::Interrupt;
+use crate::markdown::render_markdown;
+use crate::models::backend::ModelBackend;
+use crate::models::converse_stream::call_converse_stream;
+use crate::models::error::BedrockError;
+use crate::models::fallback::ChatModel;
+use crate::tools::ToolRegistry;
+use crate::usage::TurnRecord;
+
+// NOTE: Compare mode (`--compare`). Every question goes to all the models at the same time, and
+// once they are all done the answers are shown one after the other (or next to each other with
+// `--side-by-side`), with their latency and tokens. The user then picks the answer that goes into
+// the conversation, so the next question is asked to every model with the same history.
+
+// The space between two columns
+const COLUMN_GAP: &str = " │ ";
+
+pub struct Comparison {
+    pub answers: Vec<(String, Result<Conversation, BedrockError>)>,
+}
+
+impl Comparison {
+    // Asks all the models at once. They do not print while streaming, see `ChatOptions::echo`
+    pub async fn run(
+        backend: &dyn ModelBackend,
+        models: &[ChatModel],
+        conversation_history: &ConversationHistory,
+        tools: Option<&ToolRegistry>,
+        interrupt: Option<&Interrupt>,
+    ) -> Comparison {
+        let calls = models.iter().map(|model| async move {
+            call_converse_stream(
+                backend,
+                model.model_id.clone(),
+                conversation_history,
+                &model.options,
+                &model.system,
+                tools.filter(|_| model.tools),
+                interrupt,
+            )
+            .await
+            .map_err(|e| e.with_model(&model.model_id))
+        });
+        let results = join_all(calls).await;
+        Comparison {
+            answers: models
+                .iter()
+                .map(|m| m.model_id.clone())
+                .zip(results)
+                .collect(),
+        }
+    }
+
+    pub fn print(&self, side_by_side: bool, markdown: bool) {
+        if side_by_side {
+            let (_, width) = Term::stdout().size();
+            for line in self.side_by_side_lines(width as usize) {
+                println!("{}", line);
+            }
+            return;
+        }
+        for (i, (model_id, answer)) in self.answers.iter().enumerate() {
+            println!("{}", header(i, model_id).cyan());
+            match answer {
+                Ok(_) if markdown => println!("{}", render_markdown(&body(answer))),
+                _ => println!("{}", body(answer)),
+            }
+            println!("📊 | {}", stats(answer));
+            println!("----------------------------------------");
+        }
+    }
+
+    // The answers in columns, as wide as the terminal lets them be
+    fn side_by_side_lines(&self, width: usize) -> Vec<String> {
+        let count = self.answers.len().max(1);
+        let column =
+            (width.saturating_sub(measure_text_width(COLUMN_GAP) * (count - 1)) / count).max(10);
+        let heads: Vec<Vec<String>> = self
+            .answers
+            .iter()
+            .enumerate()
+            .map(|(i, (model_id, answer))| {
+                let mut lines = wrap(&header(i, model_id), column);
+                lines.extend(wrap(&stats(answer), column));
+                lines
+            })
+            .collect();
+        let bodies: Vec<Vec<String>> = self
+            .answers
+            .iter()
+            .map(|(_, answer)| wrap(&body(answer), column))
+            .collect();
+
+        let mut lines = rows(&heads, column);
+        lines.push(vec!["─".repeat(column); count].join(COLUMN_GAP));
+        lines.extend(rows(&bodies, column));
+        lines
+    }
+
+    // NOTE: Only the picked answer goes into the conversation. The usage of the others is returned
+    // as well, as those tokens were still paid for.
+    pub fn pick(self) -> Result<(Conversation, Vec<TurnRecord>), BedrockError> {
+        let answered: Vec<usize> = (0..self.answers.len())
+            .filter(|i| self.answers[*i].1.is_ok())
+            .collect();
+        let picked = match answered.len() {
+            0 => None,
+            1 => Some(answered[0]),
+            _ => {
+                let items: Vec<String> = answered
+                    .iter()
+                    .map(|i| header(*i, &self.answers[*i].0))
+                    .collect();
+                let selection = Select::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Which answer continues the conversation?")
+                    .default(0)
+                    .items(&items)
+                    .interact()
+                    .unwrap_or_default();
+                Some(answered[selection])
+            }
+        };
+
+        let mut chosen = None;
+        let mut first_error = None;
+        let mut others = Vec::new();
+        for (i, (_, answer)) in self.answers.into_iter().enumerate() {
+            match answer {
+                Ok(convo) if Some(i) == picked => chosen = Some(convo),
+                Ok(convo) => others.extend(convo.usage),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match chosen {
+            Some(convo) => Ok((convo, others)),
+            None => Err(first_error.unwrap_or_else(|| BedrockError::from("No model answered"))),
+        }
+    }
+}
+
+fn header(i: usize, model_id: &str) -> String {
+    format!("[{}] {}", i + 1, model_id)
+}
+
+fn body(answer: &Result<Conversation, BedrockError>) -> String {
+    match answer {
+        Ok(convo) => convo.content.trim().to_string(),
+        Err(e) => format!("🔴 | {}", e),
+    }
+}
+
+fn stats(answer: &Result<Conversation, BedrockError>) -> String {
+    match answer.as_ref().ok().and_then(|c| c.usage.as_ref()) {
+        Some(usage) => format!(
+            "{:.2}s | {} in / {} out",
+            usage.latency_ms as f64 / 1000.0,
+            usage.input_tokens,
+            usage.output_tokens
+        ),
+        None => "no answer".to_string(),
+    }
+}
+
+// Puts the columns next to each other, line by line
+fn rows(columns: &[Vec<String>], width: usize) -> Vec<String> {
+    let rows = columns.iter().map(Vec::len).max().unwrap_or_default();
+    (0..rows)
+        .map(|row| {
+            let cells: Vec<String> = columns
+                .iter()
+                .map(|lines| {
+                    let cell = lines.get(row).map(String::as_str).unwrap_or_default();
+                    pad_str(cell, width, Alignment::Left, None).to_string()
+                })
+                .collect();
+            cells.join(COLUMN_GAP).trim_end().to_string()
+        })
+        .collect()
+}
+
+// Breaks the text into lines of at most `width` columns, on spaces where it can
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            let needed = measure_text_width(&line) + 1 + measure_text_width(word);
+            if !line.is_empty() && needed > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            // words longer than a line are cut up
+            for c in word.chars() {
+                if measure_text_width(&line) + measure_text_width(&c.to_string()) > width {
+                    lines.push(std::mem::take(&mut line));
+                }
+                line.push(c);
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::SerializableMessage;
+    use crate::constants;
+    use crate::models::scripted::{ScriptedBackend, ScriptedTurn};
+    use crate::utils::BedrustConfig;
+    use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, Message};
+
+    #[tokio::test]
+    async fn models_are_compared_side_by_side() {
+        let config: BedrustConfig = ron::from_str(constants::BEDRUST_CONFIG_FILE).unwrap();
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Text("Nova says hello there".into()),
+            ScriptedTurn::Error("Model is not ready".into()),
+            ScriptedTurn::Text("Claude says hi".into()),
+        ]);
+        let mut models = Vec::new();
+        for model_id in ["nova", "llama", "claude"] {
+            let mut model = ChatModel::new(model_id, &backend, &config, "", None).await;
+            model.options.echo = false;
+            models.push(model);
+        }
+        let message = Message::builder()
+            .role(ConversationRole::User)
+            .content(ContentBlock::Text("Hello?".into()))
+            .build()
+            .unwrap();
+        let history = ConversationHistory::new(
+            None,
+            None,
+            None,
+            Some(vec![SerializableMessage::from(message)]),
+        );
+
+        let comparison = Comparison::run(&backend, &models, &history, None, None).await;
+        // every model got the same conversation
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.messages == requests[0].messages));
+
+        let lines = comparison.side_by_side_lines(40);
+        assert_eq!(lines[0], "[1] nova    │ [2] llama   │ [3] claude");
+        assert_eq!(lines[3], "─────────── │ ─────────── │ ───────────");
+        assert_eq!(lines[4], "Nova says   │ 🔴 | Can't  │ Claude says");
+        assert!(lines.iter().all(|l| measure_text_width(l) <= 40));
+
+        // one of them failed, so there is only one to choose from
+        let comparison = Comparison {
+            answers: comparison.answers.into_iter().take(2).collect(),
+        };
+        let (convo, others) = comparison.pick().unwrap();
+        assert_eq!(convo.content, "Nova says hello there");
+        assert!(others.is_empty());
+    }
+
+    #[test]
+    fn wrap_long_words_and_lines() {
+        assert_eq!(wrap("a quick brown fox", 7), ["a quick", "brown", "fox"]);
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("one\n\ntwo", 10), ["one", "", "two"]);
+    }
+}
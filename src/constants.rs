@@ -54,6 +54,24 @@ pub static CONVERSATION_SUMMARY_PROMPT: &str = r#"This is a conversation history
 
 Summary:"#;
 
+pub static COMPACTION_PROMPT: &str = r#"This is the older part of a conversation between a human user and a large language model, it no longer fits in what the model can read. Write a summary the model can continue the conversation from. The summary of the earlier part of the conversation (if any) is enclosed in the <EARLIER_SUMMARY> tags, and the messages that follow it in the <CONVERSATON_HISTORY> tags. Keep the questions asked, the decisions and conclusions reached, and any names, numbers, file names and code details that may come up again. Provide only the summary, without any introductory phrases or explanations.
+
+<EARLIER_SUMMARY>
+{summary}
+</EARLIER_SUMMARY>
+
+<CONVERSATON_HISTORY>
+{messages}
+</CONVERSATON_HISTORY>
+
+Summary:"#;
+
+// sent in place of the summarized messages, as a question and an answer so the roles alternate
+pub static COMPACTION_PREAMBLE: &str =
+    "The older part of our conversation was summarized to save space. Here is the summary:";
+pub static COMPACTION_ACKNOWLEDGEMENT: &str =
+    "Thanks, I will continue the conversation from that summary.";
+
 // INFERENCE CONSTANTS
 lazy_static! {
     pub static ref CONVERSATION_HISTORY_INF_PARAMS: InferenceConfiguration =
@@ -64,6 +82,15 @@ lazy_static! {
             .build();
 }
 
+lazy_static! {
+    pub static ref COMPACTION_INF_PARAMS: InferenceConfiguration =
+        InferenceConfiguration::builder()
+            .max_tokens(1024)
+            .top_p(0.8)
+            .temperature(0.2)
+            .build();
+}
+
 lazy_static! {
     pub static ref CONVERSATION_HISTORY_TITLE_INF_PARAMS: InferenceConfiguration =
         InferenceConfiguration::builder()
@@ -77,6 +104,113 @@ lazy_static! {
 // FIX: the model id is hardcoded, we need to make this configurable
 pub static PROJECT_GUESS_MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
 pub static CONVERSATION_HISTORY_MODEL_ID: &str = "anthropic.claude-3-haiku-20240307-v1:0";
+// Models that support tool use (function calling) through the Converse API.
+// GetFoundationModel does not tell us this, so it is just a list of model id prefixes.
+pub static TOOL_USE_MODEL_PREFIXES: &[&str] = &[
+    "anthropic.claude-3",
+    "anthropic.claude-sonnet-4",
+    "anthropic.claude-opus-4",
+    "amazon.nova",
+    "cohere.command-r",
+    "meta.llama3-1",
+    "meta.llama3-2-11b",
+    "meta.llama3-2-90b",
+    "meta.llama3-3",
+    "meta.llama4",
+    "mistral.mistral-large",
+    "mistral.mistral-small",
+    "ai21.jamba",
+    "writer.palmyra",
+];
+// Models that support prompt caching (cache points) through the Converse API
+pub static PROMPT_CACHING_MODEL_PREFIXES: &[&str] = &[
+    "anthropic.claude-3-7-sonnet",
+    "anthropic.claude-3-5-haiku",
+    "anthropic.claude-sonnet-4",
+    "anthropic.claude-opus-4",
+    "amazon.nova-micro",
+    "amazon.nova-lite",
+    "amazon.nova-pro",
+    "amazon.nova-premier",
+];
+// Models that think before answering when given a reasoning budget
+pub static REASONING_MODEL_PREFIXES: &[&str] = &[
+    "anthropic.claude-3-7-sonnet",
+    "anthropic.claude-sonnet-4",
+    "anthropic.claude-opus-4",
+];
+// Models that can read documents (PDF, CSV, DOCX, ...) sent along with the question
+pub static DOCUMENT_MODEL_PREFIXES: &[&str] = &[
+    "anthropic.claude-3",
+    "anthropic.claude-sonnet-4",
+    "anthropic.claude-opus-4",
+    "amazon.nova-lite",
+    "amazon.nova-pro",
+    "amazon.nova-premier",
+    "cohere.command-r",
+    "meta.llama3",
+    "meta.llama4",
+    "mistral.mistral-large",
+    "mistral.mistral-small",
+    "ai21.jamba",
+    "deepseek.r1",
+];
+// Bedrock limits for documents: how big each one can be, and how many a conversation can have
+pub const MAX_DOCUMENT_BYTES: u64 = 4_500_000;
+pub const MAX_DOCUMENTS: usize = 5;
+// Image models that take the TEXT_IMAGE task through InvokeModel
+pub static IMAGE_GENERATION_MODEL_PREFIXES: &[&str] =
+    &["amazon.nova-canvas", "amazon.titan-image-generator"];
+pub static DEFAULT_IMAGE_MODEL_ID: &str = "amazon.nova-canvas-v1:0";
+// how many images one call can make, and the largest seed both Nova Canvas and Titan take
+pub const MAX_GENERATED_IMAGES: u32 = 5;
+pub const MAX_IMAGE_SEED: u32 = 858_993_459;
+// Embedding models used to search the saved chats
+pub static DEFAULT_EMBEDDING_MODEL_ID: &str = "amazon.titan-embed-text-v2:0";
+// Cohere takes at most 2048 characters per text, so the chats are embedded in chunks
+pub const EMBEDDING_CHUNK_CHARS: usize = 2000;
+// long chats (ie code chats) only get their first chunks embedded
+pub const MAX_EMBEDDING_CHUNKS: usize = 32;
+// Context windows (in tokens), matched on the base model id. The first prefix that matches wins,
+// so the more specific ones come first. `context_windows` in the config has the last word
+pub static MODEL_CONTEXT_WINDOWS: &[(&str, usize)] = &[
+    ("anthropic.claude-v2:1", 200_000),
+    ("anthropic.claude-v2", 100_000),
+    ("anthropic.claude-instant", 100_000),
+    ("anthropic.claude", 200_000),
+    ("amazon.nova-micro", 128_000),
+    ("amazon.nova-lite", 300_000),
+    ("amazon.nova-pro", 300_000),
+    ("amazon.nova-premier", 1_000_000),
+    ("amazon.titan-text-premier", 32_000),
+    ("amazon.titan-text-express", 8_000),
+    ("amazon.titan-text-lite", 4_000),
+    ("meta.llama2", 4_096),
+    ("meta.llama3-8b", 8_000),
+    ("meta.llama3-70b", 8_000),
+    ("meta.llama3", 128_000),
+    ("mistral.mistral-large-2407", 128_000),
+    ("mistral.", 32_000),
+    ("cohere.command-r", 128_000),
+    ("cohere.command", 4_000),
+    ("ai21.jamba", 256_000),
+    ("ai21.j2", 8_191),
+    ("deepseek.r1", 128_000),
+];
+// for the models we know nothing about
+pub const DEFAULT_CONTEXT_WINDOW: usize = 32_000;
+// the token estimates are rough, so only this much of the context window is filled
+pub const CONTEXT_WINDOW_MARGIN: f64 = 0.9;
+// summarize the older messages once the conversation takes up this much of the context window,
+// and always send this many of the newest messages as they are
+pub const DEFAULT_COMPACTION_THRESHOLD: f64 = 0.75;
+pub const DEFAULT_COMPACTION_KEEP_MESSAGES: usize = 6;
+// how many batch prompts are asked at the same time, unless `--concurrency` says otherwise
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+// the smallest reasoning budget the models accept
+pub const MIN_REASONING_BUDGET: i32 = 1024;
+// Cross region inference profiles are the model id with a geo prefix in front
+pub static CROSS_REGION_PREFIXES: &[&str] = &["us.", "eu.", "apac.", "us-gov."];
 pub static CODE_IGNORE_DIRS: &[&str] = &[
     // Rust
     "target",
@@ -118,15 +252,27 @@ pub static CODE_IGNORE_DIRS: &[&str] = &[
     ".terraform",
 ];
 
+// the region used when there is none configured anywhere
+pub static DEFAULT_REGION: &str = "us-east-1";
+
 // CONFIGURATION FILES
 pub static CONFIG_DIR_NAME: &str = "bedrust";
 pub static MODEL_CONFIG_FILE_NAME: &str = "model_config.ron";
 pub static BEDRUST_CONFIG_FILE_NAME: &str = "bedrust_config.ron";
+pub static MODEL_CATALOG_FILE_NAME: &str = "model_catalog.json";
+pub static CHAT_INDEX_FILE_NAME: &str = "chat_index.json";
+// how long the cached list of models is used before we ask Bedrock again
+pub const MODEL_CATALOG_MAX_AGE_HOURS: i64 = 24;
 
-// UPDATED: 2025-03-18
+// UPDATED: 2026-10-18
 pub static BEDRUST_CONFIG_FILE: &str = r#"BedrustConfig(
   // define what AWS profile to use
   aws_profile: "default",
+  // the AWS region to use. When not set, it is the one of the AWS profile (or us-east-1)
+  region: None,
+  // models that need to be called in a specific region, ie models that are only available there
+  // "anthropic.claude-3-7-sonnet-20250219-v1:0": "eu-central-1",
+  model_regions: {},
   // what image extensions do you wish to support when running captioning
   supported_images: ["jpg", "jpeg", "png", "bmp",],
   // the prompt being used for image captioning
@@ -138,9 +284,80 @@ pub static BEDRUST_CONFIG_FILE: &str = r#"BedrustConfig(
     max_tokens: 2048,
     top_p: 0.8, 
   ),
-  system_prompt: Some("You are helpful assistant.")
+  // override the inference parameters for a provider ("anthropic", "amazon", "meta", ...) or a
+  // model. Model specific fields (ie top_k) go in `additional_fields`. For example:
+  // "anthropic": (top_p: Some(0.9), additional_fields: Some({"top_k": 250})),
+  // "amazon.nova-micro-v1:0": (temperature: Some(0.2), stop_sequences: Some(["</answer>"])),
+  model_params: {},
+  // your own short names for models, use them with `--model-id` or as the `default_model`
+  model_aliases: {
+    "sonnet": "us.anthropic.claude-3-7-sonnet-20250219-v1:0",
+  },
+  system_prompt: Some("You are helpful assistant."),
+  // let the model use the built-in tools (current time, and reading files when using `--source`)
+  enable_tools: true,
+  // cache the system prompt and the source code (with `--source`), for the models that support it
+  prompt_caching: true,
+  // let the models that support it (Claude 3.7 Sonnet, Claude 4) think before answering, with up
+  // to this many tokens (at least 1024). ie `reasoning_budget: Some(4096),`
+  reasoning_budget: None,
+  // show what the model is thinking while it streams in (toggle it in the chat with `/t`)
+  show_reasoning: true,
+  // render the markdown in the answers, with syntax highlighting for code. `--raw` turns it off
+  render_markdown: true,
+  // calls that fail because of throttling, timeouts or a model that is not ready are retried with
+  // an exponential backoff. Set max_attempts to 1 to turn retries off
+  retry: (
+    max_attempts: 4,
+    initial_delay_ms: 1000,
+    max_delay_ms: 20000,
+    max_elapsed_secs: 60,
+    jitter: true,
+  ),
+  // prices in USD per 1000 tokens, used to estimate the cost of a chat. Check the Amazon Bedrock
+  // pricing page for up to date prices, and add any model you use
+  model_prices: {
+    "anthropic.claude-3-7-sonnet-20250219-v1:0": (input_per_1k: 0.003, output_per_1k: 0.015),
+    "anthropic.claude-3-5-sonnet-20241022-v2:0": (input_per_1k: 0.003, output_per_1k: 0.015),
+    "anthropic.claude-3-5-haiku-20241022-v1:0": (input_per_1k: 0.0008, output_per_1k: 0.004),
+    "amazon.nova-pro-v1:0": (input_per_1k: 0.0008, output_per_1k: 0.0032),
+    "amazon.nova-lite-v1:0": (input_per_1k: 0.00006, output_per_1k: 0.00024),
+    "amazon.nova-micro-v1:0": (input_per_1k: 0.000035, output_per_1k: 0.00014),
+  },
+  // Amazon Bedrock Guardrails to apply to every call, per AWS profile. For example:
+  // "default": (identifier: "abc123xyz", version: "1", trace: true),
+  guardrails: {},
+  // Models to fall back to (in order) when the model is throttled or unavailable, per AWS profile.
+  // Model ids and aliases both work. For example:
+  // "default": ["us.amazon.nova-pro-v1:0", "haiku"],
+  fallback_models: {},
+  // context windows (in tokens) of the models Bedrust does not know, or gets wrong. For example:
+  // "amazon.nova-micro-v1:0": 128000,
+  context_windows: {},
+  // when the conversation no longer fits in the context window of the model: leave the oldest
+  // messages out (DropOldest), or refuse to send it (Refuse). The saved chat keeps everything
+  context_policy: DropOldest,
+  // summarize the older messages (with the helper model) once the conversation takes up this
+  // much of the context window, so long chats can keep going. The summary is sent instead of
+  // them, the saved chat keeps every message. For example:
+  // Some((threshold: 0.75, keep_messages: 6)),
+  compaction: None,
+  // the model used to search the saved chats (`--search-chats`, `/r <query>`): Amazon Titan Text
+  // Embeddings or Cohere Embed. The embeddings are kept in chat_index.json, next to this file
+  embedding_model: "amazon.titan-embed-text-v2:0",
+  // image generation with `--generate-image` and `/imagine` (Amazon Nova Canvas or Titan Image
+  // Generator). Every prompt is added to the manifest.json in the output directory
+  image_generation: (
+    model_id: "amazon.nova-canvas-v1:0",
+    width: 1024,
+    height: 1024,
+    count: 1,
+    output_dir: "bedrust_images",
+  ),
 )
 "#;
+// the syntect theme for the code blocks in the answers
+pub static CODE_THEME: &str = "base16-ocean.dark";
 // FIGLET FONT
 pub static FIGLET_FONT_FILENAME: &str = "ansishadow.flf";
 pub const FIGLET_FONT: &str = include_str!("../resources/ansishadow.flf");
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use aws_sdk_bedrockruntime::error::BuildError;
+use aws_sdk_bedrockruntime::types::{
+    GuardrailAssessment, GuardrailConfiguration, GuardrailStreamConfiguration,
+    GuardrailStreamProcessingMode, GuardrailTrace, GuardrailTraceAssessment,
+};
+use colored::*;
+use serde::{Deserialize, Serialize};
+
+// NOTE: Amazon Bedrock Guardrails. The guardrail is configured per AWS profile in
+// `bedrust_config.ron` (ie one for the work account, none for the personal one), and when there
+// is one it is applied to every call Bedrust makes. When the guardrail steps in we say so in the
+// chat, and with `trace: true` we also show (and save with the chat) what it found.
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GuardrailConfig {
+    // the id or the ARN of the guardrail
+    pub identifier: String,
+    // a version number, or DRAFT
+    #[serde(default = "_default_version")]
+    pub version: String,
+    // ask Bedrock what the guardrail found, even if it did not intervene
+    #[serde(default)]
+    pub trace: bool,
+    // when streaming, show the answer while the guardrail is still checking it. Faster, but the
+    // user can see text the guardrail would have blocked
+    #[serde(default)]
+    pub async_streaming: bool,
+}
+
+fn _default_version() -> String {
+    "DRAFT".to_string()
+}
+
+impl GuardrailConfig {
+    // The guardrail for the AWS profile we are using, if it has one
+    pub fn for_profile(
+        guardrails: &HashMap<String, GuardrailConfig>,
+        profile: &str,
+    ) -> Option<GuardrailConfig> {
+        guardrails.get(profile).cloned()
+    }
+
+    fn trace_mode(&self) -> GuardrailTrace {
+        if self.trace {
+            GuardrailTrace::Enabled
+        } else {
+            GuardrailTrace::Disabled
+        }
+    }
+
+    pub fn to_configuration(&self) -> Result<GuardrailConfiguration, BuildError> {
+        GuardrailConfiguration::builder()
+            .guardrail_identifier(&self.identifier)
+            .guardrail_version(&self.version)
+            .trace(self.trace_mode())
+            .build()
+    }
+
+    pub fn to_stream_configuration(&self) -> Result<GuardrailStreamConfiguration, BuildError> {
+        let mode = if self.async_streaming {
+            GuardrailStreamProcessingMode::Async
+        } else {
+            GuardrailStreamProcessingMode::Sync
+        };
+        GuardrailStreamConfiguration::builder()
+            .guardrail_identifier(&self.identifier)
+            .guardrail_version(&self.version)
+            .trace(self.trace_mode())
+            .stream_processing_mode(mode)
+            .build()
+    }
+}
+
+// One thing the guardrail found, in the question or in the answer
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct GuardrailFinding {
+    // `input` or `output`
+    pub source: String,
+    // topic, content, word, sensitive information or contextual grounding
+    pub policy: String,
+    // the topic, the filter type, the word, ...
+    pub name: String,
+    // what the guardrail did about it (ie BLOCKED, ANONYMIZED, NONE)
+    pub action: String,
+}
+
+// What the guardrail did during a single question, saved along with the chat
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct GuardrailTraceRecord {
+    pub intervened: bool,
+    #[serde(default)]
+    pub findings: Vec<GuardrailFinding>,
+}
+
+impl GuardrailTraceRecord {
+    pub fn is_empty(&self) -> bool {
+        !self.intervened && self.findings.is_empty()
+    }
+
+    // A question can be made of a few model calls (tool use), so we keep adding them up
+    pub fn add_trace(&mut self, trace: &GuardrailTraceAssessment) {
+        if let Some(input) = trace.input_assessment() {
+            let mut guardrails: Vec<_> = input.iter().collect();
+            guardrails.sort_by(|a, b| a.0.cmp(b.0));
+            for (_, assessment) in guardrails {
+                self.findings.extend(findings("input", assessment));
+            }
+        }
+        if let Some(outputs) = trace.output_assessments() {
+            let mut guardrails: Vec<_> = outputs.iter().collect();
+            guardrails.sort_by(|a, b| a.0.cmp(b.0));
+            for assessment in guardrails.into_iter().flat_map(|(_, a)| a) {
+                self.findings.extend(findings("output", assessment));
+            }
+        }
+    }
+}
+
+fn findings(source: &str, assessment: &GuardrailAssessment) -> Vec<GuardrailFinding> {
+    let finding = |policy: &str, name: &str, action: &str| GuardrailFinding {
+        source: source.to_string(),
+        policy: policy.to_string(),
+        name: name.to_string(),
+        action: action.to_string(),
+    };
+    let mut findings = Vec::new();
+    if let Some(policy) = assessment.topic_policy() {
+        for topic in policy.topics() {
+            findings.push(finding("topic", topic.name(), topic.action().as_str()));
+        }
+    }
+    if let Some(policy) = assessment.content_policy() {
+        for filter in policy.filters() {
+            findings.push(finding(
+                "content",
+                filter.r#type().as_str(),
+                filter.action().as_str(),
+            ));
+        }
+    }
+    if let Some(policy) = assessment.word_policy() {
+        for word in policy.custom_words() {
+            findings.push(finding("word", word.r#match(), word.action().as_str()));
+        }
+        for word in policy.managed_word_lists() {
+            findings.push(finding(
+                "word",
+                word.r#type().as_str(),
+                word.action().as_str(),
+            ));
+        }
+    }
+    if let Some(policy) = assessment.sensitive_information_policy() {
+        for entity in policy.pii_entities() {
+            findings.push(finding(
+                "sensitive information",
+                entity.r#type().as_str(),
+                entity.action().as_str(),
+            ));
+        }
+        for regex in policy.regexes() {
+            findings.push(finding(
+                "sensitive information",
+                regex.name().unwrap_or("regex"),
+                regex.action().as_str(),
+            ));
+        }
+    }
+    if let Some(policy) = assessment.contextual_grounding_policy() {
+        for filter in policy.filters() {
+            findings.push(finding(
+                "contextual grounding",
+                &format!(
+                    "{} ({:.2} < {:.2})",
+                    filter.r#type().as_str(),
+                    filter.score(),
+                    filter.threshold()
+                ),
+                filter.action().as_str(),
+            ));
+        }
+    }
+    findings
+}
+
+pub fn print_guardrail_trace(record: &GuardrailTraceRecord) {
+    if record.intervened {
+        println!(
+            "{}",
+            "🛡️  | The guardrail intervened, this is not an answer from the model".red()
+        );
+    }
+    for finding in &record.findings {
+        println!(
+            "🛡️  | {} {} policy: {} ({})",
+            finding.source, finding.policy, finding.name, finding.action
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_bedrockruntime::types::{
+        GuardrailContentFilter, GuardrailContentFilterConfidence, GuardrailContentFilterType,
+        GuardrailContentPolicyAction, GuardrailContentPolicyAssessment, GuardrailTopic,
+        GuardrailTopicPolicyAction, GuardrailTopicPolicyAssessment, GuardrailTopicType,
+    };
+
+    #[test]
+    fn guardrail_config_and_trace() {
+        let guardrails: HashMap<String, GuardrailConfig> = ron::from_str(
+            r#"{"work": (identifier: "gr-123", trace: true), "other": (identifier: "gr-456", version: "2", async_streaming: true)}"#,
+        )
+        .unwrap();
+        assert_eq!(GuardrailConfig::for_profile(&guardrails, "default"), None);
+        let work = GuardrailConfig::for_profile(&guardrails, "work").unwrap();
+        let config = work.to_configuration().unwrap();
+        assert_eq!(config.guardrail_version(), "DRAFT");
+        assert_eq!(config.trace(), &GuardrailTrace::Enabled);
+        let other = GuardrailConfig::for_profile(&guardrails, "other").unwrap();
+        let config = other.to_stream_configuration().unwrap();
+        assert_eq!(config.guardrail_identifier(), "gr-456");
+        assert_eq!(config.trace(), &GuardrailTrace::Disabled);
+        assert_eq!(
+            config.stream_processing_mode(),
+            &GuardrailStreamProcessingMode::Async
+        );
+
+        let input = GuardrailAssessment::builder()
+            .topic_policy(
+                GuardrailTopicPolicyAssessment::builder()
+                    .topics(
+                        GuardrailTopic::builder()
+                            .name("Investment advice")
+                            .r#type(GuardrailTopicType::Deny)
+                            .action(GuardrailTopicPolicyAction::Blocked)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        let output = GuardrailAssessment::builder()
+            .content_policy(
+                GuardrailContentPolicyAssessment::builder()
+                    .filters(
+                        GuardrailContentFilter::builder()
+                            .r#type(GuardrailContentFilterType::Insults)
+                            .confidence(GuardrailContentFilterConfidence::Low)
+                            .action(GuardrailContentPolicyAction::Blocked)
+                            .build()
+                            .unwrap(),
+                    )
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        let trace = GuardrailTraceAssessment::builder()
+            .input_assessment("gr-123", input)
+            .output_assessments("gr-123", vec![output])
+            .build();
+
+        let mut record = GuardrailTraceRecord::default();
+        assert!(record.is_empty());
+        record.add_trace(&trace);
+        assert!(!record.is_empty());
+        assert_eq!(
+            record.findings,
+            vec![
+                GuardrailFinding {
+                    source: "input".into(),
+                    policy: "topic".into(),
+                    name: "Investment advice".into(),
+                    action: "BLOCKED".into(),
+                },
+                GuardrailFinding {
+                    source: "output".into(),
+                    policy: "content".into(),
+                    name: "INSULTS".into(),
+                    action: "BLOCKED".into(),
+                },
+            ]
+        );
+    }
+}
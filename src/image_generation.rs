@@ -0,0 +1,430 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Local;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::constants::{DEFAULT_IMAGE_MODEL_ID, MAX_GENERATED_IMAGES, MAX_IMAGE_SEED};
+use crate::interrupt::Interrupt;
+use crate::models::backend::ModelBackend;
+use crate::models::error::{BedrockError, BedrockErrorKind};
+use crate::models::supports_image_generation;
+
+// NOTE: Image generation (`--generate-image` and `/imagine` in the chat). Nova Canvas and Titan
+// Image Generator are not available through Converse, so they are called with InvokeModel and
+// the TEXT_IMAGE task, which both of them understand. The images are written as PNGs to the
+// output directory, and every prompt (with the parameters and the seed, so it can be made again)
+// is added to the `manifest.json` in there.
+
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+// The defaults, from `image_generation` in `bedrust_config.ron`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ImageGenerationConfig {
+    pub model_id: String,
+    pub width: u32,
+    pub height: u32,
+    // how many images every prompt makes
+    pub count: u32,
+    // where the images and their manifest go
+    pub output_dir: PathBuf,
+}
+
+impl Default for ImageGenerationConfig {
+    fn default() -> Self {
+        ImageGenerationConfig {
+            model_id: DEFAULT_IMAGE_MODEL_ID.to_string(),
+            width: 1024,
+            height: 1024,
+            count: 1,
+            output_dir: PathBuf::from("bedrust_images"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ImageRequest {
+    pub model_id: String,
+    pub prompt: String,
+    // what the images should not have
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub seed: u32,
+    pub count: u32,
+}
+
+impl ImageRequest {
+    // A random seed, unless one is set later. It ends up in the manifest either way.
+    pub fn new(prompt: &str, config: &ImageGenerationConfig) -> Self {
+        ImageRequest {
+            model_id: config.model_id.clone(),
+            prompt: prompt.trim().to_string(),
+            negative_prompt: None,
+            width: config.width,
+            height: config.height,
+            seed: rand::rng().random_range(0..=MAX_IMAGE_SEED),
+            count: config.count,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !supports_image_generation(&self.model_id) {
+            bail!(
+                "`{}` can not generate images, use Amazon Nova Canvas or Titan Image Generator",
+                self.model_id
+            );
+        }
+        if self.prompt.is_empty() {
+            bail!("Describe the image you want, the prompt is empty");
+        }
+        if !(1..=MAX_GENERATED_IMAGES).contains(&self.count) {
+            bail!(
+                "Between 1 and {} images can be made at once",
+                MAX_GENERATED_IMAGES
+            );
+        }
+        if self.seed > MAX_IMAGE_SEED {
+            bail!("The seed can be at most {}", MAX_IMAGE_SEED);
+        }
+        Ok(())
+    }
+
+    // The InvokeModel body
+    pub fn body(&self) -> Value {
+        let mut text_to_image = json!({ "text": self.prompt });
+        if let Some(negative_prompt) = &self.negative_prompt {
+            text_to_image["negativeText"] = json!(negative_prompt);
+        }
+        json!({
+            "taskType": "TEXT_IMAGE",
+            "textToImageParams": text_to_image,
+            "imageGenerationConfig": {
+                "numberOfImages": self.count,
+                "width": self.width,
+                "height": self.height,
+                "seed": self.seed,
+            },
+        })
+    }
+}
+
+// What the image models answer with
+#[derive(Debug, Deserialize)]
+struct ImageResponse {
+    #[serde(default)]
+    images: Vec<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+// One line of the manifest
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    pub created: String,
+    #[serde(flatten)]
+    pub request: ImageRequest,
+    // relative to the output directory
+    pub files: Vec<String>,
+}
+
+// `1024x768` -> (1024, 768)
+pub fn parse_size(size: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("`{}` is not a size, use WIDTHxHEIGHT (ie 1024x1024)", size);
+    let size = size.to_lowercase();
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    let width: u32 = width.trim().parse().map_err(|_| invalid())?;
+    let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+    Ok((width, height))
+}
+
+fn option_value<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+    option: &str,
+) -> Result<&'a str, anyhow::Error> {
+    words
+        .next()
+        .ok_or_else(|| anyhow!("`{}` needs a value", option))
+}
+
+// `/imagine a cat on a sofa --no dogs --size 1280x720 --seed 42 --count 2` -> the request. The
+// options can go anywhere, `--no` takes the words up to the next option.
+pub fn parse_imagine(
+    text: &str,
+    config: &ImageGenerationConfig,
+) -> Result<ImageRequest, anyhow::Error> {
+    let (mut prompt, mut negative) = (Vec::new(), Vec::new());
+    let mut in_negative = false;
+    let mut request = ImageRequest::new("", config);
+    let mut words = text.split_whitespace();
+    while let Some(word) = words.next() {
+        match word {
+            "--no" => in_negative = true,
+            "--size" => {
+                let size = option_value(&mut words, word)?;
+                (request.width, request.height) = parse_size(size).map_err(|e| anyhow!(e))?;
+                in_negative = false;
+            }
+            "--seed" => {
+                let seed = option_value(&mut words, word)?;
+                request.seed = seed
+                    .parse()
+                    .map_err(|_| anyhow!("`{}` is not a seed, use a number", seed))?;
+                in_negative = false;
+            }
+            "--count" => {
+                let count = option_value(&mut words, word)?;
+                request.count = count
+                    .parse()
+                    .map_err(|_| anyhow!("`{}` is not a number of images", count))?;
+                in_negative = false;
+            }
+            _ if in_negative => negative.push(word),
+            _ => prompt.push(word),
+        }
+    }
+    request.prompt = prompt.join(" ");
+    if !negative.is_empty() {
+        request.negative_prompt = Some(negative.join(" "));
+    }
+    Ok(request)
+}
+
+// Asks the model for the images. `None` when Ctrl-C stopped it.
+pub async fn generate_images(
+    backend: &dyn ModelBackend,
+    request: &ImageRequest,
+    interrupt: Option<&Interrupt>,
+) -> Result<Option<Vec<Vec<u8>>>, BedrockError> {
+    let body = request.body().to_string().into_bytes();
+    let _streaming = interrupt.map(Interrupt::streaming);
+    let call = backend.retry_policy().run("Image generation", || {
+        backend.invoke_model(&request.model_id, body.clone())
+    });
+    let output = match interrupt {
+        Some(interrupt) => tokio::select! {
+            biased;
+            _ = interrupt.requested() => return Ok(None),
+            output = call => output?,
+        },
+        None => call.await?,
+    };
+
+    let invalid = |message: String| {
+        BedrockError::new(BedrockErrorKind::InvalidResponse, message).with_model(&request.model_id)
+    };
+    let response: ImageResponse = serde_json::from_slice(&output)
+        .map_err(|e| invalid(format!("Unable to read the generated images: {}", e)))?;
+    if let Some(error) = response.error {
+        return Err(
+            BedrockError::new(BedrockErrorKind::ModelError, error).with_model(&request.model_id)
+        );
+    }
+    if response.images.is_empty() {
+        return Err(invalid("The model sent no images".to_string()));
+    }
+    let images = response
+        .images
+        .iter()
+        .map(|image| general_purpose::STANDARD.decode(image))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(format!("The model sent a broken image: {}", e)))?;
+    Ok(Some(images))
+}
+
+// Writes the images as PNGs, and adds them to the manifest
+pub fn save_images(
+    output_dir: &Path,
+    request: &ImageRequest,
+    images: &[Vec<u8>],
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    fs::create_dir_all(output_dir)?;
+    let now = Local::now();
+    let stamp = now.format("%Y%m%d_%H%M%S");
+    let mut paths = Vec::new();
+    for (i, image) in images.iter().enumerate() {
+        // the same seed twice in a second (ie `--seed`) gets a number, so nothing is overwritten
+        let name = format!("{}_{}_{}", stamp, request.seed, i + 1);
+        let mut path = output_dir.join(format!("{}.png", name));
+        let mut copy = 1;
+        while path.exists() {
+            copy += 1;
+            path = output_dir.join(format!("{}-{}.png", name, copy));
+        }
+        fs::write(&path, image)?;
+        paths.push(path);
+    }
+
+    let manifest_path = output_dir.join(MANIFEST_FILE_NAME);
+    let mut manifest: Vec<ManifestEntry> = match fs::read_to_string(&manifest_path) {
+        Ok(content) => serde_json::from_str(&content).map_err(|e| {
+            anyhow!(
+                "`{}` is not an image manifest: {}",
+                manifest_path.display(),
+                e
+            )
+        })?,
+        Err(_) => Vec::new(),
+    };
+    manifest.push(ManifestEntry {
+        created: now.to_rfc3339(),
+        request: request.clone(),
+        files: paths
+            .iter()
+            .filter_map(|p| p.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect(),
+    });
+    fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    Ok(paths)
+}
+
+// Generates and saves the images, telling the user how it went
+pub async fn imagine(
+    backend: &dyn ModelBackend,
+    request: &ImageRequest,
+    output_dir: &Path,
+    interrupt: Option<&Interrupt>,
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    request.validate()?;
+    println!(
+        "🎨 | Generating {} image(s) of {}x{} with {} (seed {})",
+        request.count, request.width, request.height, request.model_id, request.seed
+    );
+    let Some(images) = generate_images(backend, request, interrupt).await? else {
+        println!("⏹️  | Stopped, no images were saved");
+        return Ok(Vec::new());
+    };
+    let paths = save_images(output_dir, request, &images)?;
+    for path in &paths {
+        println!("🖼️  | Saved: {}", path.display());
+    }
+    println!(
+        "📒 | The prompt and parameters are in {}",
+        output_dir.join(MANIFEST_FILE_NAME).display()
+    );
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::retry::RetryPolicy;
+    use crate::models::scripted::{ScriptedBackend, ScriptedTurn};
+    use crate::utils::test_dir;
+
+    #[test]
+    fn image_requests() {
+        assert_eq!(parse_size("1280x720"), Ok((1280, 720)));
+        assert_eq!(parse_size("512X512"), Ok((512, 512)));
+        assert!(parse_size("1024").is_err());
+        assert!(parse_size("axb").is_err());
+        let config = ImageGenerationConfig::default();
+        let request = parse_imagine(" a cat on a sofa --no dogs, people ", &config).unwrap();
+        assert_eq!(request.prompt, "a cat on a sofa");
+        assert_eq!(request.negative_prompt.as_deref(), Some("dogs, people"));
+        let request = parse_imagine("a cat --no ", &config).unwrap();
+        assert_eq!(
+            (request.prompt.as_str(), request.negative_prompt),
+            ("a cat", None)
+        );
+        let request = parse_imagine(
+            "a cat --no dogs --size 1280x720 on a sofa --seed 42 --count 2",
+            &config,
+        )
+        .unwrap();
+        assert_eq!(request.prompt, "a cat on a sofa");
+        assert_eq!(request.negative_prompt.as_deref(), Some("dogs"));
+        assert_eq!(
+            (request.width, request.height, request.seed, request.count),
+            (1280, 720, 42, 2)
+        );
+        assert!(parse_imagine("a cat --size big", &config).is_err());
+        assert!(parse_imagine("a cat --seed", &config).is_err());
+
+        let mut request = ImageRequest::new("a red bicycle", &ImageGenerationConfig::default());
+        assert!(request.seed <= MAX_IMAGE_SEED);
+        request.seed = 42;
+        request.negative_prompt = Some("people".into());
+        assert_eq!(
+            request.body(),
+            json!({
+                "taskType": "TEXT_IMAGE",
+                "textToImageParams": { "text": "a red bicycle", "negativeText": "people" },
+                "imageGenerationConfig": {
+                    "numberOfImages": 1, "width": 1024, "height": 1024, "seed": 42
+                },
+            })
+        );
+        assert!(request.validate().is_ok());
+        request.count = 6;
+        assert!(request.validate().is_err());
+        request.count = 1;
+        request.model_id = "amazon.nova-pro-v1:0".into();
+        assert!(request.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn images_are_saved_with_a_manifest() {
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            initial_delay_ms: 1,
+            jitter: false,
+            ..RetryPolicy::default()
+        };
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Throttled,
+            ScriptedTurn::Images,
+            ScriptedTurn::Images,
+            ScriptedTurn::Error("ValidationException: blocked by our content filters".into()),
+        ])
+        .with_retry_policy(policy);
+        let output_dir = test_dir("images");
+
+        let config = ImageGenerationConfig {
+            model_id: "amazon.titan-image-generator-v2:0".into(),
+            count: 2,
+            ..ImageGenerationConfig::default()
+        };
+        let request = ImageRequest::new("a lighthouse at night", &config);
+        // the throttled call is retried
+        let paths = imagine(&backend, &request, &output_dir, None)
+            .await
+            .unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(fs::read(&paths[0]).unwrap().starts_with(b"\x89PNG"));
+        let (model_id, body) = backend.invocations().pop().unwrap();
+        assert_eq!(model_id, "amazon.titan-image-generator-v2:0");
+        assert_eq!(body, request.body());
+
+        // every prompt is added to the manifest
+        let mut second = ImageRequest::new("a lighthouse at dawn", &config);
+        second.count = 1;
+        imagine(&backend, &second, &output_dir, None).await.unwrap();
+        let manifest: Vec<ManifestEntry> =
+            serde_json::from_str(&fs::read_to_string(output_dir.join(MANIFEST_FILE_NAME)).unwrap())
+                .unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest[0].request, request);
+        assert_eq!(manifest[0].files.len(), 2);
+        assert_eq!(manifest[1].request.prompt, "a lighthouse at dawn");
+
+        // the same seed again does not overwrite the images
+        let again = save_images(&output_dir, &second, &[b"\x89PNG".to_vec()]).unwrap();
+        let twice = save_images(&output_dir, &second, &[b"\x89PNG".to_vec()]).unwrap();
+        assert_ne!(again, twice);
+        assert!(again[0].exists() && twice[0].exists());
+
+        let err = imagine(&backend, &second, &output_dir, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("content filters"));
+        fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
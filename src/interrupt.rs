@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+
+// NOTE: Ctrl-C handling for the chat. While an answer is streaming, Ctrl-C stops it and we keep
+// whatever the model said so far. Anywhere else (ie at the `Human:` prompt) Ctrl-C exits, like it
+// always did. Once we listen for Ctrl-C the default handler is gone, so exiting is up to us.
+
+#[derive(Default)]
+struct InterruptState {
+    // how many answers are streaming right now (more than one when comparing models)
+    streaming: AtomicUsize,
+    // Ctrl-C was pressed while streaming
+    requested: AtomicBool,
+    notify: Notify,
+}
+
+#[derive(Clone, Default)]
+pub struct Interrupt {
+    state: Arc<InterruptState>,
+}
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Interrupt::default()
+    }
+
+    // Starts listening for Ctrl-C, for as long as Bedrust runs
+    pub fn listen(&self) {
+        let interrupt = self.clone();
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if interrupt.state.streaming.load(Ordering::SeqCst) > 0 {
+                    interrupt.trigger();
+                } else {
+                    println!();
+                    println!("Bye!");
+                    std::process::exit(130);
+                }
+            }
+        });
+    }
+
+    // Asks the current answer to stop
+    pub fn trigger(&self) {
+        self.state.requested.store(true, Ordering::SeqCst);
+        self.state.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    // Ctrl-C stops the answer (instead of exiting) until the guard is dropped
+    pub fn streaming(&self) -> StreamingGuard<'_> {
+        self.state.streaming.fetch_add(1, Ordering::SeqCst);
+        StreamingGuard { interrupt: self }
+    }
+
+    // Resolves once Ctrl-C was pressed while streaming
+    pub async fn requested(&self) {
+        loop {
+            let notified = self.state.notify.notified();
+            tokio::pin!(notified);
+            // register before checking, so we do not miss a Ctrl-C in between
+            notified.as_mut().enable();
+            if self.is_requested() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+pub struct StreamingGuard<'a> {
+    interrupt: &'a Interrupt,
+}
+
+impl Drop for StreamingGuard<'_> {
+    fn drop(&mut self) {
+        // the last answer to stop takes the request with it
+        if self
+            .interrupt
+            .state
+            .streaming
+            .fetch_sub(1, Ordering::SeqCst)
+            == 1
+        {
+            self.interrupt
+                .state
+                .requested
+                .store(false, Ordering::SeqCst);
+        }
+    }
+}
@@ -1,9 +1,20 @@
+pub mod attachments;
+pub mod batch;
 pub mod captioner;
 pub mod chat;
 pub mod code;
+pub mod compaction;
+pub mod compare;
 pub mod config;
 pub mod constants;
+pub mod guardrails;
+pub mod image_generation;
+pub mod interrupt;
+pub mod markdown;
 pub mod models;
+pub mod search;
+pub mod tools;
+pub mod usage;
 pub mod utils;
 
 use aws_config::environment::credentials::EnvironmentVariableCredentialsProvider;
@@ -17,18 +28,17 @@ use aws_types::region::Region;
 
 //======================================== AWS_REGION
 // FIX: Return Result
-pub async fn configure_aws(
-    fallback_region: String,
-    profile_name: &String,
-) -> aws_config::SdkConfig {
-    let region_provider = RegionProviderChain::first_try(
-        ProfileFileRegionProvider::builder()
-            .profile_name(profile_name)
-            .build(),
-    )
-    .or_else(aws_config::environment::EnvironmentVariableRegionProvider::new())
-    .or_else(aws_config::imds::region::ImdsRegionProvider::builder().build())
-    .or_else(Region::new(fallback_region));
+pub async fn configure_aws(region: Option<String>, profile_name: &String) -> aws_config::SdkConfig {
+    // the region from `--region` or the config wins over the one from the profile
+    let region_provider = RegionProviderChain::first_try(region.map(Region::new))
+        .or_else(
+            ProfileFileRegionProvider::builder()
+                .profile_name(profile_name)
+                .build(),
+        )
+        .or_else(aws_config::environment::EnvironmentVariableRegionProvider::new())
+        .or_else(aws_config::imds::region::ImdsRegionProvider::builder().build())
+        .or_else(Region::new(constants::DEFAULT_REGION));
 
     let credentials_provider = CredentialsProviderChain::first_try(
         "Environment",
@@ -42,9 +52,11 @@ pub async fn configure_aws(
     )
     .or_else("IMDS", ImdsCredentialsProvider::builder().build());
 
+    // NOTE: Retries are up to our RetryPolicy, the SDK should not retry on top of it
     aws_config::defaults(BehaviorVersion::latest())
         .credentials_provider(credentials_provider)
         .region(region_provider)
+        .retry_config(aws_config::retry::RetryConfig::disabled())
         .load()
         .await
 }
@@ -1,29 +1,45 @@
 use std::io;
 use std::io::Write;
+use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use aws_sdk_bedrockruntime::types::ContentBlock;
 use aws_sdk_bedrockruntime::types::ConversationRole;
-use aws_sdk_bedrockruntime::types::InferenceConfiguration;
 use aws_sdk_bedrockruntime::types::Message;
 use bedrust::config;
 use bedrust::utils;
 use colored::*;
 use dialoguer::{theme::ColorfulTheme, FuzzySelect};
 
+use bedrust::attachments::{Attachment, Attachments};
+use bedrust::batch::{default_output, run_batch};
 use bedrust::configure_aws;
+use bedrust::guardrails::GuardrailConfig;
+use bedrust::image_generation::{imagine, parse_imagine, ImageRequest};
+use bedrust::interrupt::Interrupt;
+use bedrust::models::backend::{BedrockBackend, ModelBackend};
+use bedrust::models::cache_point;
+use bedrust::models::catalog::{resolve_model_id, ModelCatalog};
+use bedrust::models::fallback::{call_with_fallback, fallback_models, ChatModel};
+use bedrust::models::reasoning::reasoning_budget;
+use bedrust::models::scripted::ScriptedBackend;
+use bedrust::search::{print_search_results, search_chats, ChatIndex};
+use bedrust::tools::ToolRegistry;
+use bedrust::usage::{print_session_usage, print_turn_usage, SessionUsage};
 use bedrust::utils::prompt_for_model_selection;
 
 use bedrust::captioner::caption_process;
 use bedrust::chat::{
-    list_chat_histories, load_chat_history, print_conversation_history, save_chat_history,
-    ConversationHistory,
+    chat_history_dir, emergency_save_chat_history, list_chat_histories, load_chat_history,
+    print_conversation_history, save_chat_history, ConversationHistory,
 };
 use bedrust::utils::{check_for_config, print_warning};
 use clap::Parser;
 
 use bedrust::code::code_chat_process;
-use bedrust::models::converse_stream::call_converse_stream;
+use bedrust::compaction::compact;
+use bedrust::compare::Comparison;
+use bedrust::models::error::{print_error_hint, BedrockError};
 
 // TODO:
 // So far I've implemented the converse API for general purpose chat and the code chat.
@@ -61,39 +77,181 @@ async fn main() -> Result<()> {
         std::process::exit(1);
     }
     // load bedrust config file
-    let bedrust_config = utils::load_bedrust_config()?;
+    let mut bedrust_config = utils::load_bedrust_config()?;
+    // the markdown is printed as is, for piping the answers somewhere else
+    if arguments.raw {
+        bedrust_config.render_markdown = false;
+    }
 
-    // configuring the SDK
-    let config = configure_aws(String::from("us-east-1"), &bedrust_config.aws_profile).await;
-    // setup the bedrock-runtime client
-    let bedrock_runtime_client = aws_sdk_bedrockruntime::Client::new(&config);
-    // setup the bedrock client
-    let bedrock_client = aws_sdk_bedrock::Client::new(&config);
+    // setup the model backend - either a scripted one (no AWS needed) or Amazon Bedrock
+    let backend: Box<dyn ModelBackend> = match arguments.script {
+        Some(ref script) => Box::new(
+            ScriptedBackend::from_file(script)?.with_retry_policy(bedrust_config.retry.clone()),
+        ),
+        None => {
+            // configuring the SDK
+            let region = arguments.region.clone().or(bedrust_config.region.clone());
+            let config = configure_aws(region, &bedrust_config.aws_profile).await;
+            if let Some(region) = config.region() {
+                println!("🌍 | Using the AWS region {}", region);
+            }
+            // the guardrail that goes with the AWS profile
+            let guardrail = GuardrailConfig::for_profile(
+                &bedrust_config.guardrails,
+                &bedrust_config.aws_profile,
+            );
+            if let Some(guardrail) = &guardrail {
+                println!(
+                    "🛡️  | Using the guardrail {} (version {})",
+                    guardrail.identifier, guardrail.version
+                );
+            }
+            Box::new(
+                BedrockBackend::new(&config)
+                    .with_model_regions(bedrust_config.model_regions.clone())
+                    .with_retry_policy(bedrust_config.retry.clone())
+                    .with_guardrail(guardrail),
+            )
+        }
+    };
+    let backend = backend.as_ref();
 
-    //let question = "Which songs are listed in the youtube video 'evolution of dance'?";
-    let model_id = arguments.model_id.or(bedrust_config.default_model);
-    let model_id = match model_id {
-        Some(model_id) => model_id,
-        None => prompt_for_model_selection()?,
+    // === IMAGE GENERATION ===
+    // `-m` picks the image model here, the config has the defaults for everything else
+    let mut image_config = bedrust_config.image_generation.clone();
+    let image_model = match arguments.generate_image {
+        Some(_) => arguments
+            .model_id
+            .as_ref()
+            .unwrap_or(&image_config.model_id),
+        None => &image_config.model_id,
+    };
+    image_config.model_id = resolve_model_id(image_model, &bedrust_config.model_aliases);
+    if let Some(output_dir) = &arguments.output_dir {
+        image_config.output_dir = output_dir.clone();
+    }
+    // there is no chat model to pick when generating images
+    if let Some(prompt) = arguments.generate_image.as_deref() {
+        let mut request = ImageRequest::new(prompt, &image_config);
+        request.negative_prompt = arguments.negative_prompt.clone();
+        if let Some((width, height)) = arguments.size {
+            request.width = width;
+            request.height = height;
+        }
+        if let Some(seed) = arguments.seed {
+            request.seed = seed;
+        }
+        if let Some(count) = arguments.count {
+            request.count = count;
+        }
+        if let Err(e) = imagine(backend, &request, &image_config.output_dir, None).await {
+            eprintln!("🔴 | {}", e);
+            if let Some(e) = e.downcast_ref::<BedrockError>() {
+                print_error_hint(e);
+            }
+            std::process::exit(1);
+        }
+        return Ok(());
     }
-    .to_str();
 
-    // === DEFAULT INFERENCE PARAMETERS ===
-    let inference_parameters = InferenceConfiguration::builder()
-        .max_tokens(bedrust_config.inference_params.max_tokens)
-        .top_p(bedrust_config.inference_params.top_p)
-        .temperature(bedrust_config.inference_params.temperature)
-        .build();
+    // === CHAT SEARCH ===
+    let embedding_model = resolve_model_id(
+        &bedrust_config.embedding_model,
+        &bedrust_config.model_aliases,
+    );
+    if let Some(query) = arguments.search_chats.as_deref() {
+        let chat_dir = chat_history_dir();
+        match search_chats(
+            backend,
+            &embedding_model,
+            query,
+            &chat_dir,
+            &ChatIndex::path(),
+        )
+        .await
+        {
+            Ok(results) => print_search_results(&results, &chat_dir),
+            Err(e) => {
+                eprintln!("🔴 | {}", e);
+                if let Some(e) = e.downcast_ref::<BedrockError>() {
+                    print_error_hint(e);
+                }
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // === BATCH ===
+    // no model to pick either, `-m` (or `default_model`) is only the default for the prompts that
+    // do not pick one
+    if let Some(input) = arguments.batch.as_deref() {
+        let default_model = arguments
+            .model_id
+            .as_deref()
+            .or(bedrust_config.default_model.as_deref());
+        let output = arguments
+            .batch_output
+            .clone()
+            .unwrap_or_else(|| default_output(input));
+        let concurrency = arguments.concurrency;
+        let config = &bedrust_config;
+        match run_batch(backend, config, default_model, input, &output, concurrency).await {
+            Ok(summary) => {
+                println!(
+                    "📒 | {} answered, {} failed, {} skipped | results in {}",
+                    summary.answered,
+                    summary.failed,
+                    summary.skipped,
+                    output.display()
+                );
+                print_session_usage(&summary.usage, &bedrust_config.model_prices);
+                if summary.failed > 0 {
+                    std::process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("🔴 | {}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // === MODEL SELECTION ===
+    // the model catalog is only cached when talking to Bedrock
+    let catalog_cache = arguments.script.is_none().then(ModelCatalog::cache_path);
+    if arguments.refresh_models {
+        ModelCatalog::load(backend, catalog_cache.as_deref(), true).await;
+    }
+    let model_id = match arguments
+        .model_id
+        .as_ref()
+        .or(bedrust_config.default_model.as_ref())
+    {
+        Some(model_id) => resolve_model_id(model_id, &bedrust_config.model_aliases),
+        None => {
+            let catalog = ModelCatalog::load(backend, catalog_cache.as_deref(), false).await;
+            prompt_for_model_selection(&catalog.choices(&bedrust_config.model_aliases))?
+        }
+    };
+    let model_id = model_id.as_str();
+
+    // how much the model can think before answering, if at all
+    let reasoning_budget =
+        reasoning_budget(bedrust_config.reasoning_budget, arguments.reasoning_budget)?;
 
     // === SYSTEM PROMPT ===
-    let system_prompt = bedrust_config.system_prompt.as_deref().unwrap_or("You are a helpful assistant");
+    let system_prompt = bedrust_config
+        .system_prompt
+        .as_deref()
+        .unwrap_or("You are a helpful assistant");
 
     //  === CAPTIONING RUN ===
     if arguments.caption.is_some() {
         caption_process(
             model_id,
-            &bedrock_client,
-            &bedrock_runtime_client,
+            backend,
             arguments.caption,
             &bedrust_config,
             arguments.xml,
@@ -106,58 +264,178 @@ async fn main() -> Result<()> {
         let mut message_count = 0;
         let mut conversation_history = ConversationHistory::new(None, None, None, None);
         let mut current_file: Option<String> = None;
+        let mut session_usage = SessionUsage::new();
 
         //  === BETA: SOURCE CODE CHAT ===
         let code: Option<String> = match arguments.source {
             Some(ref source_path) => {
-                Some(code_chat_process(source_path.to_path_buf(), &bedrock_runtime_client).await?)
+                Some(code_chat_process(source_path.to_path_buf(), backend, &bedrust_config).await?)
             }
             None => None,
         };
-        // get user input
-        loop {
-            println!("----------------------------------------");
-            println!("🤖 | What would you like to know today?");
-            print!("😎 | Human: ");
-            io::stdout().flush()?; // so the question is typed on the same line as above
 
-            let mut question = String::new();
-            io::stdin().read_line(&mut question)?;
-            message_count += 1;
-
-            let question = question.trim();
-            if question.is_empty() {
-                println!("Please enter a question.");
-                continue;
+        // === TOOLS ===
+        let tool_registry = ToolRegistry::builtin(arguments.source.as_deref());
+        // the parameters, tools, caching and reasoning of the model we are chatting with
+        let chat_model = ChatModel::new(
+            model_id,
+            backend,
+            &bedrust_config,
+            system_prompt,
+            reasoning_budget,
+        )
+        .await;
+        if chat_model.tools {
+            println!(
+                "🔧 | Tools available to the model: {}",
+                tool_registry.names().join(", ")
+            );
+        }
+        if !chat_model.options.streaming {
+            println!(
+                "🐢 | `{}` can not stream, answers show up once complete",
+                model_id
+            );
+        }
+        // === PROMPT CACHING ===
+        // the system prompt and the source code are the same on every call, Bedrock can cache them
+        let prompt_caching = chat_model.prompt_caching;
+        if prompt_caching {
+            println!("🗃️  | Prompt caching is on");
+        }
+        // === REASONING ===
+        match chat_model.reasoning {
+            Some(budget) => println!("🤔 | Reasoning budget: {} tokens", budget),
+            None if reasoning_budget.is_some() => print_warning(&format!(
+                "`{}` does not take a reasoning budget, it is ignored",
+                model_id
+            )),
+            None => {}
+        }
+        // === FALLBACK MODELS ===
+        // asked, in order, when the model is throttled or unavailable
+        let mut chat_models = vec![chat_model];
+        chat_models.extend(
+            fallback_models(
+                model_id,
+                backend,
+                &bedrust_config,
+                system_prompt,
+                reasoning_budget,
+            )
+            .await,
+        );
+        if chat_models.len() > 1 {
+            let fallbacks: Vec<&str> = chat_models[1..]
+                .iter()
+                .map(|m| m.model_id.as_str())
+                .collect();
+            println!("🪂 | Fallback models: {}", fallbacks.join(", "));
+        }
+        // === COMPARE MODE ===
+        // every question goes to all of these at once, and the user picks the answer to keep
+        let mut compare_models: Vec<ChatModel> = Vec::new();
+        if !arguments.compare.is_empty() {
+            let mut model_ids = vec![model_id.to_string()];
+            for compare in &arguments.compare {
+                let compare = resolve_model_id(compare, &bedrust_config.model_aliases);
+                if !model_ids.contains(&compare) {
+                    model_ids.push(compare);
+                }
             }
-            if question == "/q" {
-                println!("Bye!");
-                break;
-            } else if question == "/h" {
-                conversation_history.save_as_html()?;
-                continue;
-            } else if question == "/c" {
-                println!("Clearing current chat history");
-                conversation_history.clear();
-                current_file = None;
-                continue;
-            // SAVING CHAT HISTORY
-            // TODO: Implement a feature that will distinguish between user input and LLM output
-            // this will likely need to be handled in the way I handle conversation_history as I
-            // just store everything as raw there.
-            } else if question == "/s" {
-                // if there is a current_file set we keep writing to that file
-                let filename = if let Some(ref file) = current_file {
-                    save_chat_history(
-                        Some(file),
-                        &bedrock_runtime_client,
-                        &mut conversation_history,
-                    )
-                    .await?
-                } else {
-                    match save_chat_history(
-                        None,
-                        &bedrock_runtime_client,
+            for compare in &model_ids {
+                let mut chat_model = ChatModel::new(
+                    compare,
+                    backend,
+                    &bedrust_config,
+                    system_prompt,
+                    reasoning_budget,
+                )
+                .await;
+                chat_model.options.echo = false;
+                compare_models.push(chat_model);
+            }
+            println!("⚖️  | Comparing: {}", model_ids.join(", "));
+        }
+        // === ATTACHMENTS ===
+        // documents that go along with the next question, if every model we ask can read them
+        let no_documents = std::iter::once(&chat_models[0])
+            .chain(&compare_models)
+            .find(|m| !m.documents)
+            .map(|m| m.model_id.clone());
+        let mut attachments = Attachments::new();
+        for path in &arguments.attach {
+            if let Some(model) = &no_documents {
+                bail!(
+                    "`{}` can not read documents, so nothing can be attached",
+                    model
+                );
+            }
+            let attachment = attachments.attach(path, &conversation_history)?;
+            println!("📎 | Attached {}", attachment.describe());
+        }
+        let mut failed_question: Option<String> = None;
+        // Ctrl-C stops an answer, and exits at the prompt
+        let interrupt = Interrupt::new();
+        interrupt.listen();
+        // NOTE: Errors from the model only cost us the question (see below), anything else that
+        // makes it out of here ends the session. Before that happens we save the conversation, so
+        // it can be recalled with `/r`.
+        let result: Result<()> = async {
+            // get user input
+            loop {
+                println!("----------------------------------------");
+                println!("🤖 | What would you like to know today?");
+                print!("😎 | Human: ");
+                io::stdout().flush()?; // so the question is typed on the same line as above
+
+                let mut question = String::new();
+                io::stdin().read_line(&mut question)?;
+                message_count += 1;
+
+                let mut question = question.trim().to_string();
+                // asking the question that failed last time, again
+                if question == "/retry" {
+                    match failed_question.take() {
+                        Some(failed) => {
+                            println!("🔁 | Asking again: {}", failed);
+                            question = failed;
+                        }
+                        None => {
+                            println!("There is no failed question to retry.");
+                            continue;
+                        }
+                    }
+                }
+                let question = question.as_str();
+                if question.is_empty() {
+                    println!("Please enter a question.");
+                    continue;
+                }
+                if question == "/q" {
+                    print_session_usage(&session_usage, &bedrust_config.model_prices);
+                    println!("Bye!");
+                    break;
+                } else if question == "/h" {
+                    if let Err(e) = conversation_history.save_as_html() {
+                        eprintln!("Error exporting chat history: {}", e);
+                    }
+                    continue;
+                } else if question == "/c" {
+                    println!("Clearing current chat history");
+                    conversation_history = conversation_history.clear();
+                    attachments.clear();
+                    current_file = None;
+                    continue;
+                // SAVING CHAT HISTORY
+                // TODO: Implement a feature that will distinguish between user input and LLM output
+                // this will likely need to be handled in the way I handle conversation_history as I
+                // just store everything as raw there.
+                } else if question == "/s" {
+                    // if there is a current_file set we keep writing to that file
+                    let filename = match save_chat_history(
+                        current_file.as_deref(),
+                        backend,
                         &mut conversation_history,
                     )
                     .await
@@ -170,103 +448,289 @@ async fn main() -> Result<()> {
                             eprintln!("Error saving chat history: {}", e);
                             continue;
                         }
+                    };
+                    println!("Chat history saved to: {}", filename.cyan());
+                    continue;
+                } else if question == "/t" {
+                    let show_reasoning = !chat_models[0].options.show_reasoning;
+                    for chat_model in chat_models.iter_mut().chain(compare_models.iter_mut()) {
+                        chat_model.options.show_reasoning = show_reasoning;
                     }
-                };
-                println!("Chat history saved to: {}", filename.cyan());
-                continue;
-            } else if question == "/r" {
-                match list_chat_histories() {
-                    Ok(histories) => {
-                        if histories.is_empty() {
-                            println!("No chat histories found.");
+                    if show_reasoning {
+                        println!("🤔 | Showing the reasoning of the model");
+                    } else {
+                        println!("🤔 | Hiding the reasoning of the model (it is still kept)");
+                    }
+                    continue;
+                } else if let Some(path) = question
+                    .strip_prefix("/attach")
+                    .filter(|path| path.is_empty() || path.starts_with(' '))
+                {
+                    let path = path.trim().trim_matches(|c| c == '"' || c == '\'');
+                    if path.is_empty() {
+                        if attachments.is_empty() {
+                            println!("📎 | Nothing is attached, add a document with /attach <path>");
+                        }
+                        for attachment in attachments.pending() {
+                            println!("📎 | {}", attachment.describe());
+                        }
+                        continue;
+                    }
+                    if let Some(model) = &no_documents {
+                        print_warning(&format!("`{}` can not read documents", model));
+                        continue;
+                    }
+                    match attachments.attach(Path::new(path), &conversation_history) {
+                        Ok(attachment) => println!(
+                            "📎 | Attached {}, it goes with your next question",
+                            attachment.describe()
+                        ),
+                        Err(e) => print_warning(&e.to_string()),
+                    }
+                    continue;
+                } else if let Some(text) = question
+                    .strip_prefix("/imagine")
+                    .filter(|text| text.is_empty() || text.starts_with(' '))
+                {
+                    let request = match parse_imagine(text, &image_config) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            print_warning(&e.to_string());
                             continue;
                         }
-                        let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
-                            .with_prompt("Select a chat history to recall:")
-                            .default(0)
-                            .items(&histories[..])
-                            .interact()
-                            .unwrap();
-                        let selected_history = &histories[selection];
-                        match load_chat_history(selected_history) {
-                            // we load the filename and the content from the history so we can keep
-                            // sasving to it
-                            // TODO: Make this work with SerializableMessage
-                            Ok((content, filename, existing_title, summary)) => {
-                                conversation_history.messages = Some(content);
-                                conversation_history.title = Some(existing_title.clone());
-                                conversation_history.summary = Some(summary.clone());
-                                current_file = Some(filename);
-                                utils::print_warning("----------------------------------------");
-                                println!("Loaded chat history from: {}", selected_history.yellow());
-                                println!();
-                                println!("Loaded chat summary: ");
-                                println!("{}", summary);
-                                print_conversation_history(&conversation_history);
-                                println!("You can now continue the conversation.");
+                    };
+                    let output_dir = &image_config.output_dir;
+                    if let Err(e) = imagine(backend, &request, output_dir, Some(&interrupt)).await {
+                        eprintln!("🔴 | {}", e);
+                        if let Some(e) = e.downcast_ref::<BedrockError>() {
+                            print_error_hint(e);
+                        }
+                    }
+                    continue;
+                } else if let Some(query) = question
+                    .strip_prefix("/r")
+                    .filter(|q| q.is_empty() || q.starts_with(' '))
+                {
+                    // with a query, the chats closest to it are listed first
+                    let query = query.trim();
+                    let listed = if query.is_empty() {
+                        list_chat_histories().map(|h| (h.clone(), h))
+                    } else {
+                        let chat_dir = chat_history_dir();
+                        let index_path = ChatIndex::path();
+                        match search_chats(backend, &embedding_model, query, &chat_dir, &index_path)
+                            .await
+                        {
+                            Ok(results) => Ok((
+                                results.iter().map(|r| r.filename.clone()).collect(),
+                                results
+                                    .iter()
+                                    .map(|r| {
+                                        format!("{} ({:.0}%)", r.filename, r.score.max(0.0) * 100.0)
+                                    })
+                                    .collect(),
+                            )),
+                            Err(e) => {
+                                print_warning(&format!("Unable to search the saved chats: {}", e));
+                                list_chat_histories().map(|h| (h.clone(), h))
+                            }
+                        }
+                    };
+                    match listed {
+                        Ok((histories, labels)) => {
+                            if histories.is_empty() {
+                                println!("No chat histories found.");
+                                continue;
+                            }
+                            let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
+                                .with_prompt("Select a chat history to recall:")
+                                .default(0)
+                                .items(&labels[..])
+                                .interact()
+                                .unwrap();
+                            let selected_history = &histories[selection];
+                            match load_chat_history(selected_history) {
+                                // we load the filename and the content from the history so we can keep
+                                // sasving to it
+                                // TODO: Make this work with SerializableMessage
+                                Ok((
+                                    content, filename, existing_title, summary, turns, compaction,
+                                )) => {
+                                    conversation_history.messages = Some(content);
+                                    conversation_history.turns = turns;
+                                    conversation_history.compaction = compaction;
+                                    conversation_history.title = Some(existing_title.clone());
+                                    conversation_history.summary = Some(summary.clone());
+                                    current_file = Some(filename);
+                                    utils::print_warning("----------------------------------------");
+                                    println!("Loaded chat history from: {}", selected_history.yellow());
+                                    println!();
+                                    println!("Loaded chat summary: ");
+                                    println!("{}", summary);
+                                    print_conversation_history(&conversation_history);
+                                    println!("You can now continue the conversation.");
+                                }
+                                Err(e) => eprintln!("Error loading chat history: {}", e),
                             }
-                            Err(e) => eprintln!("Error loading chat history: {}", e),
                         }
+                        Err(e) => eprintln!("Error listing chat histories: {}", e),
                     }
-                    Err(e) => eprintln!("Error listing chat histories: {}", e),
+                    continue;
+                } else if question.starts_with('/') {
+                    utils::print_warning("Special command detected: /");
+                    utils::print_warning("----------------------------------------");
+                    utils::print_warning("Currently supported chat commands: ");
+                    utils::print_warning("/c\t \t - Clear current chat history");
+                    utils::print_warning("/s\t \t - (BETA) Save chat history");
+                    utils::print_warning("/r [query]\t - (BETA) Recall a chat, by meaning");
+                    utils::print_warning(
+                        "/h\t \t - (BETA) Export history as HTML(saves in current dir)",
+                    );
+                    utils::print_warning("/attach <path>\t - Attach a document to the question");
+                    utils::print_warning("/imagine <prompt>\t - Generate images (with --no, --size, --seed, --count)");
+                    utils::print_warning("/t\t \t - Show or hide the reasoning of the model");
+                    utils::print_warning("/retry\t \t - Ask the last question that failed again");
+                    utils::print_warning("/q\t \t - Quit");
+                    utils::print_warning("Ctrl-C\t \t - Stop the current answer (quits at this prompt)");
+                    continue;
                 }
-                continue;
-            } else if question.starts_with('/') {
-                utils::print_warning("Special command detected: /");
-                utils::print_warning("----------------------------------------");
-                utils::print_warning("Currently supported chat commands: ");
-                utils::print_warning("/c\t \t - Clear current chat history");
-                utils::print_warning("/s\t \t - (BETA) Save chat history");
-                utils::print_warning("/r\t \t - (BETA) Recall and load a chat history");
-                utils::print_warning(
-                    "/h\t \t - (BETA) Export history as HTML(saves in current dir)",
-                );
-                utils::print_warning("/q\t \t - Quit");
-                continue;
-            }
-            // If we are looking at code - I need to include the user question in the first
-            // message. Otherwise Bedrock keeps complaining about alternate messages between user
-            // and assistant
-            let message = if arguments.source.is_some() && message_count == 1 {
-                let question_with_code = code
-                    .as_ref()
-                    .map(|src_code| format!("{}\n<question>{}</question>", src_code, question))
-                    .unwrap_or_else(|| question.to_string());
-                Message::builder()
-                    .set_role(Some(ConversationRole::User))
-                    .set_content(Some(vec![ContentBlock::Text(question_with_code)]))
-                    .build()?
-            } else {
-                Message::builder()
+                // If we are looking at code - I need to include the user question in the first
+                // message. Otherwise Bedrock keeps complaining about alternate messages between user
+                // and assistant
+                let content = if arguments.source.is_some() && message_count == 1 {
+                    // NOTE: With caching, the code goes in its own block followed by a cache point,
+                    // so the question is not part of what gets cached
+                    match code.as_ref() {
+                        Some(src_code) if prompt_caching => vec![
+                            ContentBlock::Text(src_code.to_string()),
+                            ContentBlock::CachePoint(cache_point()),
+                            ContentBlock::Text(format!("<question>{}</question>", question)),
+                        ],
+                        Some(src_code) => vec![ContentBlock::Text(format!(
+                            "{}\n<question>{}</question>",
+                            src_code, question
+                        ))],
+                        None => vec![ContentBlock::Text(question.to_string())],
+                    }
+                } else {
+                    vec![ContentBlock::Text(question.to_string())]
+                };
+                // the attached documents go in front of the question
+                let attached = attachments.take();
+                let content = attached
+                    .iter()
+                    .map(Attachment::content_block)
+                    .chain(content)
+                    .collect();
+                let message = Message::builder()
                     .set_role(Some(ConversationRole::User))
-                    .set_content(Some(vec![ContentBlock::Text(question.to_string())]))
-                    .build()?
-            };
-            let mut messages = conversation_history.messages.unwrap_or_default().clone();
-            messages.push(message.into());
-            conversation_history.messages = Some(messages);
+                    .set_content(Some(content))
+                    .build()?;
+                let mut messages = conversation_history.messages.take().unwrap_or_default();
+                // where to roll back to, if the model fails to answer
+                let history_len = messages.len();
+                messages.push(message.into());
+                conversation_history.messages = Some(messages);
+                // the older messages are summarized, once the conversation gets long
+                if let Some(compaction) = &bedrust_config.compaction {
+                    let compacted = compact(
+                        backend,
+                        &mut conversation_history,
+                        compaction,
+                        &chat_models[0],
+                        &bedrust_config.context_windows,
+                    );
+                    match compacted.await {
+                        Ok(true) => println!("🗜️  | The older messages are sent as a summary now"),
+                        Ok(false) => {}
+                        Err(e) => {
+                            print_warning(&format!("Unable to summarize the older messages: {}", e))
+                        }
+                    }
+                }
 
-            println!("----------------------------------------");
-            println!("☎️  | Calling Model: {}", &model_id);
-            println!("----------------------------------------");
+                println!("----------------------------------------");
+                if compare_models.is_empty() {
+                    println!("☎️  | Calling Model: {}", &model_id);
+                } else {
+                    println!("☎️  | Calling {} models at once", compare_models.len());
+                }
+                println!("----------------------------------------");
 
-            let streamresp = call_converse_stream(
-                &bedrock_runtime_client,
-                model_id.to_string(),
-                &conversation_history,
-                inference_parameters.clone(),
-                system_prompt
-            )
-            .await?;
+                let answer = if compare_models.is_empty() {
+                    call_with_fallback(
+                        backend,
+                        &chat_models,
+                        &conversation_history,
+                        Some(&tool_registry),
+                        Some(&interrupt),
+                    )
+                    .await
+                } else {
+                    let comparison = Comparison::run(
+                        backend,
+                        &compare_models,
+                        &conversation_history,
+                        Some(&tool_registry),
+                        Some(&interrupt),
+                    )
+                    .await;
+                    comparison.print(arguments.side_by_side, compare_models[0].options.markdown);
+                    // the answers we did not pick still cost tokens
+                    comparison.pick().map(|(convo, others)| {
+                        others.into_iter().for_each(|turn| session_usage.add(turn));
+                        convo
+                    })
+                };
+                let streamresp = match answer {
+                    Ok(streamresp) => streamresp,
+                    Err(e) => {
+                        // NOTE: The question is taken back out of the history, so the user and
+                        // assistant messages keep alternating. It can be asked again with `/retry`.
+                        println!();
+                        eprintln!("🔴 | {}", e);
+                        print_error_hint(&e);
+                        if let Some(messages) = conversation_history.messages.as_mut() {
+                            messages.truncate(history_len);
+                        }
+                        message_count -= 1;
+                        // the documents go with the next question instead
+                        attachments.restore(attached);
+                        failed_question = Some(question.to_string());
+                        print_warning("The question was not added to the conversation, type /retry to ask it again");
+                        continue;
+                    }
+                };
 
-            // TODO: This can be a function
-            let message = Message::builder()
-                .set_role(Some(ConversationRole::Assistant))
-                .set_content(Some(vec![ContentBlock::Text(streamresp.to_string())]))
-                .build()?;
-            let mut messages = conversation_history.messages.unwrap();
-            messages.push(message.into());
-            conversation_history.messages = Some(messages);
+                // the answer, along with any tool calls the model made to get to it
+                let mut messages = conversation_history.messages.take().unwrap_or_default();
+                messages.extend(streamresp.messages);
+                conversation_history.messages = Some(messages);
+
+                if let Some(turn) = streamresp.usage {
+                    session_usage.add(turn.clone());
+                    print_turn_usage(&turn, &session_usage, &bedrust_config.model_prices);
+                    conversation_history.turns.push(turn);
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            if conversation_history
+                .messages
+                .as_ref()
+                .is_some_and(|m| !m.is_empty())
+            {
+                match emergency_save_chat_history(&conversation_history) {
+                    Ok(filename) => print_warning(&format!(
+                        "Something went wrong, the conversation was saved to: {}",
+                        filename
+                    )),
+                    Err(save_error) => eprintln!("Error saving chat history: {}", save_error),
+                }
+            }
+            return Err(e);
         }
     }
 
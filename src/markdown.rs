@@ -0,0 +1,438 @@
+use colored::*;
+use dialoguer::console::{measure_text_width, pad_str, Alignment};
+use lazy_static::lazy_static;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::SyntaxSet;
+use syntect::util::as_24_bit_terminal_escaped;
+
+use crate::constants;
+
+// NOTE: Markdown in the terminal. The answers stream in a few characters at a time, so we render
+// them as we go: the start of a line tells us what kind of line it is (header, list item, quote,
+// ...), and the words are printed as soon as no markup (`code`, **bold**, links) is left open.
+// Code blocks are highlighted a line at a time, and tables are held back until they are complete,
+// so the columns line up. Run with `--raw` to get the markdown as it is.
+
+lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref THEME: Theme = ThemeSet::load_defaults()
+        .themes
+        .remove(constants::CODE_THEME)
+        .expect("the theme is one of the defaults");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineKind {
+    Paragraph,
+    Header(usize),
+    ListItem,
+    Quote,
+    Fence,
+    Table,
+    Rule,
+}
+
+// A fenced code block we are in, and the fence that closes it
+struct CodeBlock {
+    fence: String,
+    highlighter: HighlightLines<'static>,
+}
+
+impl CodeBlock {
+    fn new(fence_line: &str) -> CodeBlock {
+        let fence_line = fence_line.trim_start();
+        let fence_char = fence_line.chars().next().unwrap_or('`');
+        let fence_len = fence_line.chars().take_while(|c| *c == fence_char).count();
+        let language = fence_line[fence_len..]
+            .split_whitespace()
+            .next()
+            .unwrap_or_default();
+        let syntax = SYNTAXES
+            .find_syntax_by_token(language)
+            .unwrap_or_else(|| SYNTAXES.find_syntax_plain_text());
+        CodeBlock {
+            fence: fence_line[..fence_len].to_string(),
+            highlighter: HighlightLines::new(syntax, &THEME),
+        }
+    }
+
+    fn is_closed_by(&self, line: &str) -> bool {
+        let line = line.trim();
+        line.starts_with(&self.fence) && line.chars().all(|c| self.fence.starts_with(c))
+    }
+
+    fn highlight(&mut self, line: &str) -> String {
+        let line = format!("{}\n", line);
+        match self.highlighter.highlight_line(&line, &SYNTAXES) {
+            Ok(ranges) => format!(
+                "{}\x1b[0m\n",
+                as_24_bit_terminal_escaped(&ranges, false).trim_end_matches('\n')
+            ),
+            Err(_) => line,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MarkdownRenderer {
+    // what we got, but have not printed yet
+    pending: String,
+    // the start of the current line is printed already, and this is what kind of line it is
+    open_line: Option<LineKind>,
+    code: Option<CodeBlock>,
+    table: Vec<String>,
+}
+
+impl MarkdownRenderer {
+    pub fn new() -> Self {
+        MarkdownRenderer::default()
+    }
+
+    // Takes the next piece of the answer, and returns what can be printed so far
+    pub fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        let mut out = String::new();
+        while let Some(end) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=end).collect();
+            out.push_str(&self.line(line.trim_end_matches(['\n', '\r'])));
+        }
+        out.push_str(&self.partial());
+        out
+    }
+
+    // The answer is done, prints whatever is left
+    pub fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        let mut out = String::new();
+        if !rest.is_empty() || self.open_line.is_some() {
+            out.push_str(&self.line(&rest));
+        }
+        out.push_str(&self.flush_table());
+        self.code = None;
+        out.truncate(out.trim_end_matches('\n').len());
+        out
+    }
+
+    // A whole line (or the rest of it, if its start is printed already)
+    fn line(&mut self, line: &str) -> String {
+        if let Some(code) = self.code.as_mut() {
+            if code.is_closed_by(line) {
+                self.code = None;
+                return format!("{}\n", line.dimmed());
+            }
+            return code.highlight(line);
+        }
+        if let Some(kind) = self.open_line.take() {
+            return format!("{}\n", render_text(kind, line));
+        }
+
+        let kind = classify(line, true).unwrap_or(LineKind::Paragraph);
+        if kind == LineKind::Table {
+            self.table.push(line.to_string());
+            return String::new();
+        }
+        let mut out = self.flush_table();
+        match kind {
+            LineKind::Fence => {
+                self.code = Some(CodeBlock::new(line));
+                out.push_str(&line.dimmed().to_string());
+            }
+            LineKind::Rule => out.push_str(&"─".repeat(40).dimmed().to_string()),
+            _ => {
+                let (prefix, rest) = line_start(kind, line);
+                out.push_str(&prefix);
+                out.push_str(&render_text(kind, rest));
+            }
+        }
+        out.push('\n');
+        out
+    }
+
+    // Prints the words of the current line we are sure about
+    fn partial(&mut self) -> String {
+        let mut out = String::new();
+        if self.code.is_some() || self.pending.is_empty() {
+            return out;
+        }
+        let kind = match self.open_line {
+            Some(kind) => kind,
+            None => match classify(&self.pending, false) {
+                // not sure yet, or we need the whole line
+                None | Some(LineKind::Fence | LineKind::Table | LineKind::Rule) => return out,
+                Some(kind) => {
+                    out.push_str(&self.flush_table());
+                    let (prefix, rest) = line_start(kind, &self.pending);
+                    out.push_str(&prefix);
+                    self.pending = rest.to_string();
+                    self.open_line = Some(kind);
+                    kind
+                }
+            },
+        };
+        if let Some(end) = printable_end(&self.pending) {
+            let text: String = self.pending.drain(..end).collect();
+            out.push_str(&render_text(kind, &text));
+        }
+        out
+    }
+
+    // Tables are printed once they are complete, with the columns lined up
+    fn flush_table(&mut self) -> String {
+        if self.table.is_empty() {
+            return String::new();
+        }
+        let rows: Vec<Vec<String>> = self
+            .table
+            .drain(..)
+            .map(|line| table_cells(&line))
+            .collect();
+        // the |---|:---:| line under the header
+        let is_separator = |row: &Vec<String>| {
+            row.iter()
+                .all(|cell| !cell.is_empty() && cell.chars().all(|c| matches!(c, '-' | ':')))
+        };
+        let has_header = rows.len() > 1 && is_separator(&rows[1]);
+        let rows: Vec<Vec<String>> = rows.into_iter().filter(|row| !is_separator(row)).collect();
+        let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+        let mut widths = vec![0; columns];
+        for row in &rows {
+            for (i, cell) in row.iter().enumerate() {
+                widths[i] = widths[i].max(measure_text_width(cell));
+            }
+        }
+
+        let border = "│".dimmed().to_string();
+        let mut out = String::new();
+        for (i, row) in rows.iter().enumerate() {
+            let cells: Vec<String> = widths
+                .iter()
+                .enumerate()
+                .map(|(j, width)| {
+                    let cell = row.get(j).map(String::as_str).unwrap_or_default();
+                    let cell = pad_str(cell, *width, Alignment::Left, None);
+                    if has_header && i == 0 {
+                        cell.bold().to_string()
+                    } else {
+                        cell.to_string()
+                    }
+                })
+                .collect();
+            let separator = format!(" {} ", border);
+            out.push_str(&format!(
+                "{} {} {}\n",
+                border,
+                cells.join(&separator),
+                border
+            ));
+            if has_header && i == 0 {
+                let line: Vec<String> = widths.iter().map(|w| "─".repeat(*w)).collect();
+                let line = format!("├─{}─┤", line.join("─┼─"));
+                out.push_str(&format!("{}\n", line.dimmed()));
+            }
+        }
+        out
+    }
+}
+
+// Renders a whole answer at once (ie when it was not streamed)
+pub fn render_markdown(text: &str) -> String {
+    let mut renderer = MarkdownRenderer::new();
+    let mut out = renderer.push(text);
+    out.push_str(&renderer.finish());
+    out
+}
+
+// What kind of line this is, from how it starts. `None` when we can not tell yet.
+fn classify(line: &str, complete: bool) -> Option<LineKind> {
+    let trimmed = line.trim_start();
+    let Some(first) = trimmed.chars().next() else {
+        return complete.then_some(LineKind::Paragraph);
+    };
+    let repeated = trimmed.chars().take_while(|c| *c == first).count();
+    let after = &trimmed[repeated * first.len_utf8()..];
+    match first {
+        '`' | '~' if repeated >= 3 => Some(LineKind::Fence),
+        '`' | '~' if after.is_empty() && !complete => None,
+        '#' => match after.chars().next() {
+            None if !complete => None,
+            Some(' ') if repeated <= 6 => Some(LineKind::Header(repeated)),
+            _ => Some(LineKind::Paragraph),
+        },
+        '|' => Some(LineKind::Table),
+        '>' => Some(LineKind::Quote),
+        '-' | '*' | '_' | '+' => {
+            let list_item = first != '_' && trimmed[1..].starts_with(' ');
+            // `---` is a rule, but `- ` is a list item
+            if trimmed.chars().all(|c| c == first || c == ' ') {
+                if !(complete || (list_item && repeated == 1)) {
+                    return None;
+                }
+                let count = trimmed.chars().filter(|c| *c == first).count();
+                if complete && count >= 3 && first != '+' {
+                    return Some(LineKind::Rule);
+                }
+            }
+            if list_item {
+                Some(LineKind::ListItem)
+            } else {
+                Some(LineKind::Paragraph)
+            }
+        }
+        _ => Some(LineKind::Paragraph),
+    }
+}
+
+// The rendered start of a line (indentation, bullet, ...), and the text after it
+fn line_start(kind: LineKind, line: &str) -> (String, &str) {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+    match kind {
+        LineKind::Header(level) => (indent.to_string(), trimmed[level..].trim_start()),
+        LineKind::ListItem => (format!("{}{} ", indent, "•".cyan()), &trimmed[2..]),
+        LineKind::Quote => (
+            format!("{}{} ", indent, "│".dimmed()),
+            trimmed[1..].trim_start(),
+        ),
+        _ => (indent.to_string(), trimmed),
+    }
+}
+
+fn render_text(kind: LineKind, text: &str) -> String {
+    match kind {
+        LineKind::Header(1) => text.bold().underline().to_string(),
+        LineKind::Header(_) => text.bold().to_string(),
+        LineKind::Quote => render_inline(text).italic().to_string(),
+        _ => render_inline(text),
+    }
+}
+
+// `code`, **bold**, *italic* and [links](https://...)
+fn render_inline(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                out.push_str(&rest[1..1 + end].yellow().to_string());
+                rest = &rest[end + 2..];
+                continue;
+            }
+        } else if let Some(inner) = rest.strip_prefix("**") {
+            if let Some(end) = inner.find("**").filter(|end| *end > 0) {
+                out.push_str(&inner[..end].bold().to_string());
+                rest = &inner[end + 2..];
+                continue;
+            }
+        } else if c == '*' && rest[1..].starts_with(|c: char| !c.is_whitespace()) {
+            if let Some(end) = rest[1..].find('*') {
+                let inner = &rest[1..1 + end];
+                if !inner.ends_with(char::is_whitespace) {
+                    out.push_str(&inner.italic().to_string());
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        } else if c == '[' {
+            if let Some(middle) = rest.find("](") {
+                if let Some(end) = rest[middle..].find(')') {
+                    let label = &rest[1..middle];
+                    let url = &rest[middle + 2..middle + end];
+                    out.push_str(&format!("{} ({})", label.underline(), url.dimmed()));
+                    rest = &rest[middle + end + 1..];
+                    continue;
+                }
+            }
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+// Markup that was opened, but not closed yet. More text could change how this renders.
+fn inline_open(text: &str) -> bool {
+    let bold = text.matches("**").count();
+    let italic = text.replace("**", "").matches('*').count();
+    let link_open = text.rfind('[').is_some_and(|start| {
+        let after = &text[start..];
+        match after.find(']') {
+            None => true,
+            Some(close) => after[close..].starts_with("](") && !after[close..].contains(')'),
+        }
+    });
+    text.matches('`').count() % 2 == 1 || bold % 2 == 1 || italic % 2 == 1 || link_open
+}
+
+// How much of the text can be printed: up to the last space where no markup is left open
+fn printable_end(text: &str) -> Option<usize> {
+    text.char_indices()
+        .rev()
+        .filter(|(_, c)| c.is_whitespace())
+        .map(|(i, c)| i + c.len_utf8())
+        .find(|end| !inline_open(&text[..*end]))
+}
+
+fn table_cells(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    line.split('|')
+        .map(|cell| render_inline(cell.trim()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWER: &str = "# Title\n\nSome **bold** and `code`, see [docs](https://rup12.net).\n\
+        - one\n- two\n\n| a | bb |\n|---|:-:|\n| 1 | 2 |\n\n```rust\nfn main() {}\n```\n---\nDone";
+
+    #[test]
+    fn markdown_is_rendered_while_streaming() {
+        colored::control::set_override(false);
+
+        let mut renderer = MarkdownRenderer::new();
+        // the words are printed once we know they are not part of some markup
+        assert_eq!(renderer.push("Some **bo"), "Some ");
+        assert_eq!(renderer.push("ld** te"), "bold ");
+        assert_eq!(renderer.push("xt\n- "), "text\n• ");
+        assert_eq!(renderer.push("item"), "");
+        assert_eq!(renderer.finish(), "item");
+
+        // one character at a time, it ends up the same as all at once
+        let mut renderer = MarkdownRenderer::new();
+        let mut streamed: String = ANSWER
+            .chars()
+            .map(|c| renderer.push(&c.to_string()))
+            .collect();
+        streamed.push_str(&renderer.finish());
+        let rendered = render_markdown(ANSWER);
+        assert_eq!(streamed, rendered);
+
+        let (before, code) = rendered.split_once("```rust\n").unwrap();
+        assert_eq!(
+            before,
+            "Title\n\nSome bold and code, see docs (https://rup12.net).\n• one\n• two\n\n\
+            │ a │ bb │\n├───┼────┤\n│ 1 │ 2  │\n\n"
+        );
+        // highlighted code is always colored, it comes from syntect
+        assert!(code.starts_with("\x1b[38;2;"));
+        assert!(code.ends_with(&format!("```\n{}\nDone", "─".repeat(40))));
+    }
+
+    #[test]
+    fn line_kinds() {
+        assert_eq!(classify("## Usage", false), Some(LineKind::Header(2)));
+        assert_eq!(classify("##", false), None);
+        assert_eq!(classify("#hashtag", false), Some(LineKind::Paragraph));
+        assert_eq!(classify("--", false), None);
+        assert_eq!(classify("- ", false), Some(LineKind::ListItem));
+        assert_eq!(classify("---", true), Some(LineKind::Rule));
+        assert_eq!(classify("**bold**", false), Some(LineKind::Paragraph));
+        assert_eq!(classify("``", false), None);
+        assert_eq!(classify("`code`", false), Some(LineKind::Paragraph));
+        assert_eq!(classify("  ~~~python", false), Some(LineKind::Fence));
+    }
+}
@@ -0,0 +1,479 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use aws_sdk_bedrock::types::FoundationModelDetails;
+use aws_sdk_bedrockruntime::{
+    operation::{
+        converse::ConverseOutput,
+        converse_stream::ConverseStreamOutput as ConverseStreamOperationOutput,
+    },
+    primitives::Blob,
+    types::{
+        ConverseStreamOutput, InferenceConfiguration, Message, SystemContentBlock,
+        ToolConfiguration,
+    },
+};
+use aws_smithy_types::Document;
+use aws_types::region::Region;
+
+use crate::guardrails::GuardrailConfig;
+use crate::models::base_model_id;
+use crate::models::catalog::CatalogEntry;
+use crate::models::error::{BedrockError, BedrockErrorKind};
+use crate::models::resolver::arn_region;
+use crate::models::retry::RetryPolicy;
+
+// NOTE: This is the seam between Bedrust and whatever is actually answering the questions.
+// Everything that used to talk to the `aws_sdk_bedrockruntime::Client` or the
+// `aws_sdk_bedrock::Client` directly now goes through a `ModelBackend`. The Bedrock one is what
+// you get by default, the scripted one (see `scripted.rs`) lets us run offline.
+
+// Everything we need to send a Converse or ConverseStream request, regardless of the backend
+#[derive(Debug, Clone)]
+pub struct ConverseRequest {
+    pub model_id: String,
+    pub messages: Vec<Message>,
+    pub system: Option<Vec<SystemContentBlock>>,
+    pub inference_config: Option<InferenceConfiguration>,
+    pub tool_config: Option<ToolConfiguration>,
+    // model specific request fields (ie the reasoning budget for Claude)
+    pub additional_fields: Option<Document>,
+}
+
+impl ConverseRequest {
+    pub fn new(model_id: impl Into<String>, messages: Vec<Message>) -> Self {
+        ConverseRequest {
+            model_id: model_id.into(),
+            messages,
+            system: None,
+            inference_config: None,
+            tool_config: None,
+            additional_fields: None,
+        }
+    }
+
+    pub fn system(mut self, system: Option<Vec<SystemContentBlock>>) -> Self {
+        self.system = system;
+        self
+    }
+
+    pub fn inference_config(mut self, inference_config: InferenceConfiguration) -> Self {
+        self.inference_config = Some(inference_config);
+        self
+    }
+
+    pub fn tool_config(mut self, tool_config: Option<ToolConfiguration>) -> Self {
+        self.tool_config = tool_config;
+        self
+    }
+
+    pub fn additional_fields(mut self, additional_fields: Option<Document>) -> Self {
+        self.additional_fields = additional_fields;
+        self
+    }
+}
+
+// Where the stream events come from
+enum EventSource {
+    Bedrock(Box<ConverseStreamOperationOutput>),
+    Scripted(VecDeque<Result<ConverseStreamOutput, BedrockError>>),
+}
+
+// A stream of ConverseStream events. It mimics the `recv()` of the SDK event receiver, so the
+// streaming loop does not care where the events are coming from.
+pub struct ConverseEventStream {
+    source: EventSource,
+}
+
+impl ConverseEventStream {
+    pub fn from_events(
+        events: impl IntoIterator<Item = Result<ConverseStreamOutput, BedrockError>>,
+    ) -> Self {
+        ConverseEventStream {
+            source: EventSource::Scripted(events.into_iter().collect()),
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<Option<ConverseStreamOutput>, BedrockError> {
+        match &mut self.source {
+            EventSource::Bedrock(output) => output
+                .stream
+                .recv()
+                .await
+                .map_err(|e| BedrockError::from_sdk_error(&e)),
+            EventSource::Scripted(events) => events.pop_front().transpose(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait ModelBackend: Send + Sync {
+    // Single shot Converse call
+    async fn converse(&self, request: ConverseRequest) -> Result<ConverseOutput, BedrockError>;
+
+    // Streaming Converse call
+    async fn converse_stream(
+        &self,
+        request: ConverseRequest,
+    ) -> Result<ConverseEventStream, BedrockError>;
+
+    // InvokeModel with a JSON body, for the models Converse does not cover (ie image generation)
+    async fn invoke_model(&self, model_id: &str, body: Vec<u8>) -> Result<Vec<u8>, BedrockError>;
+
+    // How calls to this backend are retried
+    fn retry_policy(&self) -> &RetryPolicy;
+
+    // Details about a foundation model (modalities, streaming support, ...)
+    async fn model_details(&self, model_id: &str) -> Result<FoundationModelDetails, anyhow::Error>;
+
+    // All the foundation models and inference profiles we can use
+    async fn list_models(&self) -> Result<Vec<CatalogEntry>, anyhow::Error>;
+
+    // ARNs of the foundation models behind an inference profile (id or ARN)
+    async fn inference_profile_models(&self, profile: &str) -> Result<Vec<String>, anyhow::Error>;
+
+    // ARN of the foundation model behind a provisioned throughput
+    async fn provisioned_model(&self, arn: &str) -> Result<String, anyhow::Error>;
+
+    // The AWS region models are called in, unless they are pinned to another one
+    fn region(&self) -> Option<&str> {
+        None
+    }
+}
+
+// === Amazon Bedrock ===
+// The clients for a single region
+#[derive(Clone)]
+struct BedrockClients {
+    runtime: aws_sdk_bedrockruntime::Client,
+    bedrock: aws_sdk_bedrock::Client,
+}
+
+impl BedrockClients {
+    fn new(config: &aws_config::SdkConfig) -> Self {
+        BedrockClients {
+            runtime: aws_sdk_bedrockruntime::Client::new(config),
+            bedrock: aws_sdk_bedrock::Client::new(config),
+        }
+    }
+}
+
+pub struct BedrockBackend {
+    config: aws_config::SdkConfig,
+    // the clients for the configured region
+    clients: BedrockClients,
+    // model id -> the region the model is pinned to
+    model_regions: HashMap<String, String>,
+    // clients for the other regions, created the first time a model needs them
+    regional_clients: Mutex<HashMap<String, BedrockClients>>,
+    retry_policy: RetryPolicy,
+    // applied to every Converse and ConverseStream call
+    guardrail: Option<GuardrailConfig>,
+}
+
+impl BedrockBackend {
+    pub fn new(config: &aws_config::SdkConfig) -> Self {
+        BedrockBackend {
+            config: config.clone(),
+            clients: BedrockClients::new(config),
+            model_regions: HashMap::new(),
+            regional_clients: Mutex::new(HashMap::new()),
+            retry_policy: RetryPolicy::default(),
+            guardrail: None,
+        }
+    }
+
+    pub fn with_model_regions(mut self, model_regions: HashMap<String, String>) -> Self {
+        self.model_regions = model_regions;
+        self
+    }
+
+    // NOTE: A model is called in the region it is pinned to (by its id, or the id of the base
+    // model), and ARNs in the region they are from. Everything else uses the configured region.
+    // The pin of a base model only covers the profiles of its geography, a `us.` profile can not
+    // be called in `eu-central-1`.
+    pub fn model_region<'a>(&'a self, model_id: &'a str) -> Option<&'a str> {
+        self.model_regions
+            .get(model_id)
+            .or_else(|| {
+                self.model_regions
+                    .get(base_model_id(model_id))
+                    .filter(|region| in_geography(model_id, region))
+            })
+            .map(String::as_str)
+            .or_else(|| arn_region(model_id))
+    }
+
+    fn clients_for(&self, model_id: &str) -> BedrockClients {
+        let region = match self.model_region(model_id) {
+            Some(region) if Some(region) != self.region() => region,
+            _ => return self.clients.clone(),
+        };
+        self.regional_clients
+            .lock()
+            .unwrap()
+            .entry(region.to_string())
+            .or_insert_with(|| {
+                println!("🌍 | Using the AWS region {} for {}", region, model_id);
+                let config = self
+                    .config
+                    .to_builder()
+                    .region(Region::new(region.to_string()))
+                    .build();
+                BedrockClients::new(&config)
+            })
+            .clone()
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_guardrail(mut self, guardrail: Option<GuardrailConfig>) -> Self {
+        self.guardrail = guardrail;
+        self
+    }
+}
+
+// Is the region part of the geography of the cross region profile? (Any region is, for a model id
+// without a geo prefix)
+fn in_geography(model_id: &str, region: &str) -> bool {
+    let geography = model_id
+        .strip_suffix(base_model_id(model_id))
+        .unwrap_or_default();
+    match geography {
+        "" => true,
+        "us." => region.starts_with("us-") && !region.starts_with("us-gov-"),
+        "us-gov." => region.starts_with("us-gov-"),
+        "eu." => region.starts_with("eu-"),
+        "apac." => region.starts_with("ap-"),
+        _ => false,
+    }
+}
+
+fn invalid_guardrail(e: aws_sdk_bedrockruntime::error::BuildError) -> BedrockError {
+    BedrockError::new(
+        BedrockErrorKind::Validation,
+        format!("Invalid guardrail configuration: {}", e),
+    )
+}
+
+#[async_trait]
+impl ModelBackend for BedrockBackend {
+    fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    async fn converse(&self, request: ConverseRequest) -> Result<ConverseOutput, BedrockError> {
+        let model_id = request.model_id;
+        let guardrail = self
+            .guardrail
+            .as_ref()
+            .map(GuardrailConfig::to_configuration)
+            .transpose()
+            .map_err(invalid_guardrail)?;
+        self.clients_for(&model_id)
+            .runtime
+            .converse()
+            .model_id(&model_id)
+            .set_guardrail_config(guardrail)
+            .set_system(request.system)
+            .set_messages(Some(request.messages))
+            .set_inference_config(request.inference_config)
+            .set_tool_config(request.tool_config)
+            .set_additional_model_request_fields(request.additional_fields)
+            .send()
+            .await
+            .map_err(|e| BedrockError::from_sdk_error(&e).with_model(&model_id))
+    }
+
+    async fn converse_stream(
+        &self,
+        request: ConverseRequest,
+    ) -> Result<ConverseEventStream, BedrockError> {
+        let model_id = request.model_id;
+        let guardrail = self
+            .guardrail
+            .as_ref()
+            .map(GuardrailConfig::to_stream_configuration)
+            .transpose()
+            .map_err(invalid_guardrail)?;
+        let output = self
+            .clients_for(&model_id)
+            .runtime
+            .converse_stream()
+            .model_id(&model_id)
+            .set_guardrail_config(guardrail)
+            .set_system(request.system)
+            .set_messages(Some(request.messages))
+            .set_inference_config(request.inference_config)
+            .set_tool_config(request.tool_config)
+            .set_additional_model_request_fields(request.additional_fields)
+            .send()
+            .await
+            .map_err(|e| BedrockError::from_sdk_error(&e).with_model(&model_id))?;
+
+        Ok(ConverseEventStream {
+            source: EventSource::Bedrock(Box::new(output)),
+        })
+    }
+
+    async fn invoke_model(&self, model_id: &str, body: Vec<u8>) -> Result<Vec<u8>, BedrockError> {
+        let output = self
+            .clients_for(model_id)
+            .runtime
+            .invoke_model()
+            .model_id(model_id)
+            .content_type("application/json")
+            .accept("application/json")
+            .body(Blob::new(body))
+            .send()
+            .await
+            .map_err(|e| BedrockError::from_sdk_error(&e).with_model(model_id))?;
+        Ok(output.body.into_inner())
+    }
+
+    async fn model_details(&self, model_id: &str) -> Result<FoundationModelDetails, anyhow::Error> {
+        let bedrock = self.clients_for(model_id).bedrock;
+        let res = self
+            .retry_policy
+            .run("Getting the model details", || async {
+                bedrock
+                    .get_foundation_model()
+                    .model_identifier(model_id)
+                    .send()
+                    .await
+                    .map_err(|e| BedrockError::from_sdk_error(&e).with_model(model_id))
+            })
+            .await?;
+        res.model_details()
+            .cloned()
+            .ok_or_else(|| anyhow!("Unable to get model details"))
+    }
+
+    async fn list_models(&self) -> Result<Vec<CatalogEntry>, anyhow::Error> {
+        let bedrock = &self.clients.bedrock;
+        let models = self
+            .retry_policy
+            .run("Listing the models", || async {
+                bedrock
+                    .list_foundation_models()
+                    .send()
+                    .await
+                    .map_err(|e| BedrockError::from_sdk_error(&e))
+            })
+            .await?;
+        let mut entries: Vec<CatalogEntry> = models
+            .model_summaries()
+            .iter()
+            .map(CatalogEntry::from_model_summary)
+            .collect();
+
+        // the whole listing starts over when a page fails
+        let profiles = self
+            .retry_policy
+            .run("Listing the inference profiles", || async {
+                bedrock
+                    .list_inference_profiles()
+                    .into_paginator()
+                    .items()
+                    .send()
+                    .collect::<Vec<_>>()
+                    .await
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| BedrockError::from_sdk_error(&e))
+            })
+            .await?;
+        entries.extend(profiles.iter().map(CatalogEntry::from_profile_summary));
+        Ok(entries)
+    }
+
+    async fn inference_profile_models(&self, profile: &str) -> Result<Vec<String>, anyhow::Error> {
+        let bedrock = self.clients_for(profile).bedrock;
+        let res = self
+            .retry_policy
+            .run("Getting the inference profile", || async {
+                bedrock
+                    .get_inference_profile()
+                    .inference_profile_identifier(profile)
+                    .send()
+                    .await
+                    .map_err(|e| BedrockError::from_sdk_error(&e).with_model(profile))
+            })
+            .await?;
+        Ok(res
+            .models()
+            .iter()
+            .filter_map(|m| m.model_arn().map(|a| a.to_string()))
+            .collect())
+    }
+
+    async fn provisioned_model(&self, arn: &str) -> Result<String, anyhow::Error> {
+        let bedrock = self.clients_for(arn).bedrock;
+        let res = self
+            .retry_policy
+            .run("Getting the provisioned throughput", || async {
+                bedrock
+                    .get_provisioned_model_throughput()
+                    .provisioned_model_id(arn)
+                    .send()
+                    .await
+                    .map_err(|e| BedrockError::from_sdk_error(&e).with_model(arn))
+            })
+            .await?;
+        Ok(res.foundation_model_arn().to_string())
+    }
+
+    fn region(&self) -> Option<&str> {
+        self.config.region().map(|region| region.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_config::BehaviorVersion;
+
+    #[test]
+    fn models_are_called_in_their_region() {
+        let config = aws_config::SdkConfig::builder()
+            .region(Region::new("us-east-1"))
+            .behavior_version(BehaviorVersion::latest())
+            .build();
+        let backend = BedrockBackend::new(&config).with_model_regions(HashMap::from([(
+            "anthropic.claude-3-7-sonnet-20250219-v1:0".to_string(),
+            "eu-central-1".to_string(),
+        )]));
+        assert_eq!(backend.region(), Some("us-east-1"));
+        assert_eq!(backend.model_region("amazon.nova-pro-v1:0"), None);
+        assert_eq!(
+            backend.model_region("eu.anthropic.claude-3-7-sonnet-20250219-v1:0"),
+            Some("eu-central-1")
+        );
+        // the profile of another geography stays in the configured region
+        assert_eq!(
+            backend.model_region("us.anthropic.claude-3-7-sonnet-20250219-v1:0"),
+            None
+        );
+        assert_eq!(
+            backend.model_region("arn:aws:bedrock:us-west-2:123456789012:provisioned-model/abc"),
+            Some("us-west-2")
+        );
+
+        // the clients for another region are created once, and only when needed
+        backend.clients_for("amazon.nova-pro-v1:0");
+        backend.clients_for("arn:aws:bedrock:us-east-1::foundation-model/amazon.nova-pro-v1:0");
+        assert!(backend.regional_clients.lock().unwrap().is_empty());
+        let clients = backend.clients_for("anthropic.claude-3-7-sonnet-20250219-v1:0");
+        backend.clients_for("eu.anthropic.claude-3-7-sonnet-20250219-v1:0");
+        assert_eq!(backend.regional_clients.lock().unwrap().len(), 1);
+        assert_eq!(
+            clients.runtime.config().region().map(|r| r.as_ref()),
+            Some("eu-central-1")
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aws_sdk_bedrock::types::{FoundationModelSummary, InferenceProfileSummary};
+use chrono::Utc;
+use clap::ValueEnum;
+use dirs::home_dir;
+use serde::{Deserialize, Serialize};
+
+use crate::constants;
+use crate::models::backend::ModelBackend;
+use crate::models::base_model_id;
+use crate::utils::{print_warning, ArgModels};
+
+// NOTE: The models (and inference profiles) we can use. Instead of a hardcoded list that goes out
+// of date with every Bedrock release, we ask Bedrock (ListFoundationModels and
+// ListInferenceProfiles) and cache the result on disk. The cache is refreshed once it is older
+// than `constants::MODEL_CATALOG_MAX_AGE_HOURS`, or when running with `--refresh-models`.
+//
+// On top of that there are aliases: short names for model ids. The user can define their own in
+// `bedrust_config.ron`, and the old `--model-id` names (`claude-v37-sonnet`, `nova-pro`, ...)
+// keep working as built-in aliases. Anything that is not an alias is used as is, so any model id
+// or ARN works.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CatalogEntryKind {
+    FoundationModel,
+    InferenceProfile,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub id: String,
+    pub name: String,
+    pub kind: CatalogEntryKind,
+    #[serde(default)]
+    pub arn: Option<String>,
+    #[serde(default)]
+    pub provider: Option<String>,
+    #[serde(default)]
+    pub input_modalities: Vec<String>,
+    #[serde(default)]
+    pub output_modalities: Vec<String>,
+    #[serde(default)]
+    pub streaming: Option<bool>,
+    // ON_DEMAND, PROVISIONED, INFERENCE_PROFILE
+    #[serde(default)]
+    pub inference_types: Vec<String>,
+    // For inference profiles: the ARNs of the foundation models behind the profile
+    #[serde(default)]
+    pub models: Vec<String>,
+}
+
+impl CatalogEntry {
+    pub fn from_model_summary(summary: &FoundationModelSummary) -> Self {
+        CatalogEntry {
+            id: summary.model_id().to_string(),
+            name: summary
+                .model_name()
+                .unwrap_or(summary.model_id())
+                .to_string(),
+            kind: CatalogEntryKind::FoundationModel,
+            arn: Some(summary.model_arn().to_string()),
+            provider: summary.provider_name().map(|p| p.to_string()),
+            input_modalities: summary
+                .input_modalities()
+                .iter()
+                .map(|m| m.as_str().to_string())
+                .collect(),
+            output_modalities: summary
+                .output_modalities()
+                .iter()
+                .map(|m| m.as_str().to_string())
+                .collect(),
+            streaming: summary.response_streaming_supported(),
+            inference_types: summary
+                .inference_types_supported()
+                .iter()
+                .map(|t| t.as_str().to_string())
+                .collect(),
+            models: Vec::new(),
+        }
+    }
+
+    pub fn from_profile_summary(summary: &InferenceProfileSummary) -> Self {
+        CatalogEntry {
+            id: summary.inference_profile_id().to_string(),
+            name: summary.inference_profile_name().to_string(),
+            kind: CatalogEntryKind::InferenceProfile,
+            arn: Some(summary.inference_profile_arn().to_string()),
+            provider: None,
+            input_modalities: Vec::new(),
+            output_modalities: Vec::new(),
+            streaming: None,
+            inference_types: Vec::new(),
+            models: summary
+                .models()
+                .iter()
+                .filter_map(|m| m.model_arn().map(|a| a.to_string()))
+                .collect(),
+        }
+    }
+
+    // Can we chat with it? Inference profiles are always offered, foundation models only if they
+    // produce text and can be called on demand.
+    pub fn is_chat_model(&self) -> bool {
+        match self.kind {
+            CatalogEntryKind::InferenceProfile => true,
+            CatalogEntryKind::FoundationModel => {
+                self.output_modalities.iter().any(|m| m == "TEXT")
+                    && (self.inference_types.is_empty()
+                        || self.inference_types.iter().any(|t| t == "ON_DEMAND"))
+            }
+        }
+    }
+}
+
+// One line in the model picker
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelChoice {
+    pub id: String,
+    pub label: String,
+}
+
+impl Display for ModelChoice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.label)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelCatalog {
+    // unix timestamp of when the catalog was fetched
+    pub fetched_at: i64,
+    // the models differ from region to region
+    #[serde(default)]
+    pub region: Option<String>,
+    pub entries: Vec<CatalogEntry>,
+}
+
+impl ModelCatalog {
+    pub fn cache_path() -> PathBuf {
+        let home_dir = home_dir().expect("Failed to get HOME directory");
+        home_dir.join(format!(
+            ".config/{}/{}",
+            constants::CONFIG_DIR_NAME,
+            constants::MODEL_CATALOG_FILE_NAME
+        ))
+    }
+
+    pub fn load_cached(path: &Path) -> Option<Self> {
+        let content = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn is_stale(&self) -> bool {
+        Utc::now().timestamp() - self.fetched_at > constants::MODEL_CATALOG_MAX_AGE_HOURS * 3600
+    }
+
+    pub async fn fetch(backend: &dyn ModelBackend) -> Result<Self, anyhow::Error> {
+        Ok(ModelCatalog {
+            fetched_at: Utc::now().timestamp(),
+            region: backend.region().map(str::to_string),
+            entries: backend.list_models().await?,
+        })
+    }
+
+    // Returns the cached catalog, refreshing it when it is stale (or when asked to). If Bedrock
+    // can not be reached we keep using what we have. Without a cache path nothing is read from,
+    // or written to disk.
+    pub async fn load(backend: &dyn ModelBackend, cache: Option<&Path>, refresh: bool) -> Self {
+        let cached = cache.and_then(ModelCatalog::load_cached);
+        if let Some(catalog) = &cached {
+            if !refresh && !catalog.is_stale() && catalog.region.as_deref() == backend.region() {
+                return catalog.clone();
+            }
+        }
+        println!("⏳ | Fetching the list of available models");
+        match ModelCatalog::fetch(backend).await {
+            Ok(catalog) => {
+                if let Some(path) = cache {
+                    if let Err(e) = catalog.save(path) {
+                        print_warning(&format!("Unable to save the model list: {}", e));
+                    }
+                }
+                catalog
+            }
+            Err(e) => {
+                print_warning(&format!("Unable to fetch the list of models: {}", e));
+                cached.unwrap_or_default()
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&CatalogEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.id == id || e.arn.as_deref() == Some(id))
+    }
+
+    // Everything we offer in the model picker: the user aliases first, then the models from the
+    // catalog. If we do not have a catalog, the built-in aliases are offered instead.
+    pub fn choices(&self, aliases: &HashMap<String, String>) -> Vec<ModelChoice> {
+        let mut user_aliases: Vec<_> = aliases.iter().collect();
+        user_aliases.sort();
+        let mut choices: Vec<ModelChoice> = user_aliases
+            .into_iter()
+            .map(|(alias, id)| ModelChoice {
+                id: id.clone(),
+                label: format!("{} ({})", alias, id),
+            })
+            .collect();
+
+        let mut models: Vec<&CatalogEntry> =
+            self.entries.iter().filter(|e| e.is_chat_model()).collect();
+        models.sort_by(|a, b| a.id.cmp(&b.id));
+        if models.is_empty() {
+            choices.extend(
+                builtin_aliases()
+                    .into_iter()
+                    .map(|(alias, id)| ModelChoice {
+                        label: format!("{} ({})", alias, id),
+                        id: id.to_string(),
+                    }),
+            );
+        } else {
+            choices.extend(models.into_iter().map(|e| ModelChoice {
+                id: e.id.clone(),
+                label: format!("{} - {}", e.id, e.name),
+            }));
+        }
+        choices
+    }
+}
+
+// The old `--model-id` values, so existing scripts and muscle memory keep working
+pub fn builtin_aliases() -> Vec<(String, &'static str)> {
+    ArgModels::value_variants()
+        .iter()
+        .filter_map(|m| {
+            m.to_possible_value()
+                .map(|v| (v.get_name().to_string(), m.to_str()))
+        })
+        .collect()
+}
+
+// How many tokens fit in the context window of the model, the config can override it per model
+pub fn context_window(model_id: &str, overrides: &HashMap<String, usize>) -> usize {
+    let base_model = base_model_id(model_id);
+    if let Some(window) = overrides
+        .get(model_id)
+        .or_else(|| overrides.get(base_model))
+    {
+        return *window;
+    }
+    constants::MODEL_CONTEXT_WINDOWS
+        .iter()
+        .find(|(prefix, _)| base_model.starts_with(prefix))
+        .map(|(_, window)| *window)
+        .unwrap_or(constants::DEFAULT_CONTEXT_WINDOW)
+}
+
+// Turns whatever the user gave us (an alias, a model id, an ARN) into what we send to Bedrock
+pub fn resolve_model_id(input: &str, aliases: &HashMap<String, String>) -> String {
+    let input = input.trim();
+    if let Some(id) = aliases.get(input) {
+        return id.clone();
+    }
+    match ArgModels::from_str(input, true) {
+        Ok(model) => model.to_str().to_string(),
+        Err(_) => input.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    fn entry(id: &str, kind: CatalogEntryKind, output: &[&str], types: &[&str]) -> CatalogEntry {
+        CatalogEntry {
+            id: id.to_string(),
+            name: id.to_uppercase(),
+            kind,
+            arn: Some(format!(
+                "arn:aws:bedrock:us-east-1::foundation-model/{}",
+                id
+            )),
+            provider: None,
+            input_modalities: vec!["TEXT".into()],
+            output_modalities: output.iter().map(|s| s.to_string()).collect(),
+            streaming: Some(true),
+            inference_types: types.iter().map(|s| s.to_string()).collect(),
+            models: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn resolve_aliases_and_ids() {
+        let aliases = HashMap::from([("fast".to_string(), "amazon.nova-micro-v1:0".to_string())]);
+        assert_eq!(resolve_model_id("fast", &aliases), "amazon.nova-micro-v1:0");
+        // the old enum names are still accepted
+        assert_eq!(
+            resolve_model_id("claude-v37-sonnet", &aliases),
+            "us.anthropic.claude-3-7-sonnet-20250219-v1:0"
+        );
+        assert_eq!(
+            resolve_model_id("eu.amazon.nova-pro-v1:0", &aliases),
+            "eu.amazon.nova-pro-v1:0"
+        );
+        let arn = "arn:aws:bedrock:us-east-1:123456789012:application-inference-profile/abc";
+        assert_eq!(resolve_model_id(arn, &aliases), arn);
+    }
+
+    #[test]
+    fn catalog_choices_and_cache() {
+        let catalog = ModelCatalog {
+            fetched_at: Utc::now().timestamp(),
+            region: Some("us-east-1".into()),
+            entries: vec![
+                entry(
+                    "b.text",
+                    CatalogEntryKind::FoundationModel,
+                    &["TEXT"],
+                    &["ON_DEMAND"],
+                ),
+                entry(
+                    "a.image",
+                    CatalogEntryKind::FoundationModel,
+                    &["IMAGE"],
+                    &["ON_DEMAND"],
+                ),
+                entry(
+                    "c.profile-only",
+                    CatalogEntryKind::FoundationModel,
+                    &["TEXT"],
+                    &["INFERENCE_PROFILE"],
+                ),
+                entry(
+                    "us.c.profile-only",
+                    CatalogEntryKind::InferenceProfile,
+                    &[],
+                    &[],
+                ),
+            ],
+        };
+        let aliases = HashMap::from([("mine".to_string(), "b.text".to_string())]);
+        let ids: Vec<String> = catalog
+            .choices(&aliases)
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(ids, vec!["b.text", "b.text", "us.c.profile-only"]);
+        assert!(!catalog.is_stale());
+
+        // no catalog, we fall back to the built-in aliases
+        let empty = ModelCatalog::default();
+        assert!(empty.is_stale());
+        assert_eq!(
+            empty.choices(&HashMap::new()).len(),
+            ArgModels::value_variants().len()
+        );
+
+        let dir = test_dir("catalog");
+        let path = dir.join("models.json");
+        catalog.save(&path).unwrap();
+        let loaded = ModelCatalog::load_cached(&path).unwrap();
+        assert_eq!(loaded.entries, catalog.entries);
+        assert_eq!(loaded.region.as_deref(), Some("us-east-1"));
+        assert!(loaded.get("b.text").is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
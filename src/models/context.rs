@@ -0,0 +1,313 @@
+use aws_sdk_bedrockruntime::types::{
+    ContentBlock, ConversationRole, DocumentBlock, DocumentFormat, Message, SystemContentBlock,
+    ToolResultContentBlock,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::constants;
+use crate::models::document::document_to_json;
+use crate::models::error::{BedrockError, BedrockErrorKind};
+
+// NOTE: Context window guard. The whole conversation is sent with every question, and a long one
+// (ie a code chat) ends up bigger than the context window of the model, which Bedrock answers with
+// a ValidationException. So before sending, we estimate the tokens of the conversation and either
+// leave the oldest messages out, or refuse with a clear message (`context_policy`). Only what is
+// sent is cut, the conversation (and the saved chat) keeps everything.
+
+// Bedrock counts images by their size, this is about what a 1000x1000 image costs
+const IMAGE_TOKENS: usize = 1_600;
+const VIDEO_TOKENS: usize = 10_000;
+// the role and the framing of every message
+const MESSAGE_TOKENS: usize = 4;
+
+// What to do when the conversation does not fit in the context window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ContextPolicy {
+    // leave the oldest messages out, so the conversation keeps going
+    #[default]
+    DropOldest,
+    // do not send it, the user decides what to do
+    Refuse,
+}
+
+// The context window of a model, and what to do when the conversation does not fit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContextGuard {
+    pub window: usize,
+    pub policy: ContextPolicy,
+}
+
+// The messages that fit, and how many were left out
+#[derive(Debug)]
+pub struct FittedMessages {
+    pub messages: Vec<Message>,
+    pub dropped: usize,
+    pub tokens: usize,
+}
+
+impl ContextGuard {
+    pub fn new(window: usize, policy: ContextPolicy) -> Self {
+        ContextGuard { window, policy }
+    }
+
+    // How many tokens the messages can have, once the system prompt and the answer have room
+    pub fn budget(&self, system: &[SystemContentBlock], max_output: usize) -> usize {
+        let usable = (self.window as f64 * constants::CONTEXT_WINDOW_MARGIN) as usize;
+        usable.saturating_sub(estimate_system_tokens(system) + max_output)
+    }
+
+    pub fn fit(
+        &self,
+        mut messages: Vec<Message>,
+        system: &[SystemContentBlock],
+        max_output: usize,
+    ) -> Result<FittedMessages, BedrockError> {
+        let budget = self.budget(system, max_output);
+        let tokens: Vec<usize> = messages.iter().map(estimate_tokens).collect();
+        let total: usize = tokens.iter().sum();
+        if total <= budget {
+            return Ok(FittedMessages {
+                messages,
+                dropped: 0,
+                tokens: total,
+            });
+        }
+        if self.policy == ContextPolicy::Refuse {
+            return Err(BedrockError::new(
+                BedrockErrorKind::ContextWindow,
+                format!(
+                    "The conversation is about {} tokens, only about {} fit in the context window ({} tokens, with room for the answer)",
+                    total, budget, self.window
+                ),
+            ));
+        }
+
+        // NOTE: The conversation has to start with a question from the user, not with an answer
+        // or the result of a tool the model asked for (its tool use would be gone)
+        let mut start = 0;
+        let mut remaining = total;
+        while start < messages.len() && (remaining > budget || !starts_a_turn(&messages[start])) {
+            remaining -= tokens[start];
+            start += 1;
+        }
+        if start == messages.len() {
+            let question = messages
+                .iter()
+                .rposition(starts_a_turn)
+                .map(|i| tokens[i..].iter().sum::<usize>())
+                .unwrap_or(total);
+            return Err(BedrockError::new(
+                BedrockErrorKind::ContextWindow,
+                format!(
+                    "The question alone is about {} tokens, only about {} fit in the context window ({} tokens, with room for the answer)",
+                    question, budget, self.window
+                ),
+            ));
+        }
+        messages.drain(..start);
+        Ok(FittedMessages {
+            messages,
+            dropped: start,
+            tokens: remaining,
+        })
+    }
+}
+
+// A question from the user, not the result of a tool
+pub fn starts_a_turn(message: &Message) -> bool {
+    message.role() == &ConversationRole::User
+        && !message
+            .content()
+            .iter()
+            .any(|block| matches!(block, ContentBlock::ToolResult(_)))
+}
+
+// About 4 characters per token, which is close enough for English and code. Rounded up, so a
+// conversation is rather too big than too small.
+fn text_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+// Text documents are counted like text. The others (pdf, docx, xlsx) are mostly not text, so
+// their bytes count for less.
+fn document_tokens(document: &DocumentBlock) -> usize {
+    let bytes = document
+        .source()
+        .and_then(|source| source.as_bytes().ok())
+        .map(|bytes| bytes.as_ref().len())
+        .unwrap_or_default();
+    match document.format() {
+        DocumentFormat::Txt | DocumentFormat::Md | DocumentFormat::Csv | DocumentFormat::Html => {
+            bytes.div_ceil(4)
+        }
+        _ => bytes.div_ceil(8),
+    }
+}
+
+pub fn estimate_tokens(message: &Message) -> usize {
+    let content: usize = message
+        .content()
+        .iter()
+        .map(|block| match block {
+            ContentBlock::Text(text) => text_tokens(text),
+            ContentBlock::Image(_) => IMAGE_TOKENS,
+            ContentBlock::Video(_) => VIDEO_TOKENS,
+            ContentBlock::Document(document) => document_tokens(document),
+            ContentBlock::ToolUse(tool_use) => {
+                text_tokens(tool_use.name())
+                    + text_tokens(&document_to_json(tool_use.input()).to_string())
+            }
+            ContentBlock::ToolResult(result) => result
+                .content()
+                .iter()
+                .map(|content| match content {
+                    ToolResultContentBlock::Text(text) => text_tokens(text),
+                    ToolResultContentBlock::Json(json) => {
+                        text_tokens(&document_to_json(json).to_string())
+                    }
+                    ToolResultContentBlock::Image(_) => IMAGE_TOKENS,
+                    ToolResultContentBlock::Video(_) => VIDEO_TOKENS,
+                    ToolResultContentBlock::Document(document) => document_tokens(document),
+                    _ => 0,
+                })
+                .sum(),
+            ContentBlock::ReasoningContent(reasoning) => match reasoning.as_reasoning_text() {
+                Ok(reasoning) => text_tokens(reasoning.text()),
+                Err(_) => reasoning
+                    .as_redacted_content()
+                    .map(|redacted| redacted.as_ref().len().div_ceil(4))
+                    .unwrap_or_default(),
+            },
+            ContentBlock::GuardContent(guard) => guard
+                .as_text()
+                .map(|text| text_tokens(text.text()))
+                .unwrap_or_default(),
+            _ => 0,
+        })
+        .sum();
+    content + MESSAGE_TOKENS
+}
+
+pub fn estimate_system_tokens(system: &[SystemContentBlock]) -> usize {
+    system
+        .iter()
+        .map(|block| match block {
+            SystemContentBlock::Text(text) => text_tokens(text),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{ConversationHistory, SerializableMessage};
+    use crate::models::catalog::context_window;
+    use crate::models::converse_stream::{call_converse_stream, ChatOptions};
+    use crate::models::scripted::{ScriptedBackend, ScriptedTurn};
+    use aws_sdk_bedrockruntime::types::{InferenceConfiguration, ToolResultBlock};
+    use std::collections::HashMap;
+
+    fn message(role: ConversationRole, content: ContentBlock) -> Message {
+        Message::builder()
+            .role(role)
+            .content(content)
+            .build()
+            .unwrap()
+    }
+
+    fn text(role: ConversationRole, chars: usize) -> Message {
+        message(role, ContentBlock::Text("a".repeat(chars)))
+    }
+
+    #[tokio::test]
+    async fn long_conversations_are_cut_to_fit() {
+        let overrides = HashMap::from([("amazon.nova-micro-v1:0".to_string(), 1_000)]);
+        assert_eq!(
+            context_window("us.amazon.nova-micro-v1:0", &overrides),
+            1_000
+        );
+        assert_eq!(
+            context_window("us.amazon.nova-lite-v1:0", &overrides),
+            300_000
+        );
+        assert_eq!(context_window("anthropic.claude-v2", &overrides), 100_000);
+        assert_eq!(context_window("anthropic.claude-v2:1", &overrides), 200_000);
+        assert_eq!(context_window("some.new-model", &overrides), 32_000);
+
+        // 10 tokens of system prompt, 100 for the answer: 900 - 110 = 790 for the messages
+        let guard = ContextGuard::new(1_000, ContextPolicy::DropOldest);
+        let system = [SystemContentBlock::Text("s".repeat(40))];
+        assert_eq!(guard.budget(&system, 100), 790);
+        let tool_result = ContentBlock::ToolResult(
+            ToolResultBlock::builder()
+                .tool_use_id("1")
+                .content(ToolResultContentBlock::Text("b".repeat(1_000)))
+                .build()
+                .unwrap(),
+        );
+        let messages = vec![
+            text(ConversationRole::User, 1_200),
+            text(ConversationRole::Assistant, 400),
+            message(ConversationRole::User, tool_result),
+            text(ConversationRole::Assistant, 400),
+            text(ConversationRole::User, 800),
+            text(ConversationRole::Assistant, 800),
+            text(ConversationRole::User, 400),
+        ];
+        let tokens: Vec<usize> = messages.iter().map(estimate_tokens).collect();
+        assert_eq!(tokens, [304, 104, 254, 104, 204, 204, 104]);
+
+        // an answer can not go first either, so the one after the tool result goes too
+        let fitted = guard.fit(messages.clone(), &system, 100).unwrap();
+        assert_eq!((fitted.dropped, fitted.tokens), (4, 512));
+        assert_eq!(fitted.messages.len(), 3);
+        let small = guard.fit(messages[4..].to_vec(), &system, 100).unwrap();
+        assert_eq!(small.dropped, 0);
+
+        let refuse = ContextGuard::new(1_000, ContextPolicy::Refuse);
+        let err = refuse.fit(messages.clone(), &system, 100).unwrap_err();
+        assert_eq!(err.kind, BedrockErrorKind::ContextWindow);
+        assert!(err
+            .message
+            .starts_with("The conversation is about 1278 tokens"));
+        let err = guard
+            .fit(vec![text(ConversationRole::User, 4_000)], &system, 100)
+            .unwrap_err();
+        assert!(err
+            .message
+            .starts_with("The question alone is about 1004 tokens"));
+        assert!(err.hint().unwrap().starts_with("Ask a shorter question"));
+
+        // only what is sent is cut, the conversation keeps everything
+        let backend = ScriptedBackend::new(vec![ScriptedTurn::Text("Fits".into())]);
+        let history = ConversationHistory::new(
+            None,
+            None,
+            None,
+            Some(
+                messages
+                    .into_iter()
+                    .map(SerializableMessage::from)
+                    .collect(),
+            ),
+        );
+        let options = ChatOptions::new(InferenceConfiguration::builder().max_tokens(100).build())
+            .echo(false)
+            .context(guard);
+        let convo = call_converse_stream(
+            &backend,
+            "amazon.nova-micro-v1:0".into(),
+            &history,
+            &options,
+            &system,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(convo.content, "Fits");
+        assert_eq!(backend.requests()[0].messages.len(), 3);
+        assert_eq!(history.messages.unwrap().len(), 7);
+    }
+}
@@ -1,88 +1,110 @@
 use aws_sdk_bedrockruntime::{
-    operation::converse::{ConverseError, ConverseOutput},
-    types::{ContentBlock, ConversationRole, InferenceConfiguration, Message, SystemContentBlock},
+    operation::converse::ConverseOutput,
+    types::{
+        ContentBlock, ConversationRole, InferenceConfiguration, Message, StopReason,
+        SystemContentBlock,
+    },
 };
 
-// Converse Error type
-//
-#[derive(Debug)]
-pub struct BedrockConverseError(String);
-impl std::fmt::Display for BedrockConverseError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // FIX:
-        // Figure out how to have this
-        // write!(f, "Can't invoke '{}'. Reason: {}", MODEL_ID, self.0)
-        write!(f, "Can't invoke. Reason: {}", self.0)
-    }
-}
-impl std::error::Error for BedrockConverseError {}
-impl From<&str> for BedrockConverseError {
-    fn from(value: &str) -> Self {
-        BedrockConverseError(value.to_string())
-    }
-}
-impl From<&ConverseError> for BedrockConverseError {
-    fn from(value: &ConverseError) -> Self {
-        BedrockConverseError::from(match value {
-            ConverseError::ModelTimeoutException(_) => "Model took too long",
-            ConverseError::ModelNotReadyException(_) => "Model is not ready",
-            _ => "Unknown",
-        })
-    }
-}
+use crate::guardrails::{print_guardrail_trace, GuardrailTraceRecord};
+use crate::models::backend::{ConverseRequest, ModelBackend};
+use crate::models::converse_stream::converse_metadata;
+use crate::models::error::{BedrockError, BedrockErrorKind};
+use crate::usage::TurnRecord;
+use aws_smithy_types::Document;
+
 // === Main functions ===
 
-// Function to get the output text
-fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockConverseError> {
-    let text = output
+// Function to get the output text. Reasoning models think first, so the reasoning blocks are
+// skipped, and all the text blocks are joined.
+fn get_converse_output_text(output: ConverseOutput) -> Result<String, BedrockError> {
+    let content = output
         .output()
         .ok_or("no output")?
         .as_message()
         .map_err(|_| "output not a message")?
-        .content()
-        .first()
-        .ok_or("no content in message")?
-        .as_text()
-        .map_err(|_| "content is not text")?
-        .to_string();
-    Ok(text)
+        .content();
+    if content.is_empty() {
+        return Err("no content in message".into());
+    }
+    let text: Vec<&str> = content
+        .iter()
+        .filter_map(|block| block.as_text().ok())
+        .map(String::as_str)
+        .collect();
+    if text.is_empty() {
+        return Err("content is not text".into());
+    }
+    Ok(text.join("\n"))
 }
 
 pub async fn call_converse(
-    bc: &aws_sdk_bedrockruntime::Client,
+    backend: &dyn ModelBackend,
     model_id: String,
     inference_parameters: InferenceConfiguration,
+    additional_fields: Option<Document>,
     content: ContentBlock,
     system: Option<Vec<SystemContentBlock>>,
     echo: bool,
-) -> Result<String, BedrockConverseError> {
-    let response = bc
-        .converse()
-        .model_id(model_id)
-        .set_system(system)
-        .messages(
-            Message::builder()
-                .role(ConversationRole::User)
-                // FIX: How to not clone this?
-                .content(content.clone())
-                .build()
-                .map_err(|_| "Failed to build message")?,
-        )
+) -> Result<String, BedrockError> {
+    // FIX: How to not clone this?
+    let (text, _) = call_converse_with_usage(
+        backend,
+        model_id,
+        inference_parameters,
+        additional_fields,
+        vec![content.clone()],
+        system,
+    )
+    .await?;
+    if echo {
+        println!("{}", text);
+    }
+    Ok(text)
+}
+
+// Same as `call_converse`, for a question made of more than one block (ie documents and the
+// text), with what the answer used
+pub async fn call_converse_with_usage(
+    backend: &dyn ModelBackend,
+    model_id: String,
+    inference_parameters: InferenceConfiguration,
+    additional_fields: Option<Document>,
+    content: Vec<ContentBlock>,
+    system: Option<Vec<SystemContentBlock>>,
+) -> Result<(String, TurnRecord), BedrockError> {
+    let message = Message::builder()
+        .role(ConversationRole::User)
+        .set_content(Some(content))
+        .build()
+        .map_err(|_| "Failed to build message")?;
+
+    let request = ConverseRequest::new(model_id.as_str(), vec![message])
+        .system(system)
         .inference_config(inference_parameters)
-        .send()
-        .await;
+        .additional_fields(additional_fields);
 
-    match response {
-        Ok(output) => {
-            let text = get_converse_output_text(output)?;
-            if echo {
-                println!("{}", text);
-            }
-            Ok(text)
-        }
-        Err(e) => Err(e
-            .as_service_error()
-            .map(BedrockConverseError::from)
-            .unwrap_or_else(|| BedrockConverseError("Unknown service error".into()))),
+    let output = backend.converse(request).await?;
+    let mut usage = TurnRecord::new(&model_id);
+    usage.add_metadata(&converse_metadata(&output));
+    let mut guardrail = GuardrailTraceRecord {
+        intervened: output.stop_reason() == &StopReason::GuardrailIntervened,
+        ..Default::default()
+    };
+    if let Some(trace) = output.trace().and_then(|t| t.guardrail()) {
+        guardrail.add_trace(trace);
+    }
+    if !guardrail.is_empty() {
+        print_guardrail_trace(&guardrail);
+        usage.guardrail = Some(guardrail.clone());
+    }
+    let text = get_converse_output_text(output)?;
+    // NOTE: The text is the blocked message of the guardrail, not an answer we can use (as a
+    // caption, a file name, ...)
+    if guardrail.intervened {
+        return Err(
+            BedrockError::new(BedrockErrorKind::GuardrailIntervened, text).with_model(&model_id),
+        );
     }
+    Ok((text, usage))
 }
@@ -1,170 +1,592 @@
-use crate::chat::{Conversation, ConversationEntity, ConversationHistory};
+use crate::chat::{Conversation, ConversationEntity, ConversationHistory, SerializableMessage};
+use crate::compaction::compacted_messages;
+use crate::guardrails::{print_guardrail_trace, GuardrailTraceRecord};
+use crate::interrupt::Interrupt;
+use crate::markdown::{render_markdown, MarkdownRenderer};
+use crate::models::backend::{ConverseEventStream, ConverseRequest, ModelBackend};
+use crate::models::cache_point;
+use crate::models::context::ContextGuard;
+use crate::models::document::{document_to_json, json_to_document};
+use crate::models::error::{BedrockError, BedrockErrorKind};
+use crate::tools::ToolRegistry;
+use crate::usage::TurnRecord;
 use aws_sdk_bedrockruntime::{
-    error::ProvideErrorMetadata,
-    operation::converse_stream::ConverseStreamError,
+    operation::converse::ConverseOutput,
     types::{
-        error::ConverseStreamOutputError, ConverseStreamOutput as ConverseStreamOutputType,
-        InferenceConfiguration, Message, SystemContentBlock,
+        ContentBlock, ContentBlockDelta, ContentBlockStart, ConversationRole,
+        ConverseStreamMetadataEvent, ConverseStreamMetrics,
+        ConverseStreamOutput as ConverseStreamOutputType, ConverseStreamTrace,
+        InferenceConfiguration, Message, ReasoningContentBlock, ReasoningContentBlockDelta,
+        ReasoningTextBlock, StopReason, SystemContentBlock, ToolResultStatus, ToolUseBlock,
     },
 };
+use aws_smithy_types::{Blob, Document};
+use serde_json::Value;
+use std::io::{self, Write};
 
-// Converse Error type
-//
-#[derive(Debug)]
-pub struct BedrockConverseStreamError(String);
-impl std::fmt::Display for BedrockConverseStreamError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Can't invoke. Reason: {}", self.0)
+// How many times in a row the model can ask for tools in a single turn
+const MAX_TOOL_ROUNDS: usize = 10;
+
+// Added to an answer that was stopped with Ctrl-C, so both the user and the model know it is not
+// the whole answer
+pub const INTERRUPTED_MARKER: &str = "[answer interrupted by the user]";
+
+// The system prompt, followed by a cache point when the model can cache it
+pub fn system_blocks(system_prompt: &str, cache: bool) -> Vec<SystemContentBlock> {
+    let mut blocks = vec![SystemContentBlock::Text(system_prompt.to_string())];
+    if cache {
+        blocks.push(SystemContentBlock::CachePoint(cache_point()));
     }
+    blocks
 }
-impl std::error::Error for BedrockConverseStreamError {}
-impl From<&str> for BedrockConverseStreamError {
-    fn from(value: &str) -> Self {
-        BedrockConverseStreamError(value.into())
+
+// === Main functions ===
+
+// How the chat calls the model, besides the conversation and the system prompt
+#[derive(Debug, Clone)]
+pub struct ChatOptions {
+    pub inference_config: InferenceConfiguration,
+    // model specific request fields (ie the reasoning budget)
+    pub additional_fields: Option<Document>,
+    // print the reasoning while it streams in, it is kept in the conversation either way
+    pub show_reasoning: bool,
+    // the model can cache, so the cache points in the conversation are sent along
+    pub prompt_caching: bool,
+    // the model reasons, so its signed reasoning is sent back to it
+    pub reasoning: bool,
+    // print the answer while it streams in (not when comparing models, they answer all at once)
+    pub echo: bool,
+    // render the markdown in the answer, instead of printing it as it comes
+    pub markdown: bool,
+    // the model supports ConverseStream, the others get the whole answer at once with Converse
+    pub streaming: bool,
+    // keeps what is sent within the context window of the model
+    pub context: Option<ContextGuard>,
+}
+
+impl ChatOptions {
+    pub fn new(inference_config: InferenceConfiguration) -> Self {
+        ChatOptions {
+            inference_config,
+            additional_fields: None,
+            show_reasoning: true,
+            prompt_caching: true,
+            reasoning: true,
+            echo: true,
+            markdown: false,
+            streaming: true,
+            context: None,
+        }
+    }
+
+    pub fn additional_fields(mut self, additional_fields: Option<Document>) -> Self {
+        self.additional_fields = additional_fields;
+        self
+    }
+
+    pub fn show_reasoning(mut self, show_reasoning: bool) -> Self {
+        self.show_reasoning = show_reasoning;
+        self
+    }
+
+    pub fn prompt_caching(mut self, prompt_caching: bool) -> Self {
+        self.prompt_caching = prompt_caching;
+        self
+    }
+
+    pub fn reasoning(mut self, reasoning: bool) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    pub fn markdown(mut self, markdown: bool) -> Self {
+        self.markdown = markdown;
+        self
+    }
+
+    pub fn streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    pub fn context(mut self, context: ContextGuard) -> Self {
+        self.context = Some(context);
+        self
     }
 }
 
-impl From<&ConverseStreamError> for BedrockConverseStreamError {
-    fn from(value: &ConverseStreamError) -> Self {
-        BedrockConverseStreamError(
-            match value {
-                ConverseStreamError::ModelTimeoutException(_) => "Model took too long",
-                ConverseStreamError::ModelNotReadyException(_) => "Model is not ready",
-                ConverseStreamError::ThrottlingException(_) => {
-                    "Your request was throttled, please check your service quotas"
-                }
-                _ => "Unknown",
-            }
-            .into(),
-        )
+// Reasoning arrives in pieces as well: the text, and then the signature we need to send it back.
+// Or, when the provider encrypted it, the redacted content.
+#[derive(Default)]
+struct PendingReasoning {
+    text: String,
+    signature: Option<String>,
+    redacted: Vec<u8>,
+}
+
+impl PendingReasoning {
+    fn into_block(self) -> Option<ContentBlock> {
+        let reasoning = if !self.redacted.is_empty() {
+            ReasoningContentBlock::RedactedContent(Blob::new(self.redacted))
+        } else if !self.text.is_empty() {
+            ReasoningContentBlock::ReasoningText(
+                ReasoningTextBlock::builder()
+                    .text(self.text)
+                    .set_signature(self.signature)
+                    .build()
+                    .ok()?,
+            )
+        } else {
+            return None;
+        };
+        Some(ContentBlock::ReasoningContent(reasoning))
     }
 }
 
-impl From<&ConverseStreamOutputError> for BedrockConverseStreamError {
-    fn from(value: &ConverseStreamOutputError) -> Self {
-        match value {
-            ConverseStreamOutputError::ValidationException(ve) => BedrockConverseStreamError(
-                ve.message().unwrap_or("Unknown ValidationException").into(),
-            ),
-            ConverseStreamOutputError::ThrottlingException(te) => BedrockConverseStreamError(
-                te.message().unwrap_or("Unknown ThrottlingException").into(),
-            ),
-            value => BedrockConverseStreamError(
-                value
-                    .message()
-                    .unwrap_or("Unknown StreamOutput exception")
-                    .into(),
-            ),
-        }
+// NOTE: Bedrock only takes reasoning back along with its signature. Reasoning without one (ie from
+// DeepSeek R1, or cut short with Ctrl-C) stays in the conversation, but is not sent to the model.
+// The same goes for reasoning and cache points, when the model we are calling does not take them
+// (ie a fallback model answering a conversation that started on another one).
+fn sendable_messages(messages: &[Message], options: &ChatOptions) -> Vec<Message> {
+    messages
+        .iter()
+        .map(|message| {
+            let content: Vec<ContentBlock> = message
+                .content()
+                .iter()
+                .filter(|block| match block {
+                    ContentBlock::ReasoningContent(ReasoningContentBlock::ReasoningText(r)) => {
+                        options.reasoning && r.signature().is_some()
+                    }
+                    ContentBlock::ReasoningContent(_) => options.reasoning,
+                    ContentBlock::CachePoint(_) => options.prompt_caching,
+                    _ => true,
+                })
+                .cloned()
+                .collect();
+            build_message(message.role().clone(), content).unwrap_or_else(|_| message.clone())
+        })
+        .collect()
+}
+
+// Tool use blocks arrive in pieces: the start event carries the id and the name, and the deltas
+// carry the input JSON as a string
+struct PendingToolUse {
+    tool_use_id: String,
+    name: String,
+    input: String,
+}
+
+impl PendingToolUse {
+    fn into_block(self) -> Result<ToolUseBlock, BedrockError> {
+        let input: Value = if self.input.trim().is_empty() {
+            Value::Object(Default::default())
+        } else {
+            serde_json::from_str(&self.input)
+                .map_err(|_| BedrockError::from("Unable to parse the tool input"))?
+        };
+        ToolUseBlock::builder()
+            .tool_use_id(self.tool_use_id)
+            .name(self.name)
+            .input(json_to_document(&input))
+            .build()
+            .map_err(|_| BedrockError::from("Unable to build the tool use block"))
     }
 }
 
-// === Main functions ===
+// Everything that came back from a single ConverseStream call
+#[derive(Default)]
+struct StreamStep {
+    text: String,
+    // reasoning blocks, they go in front of the text and the tool uses
+    reasoning: Vec<ContentBlock>,
+    tool_uses: Vec<ToolUseBlock>,
+    stop_reason: Option<StopReason>,
+    metadata: Option<ConverseStreamMetadataEvent>,
+    // stopped with Ctrl-C before the end of the stream
+    interrupted: bool,
+}
+
+// Receives the whole stream, printing the text as it comes in
+async fn receive_stream(
+    stream: &mut ConverseEventStream,
+    options: &ChatOptions,
+    interrupt: Option<&Interrupt>,
+) -> Result<StreamStep, BedrockError> {
+    let mut step = StreamStep::default();
+    let mut pending_tool: Option<PendingToolUse> = None;
+    let mut pending_reasoning = PendingReasoning::default();
+    // the model is thinking right now
+    let mut thinking = false;
+    let mut markdown = (options.echo && options.markdown).then(MarkdownRenderer::new);
 
-// Function to get the output text
-fn get_converse_output_text(
-    output: ConverseStreamOutputType,
-    is_reasoning: &mut bool,
-) -> Result<String, BedrockConverseStreamError> {
-    Ok(match output {
-        ConverseStreamOutputType::ContentBlockDelta(event) => match event.delta() {
-            Some(delta) => {
-                if delta.is_reasoning_content() {
-                    // CHECK FOR SWITCH
-                    let was_reasoning = *is_reasoning;
-                    if !was_reasoning { 
-                        *is_reasoning = true;
-                        match delta.as_reasoning_content() {
-                            Ok(rc) => format!("\n🤔 Thinking...\n{}", rc.as_text().cloned().unwrap_or_else(|_|"".to_string())),
-                            Err(_) => "\n🤔 Thinking...\n".into()
+    // the main printing loop
+    loop {
+        let event = match interrupt {
+            Some(interrupt) => tokio::select! {
+                biased;
+                _ = interrupt.requested() => {
+                    step.interrupted = true;
+                    break;
+                }
+                event = stream.recv() => event?,
+            },
+            None => stream.recv().await?,
+        };
+        let Some(event) = event else {
+            break;
+        };
+        match event {
+            ConverseStreamOutputType::ContentBlockStart(event) => {
+                if let Some(ContentBlockStart::ToolUse(start)) = event.start() {
+                    pending_tool = Some(PendingToolUse {
+                        tool_use_id: start.tool_use_id().to_string(),
+                        name: start.name().to_string(),
+                        input: String::new(),
+                    });
+                }
+            }
+            ConverseStreamOutputType::ContentBlockDelta(event) => match event.delta() {
+                Some(ContentBlockDelta::ToolUse(delta)) => {
+                    if let Some(pending) = pending_tool.as_mut() {
+                        pending.input.push_str(delta.input());
+                    }
+                }
+                Some(ContentBlockDelta::ReasoningContent(delta)) => {
+                    if !thinking {
+                        thinking = true;
+                        if options.echo && options.show_reasoning {
+                            print!("\n🤔 Thinking...\n");
+                        } else if options.echo {
+                            print!("\n🤔 Thinking... (type /t to show it)");
+                        }
+                    }
+                    match delta {
+                        ReasoningContentBlockDelta::Text(text) => {
+                            if options.echo && options.show_reasoning {
+                                print!("{}", text);
+                            }
+                            pending_reasoning.text.push_str(text);
+                        }
+                        ReasoningContentBlockDelta::Signature(signature) => {
+                            pending_reasoning.signature = Some(signature.clone());
+                        }
+                        ReasoningContentBlockDelta::RedactedContent(redacted) => {
+                            pending_reasoning
+                                .redacted
+                                .extend_from_slice(redacted.as_ref());
+                        }
+                        _ => {}
+                    }
+                }
+                Some(ContentBlockDelta::Text(text)) => {
+                    if thinking {
+                        thinking = false;
+                        if options.echo {
+                            print!("\n ✅ Thinking Done\n");
                         }
-                    } else {
-                        match delta.as_reasoning_content() {
-                            Ok(rc) => rc.as_text().cloned().unwrap_or_else(|_|"".to_string()),
-                            Err(_) => "".into()
+                    }
+                    if options.echo {
+                        match markdown.as_mut() {
+                            Some(markdown) => print!("{}", markdown.push(text)),
+                            None => print!("{}", text),
                         }
-                    } 
-                } else { 
-                    // END OF THINKING
-                    let was_reasoning = *is_reasoning;
-                    if was_reasoning {
-                        *is_reasoning = false;
-                        format!("\n ✅ Thinking Done\n{}", delta.as_text().cloned().unwrap_or_else(|_| "".into()))
-                    } else {
-                        delta.as_text().cloned().unwrap_or_else(|_| "".into())
+                        let _ = io::stdout().flush();
                     }
+                    step.text.push_str(text);
+                }
+                _ => {}
+            },
+            ConverseStreamOutputType::ContentBlockStop(_) => {
+                if let Some(pending) = pending_tool.take() {
+                    step.tool_uses.push(pending.into_block()?);
                 }
-            }, 
-            None => "".into(),
+                if let Some(reasoning) = std::mem::take(&mut pending_reasoning).into_block() {
+                    step.reasoning.push(reasoning);
+                }
+            }
+            ConverseStreamOutputType::MessageStop(event) => {
+                step.stop_reason = Some(event.stop_reason().clone());
+            }
+            // token usage and latency, the last event of the stream
+            ConverseStreamOutputType::Metadata(event) => {
+                step.metadata = Some(event);
+            }
+            _ => {}
+        }
+    }
+    // stopped in the middle of a block
+    step.reasoning.extend(pending_reasoning.into_block());
+    if let Some(markdown) = markdown.as_mut() {
+        print!("{}", markdown.finish());
+    }
+
+    Ok(step)
+}
+
+// NOTE: Models that can not stream get the whole answer in one go, from the Converse API. We turn
+// it into a `StreamStep`, so the rest of the turn (tools, usage, the guardrail) is the same.
+async fn converse_step(
+    backend: &dyn ModelBackend,
+    request: &ConverseRequest,
+    options: &ChatOptions,
+    interrupt: Option<&Interrupt>,
+) -> Result<StreamStep, BedrockError> {
+    let call = backend
+        .retry_policy()
+        .run("Converse", || backend.converse(request.clone()));
+    let output = match interrupt {
+        Some(interrupt) => tokio::select! {
+            biased;
+            _ = interrupt.requested() => None,
+            output = call => Some(output?),
         },
-        _ => "".into(),
-    })
+        None => Some(call.await?),
+    };
+    let Some(output) = output else {
+        return Ok(StreamStep {
+            interrupted: true,
+            ..Default::default()
+        });
+    };
+
+    let mut step = StreamStep {
+        stop_reason: Some(output.stop_reason().clone()),
+        metadata: Some(converse_metadata(&output)),
+        ..Default::default()
+    };
+    let message = output
+        .output()
+        .and_then(|o| o.as_message().ok())
+        .ok_or_else(|| {
+            BedrockError::new(
+                BedrockErrorKind::InvalidResponse,
+                "The model sent no message",
+            )
+        })?;
+    for block in message.content() {
+        match block {
+            ContentBlock::Text(text) => step.text.push_str(text),
+            ContentBlock::ReasoningContent(_) => step.reasoning.push(block.clone()),
+            ContentBlock::ToolUse(tool_use) => step.tool_uses.push(tool_use.clone()),
+            _ => {}
+        }
+    }
+
+    if options.echo {
+        let reasoning: String = step
+            .reasoning
+            .iter()
+            .filter_map(|block| block.as_reasoning_content().ok()?.as_reasoning_text().ok())
+            .map(|reasoning| reasoning.text())
+            .collect();
+        if !step.reasoning.is_empty() && options.show_reasoning {
+            print!("\n🤔 Thinking...\n{}", reasoning);
+            print!("\n ✅ Thinking Done\n");
+        } else if !step.reasoning.is_empty() {
+            print!("\n🤔 Thinking... (type /t to show it)");
+            print!("\n ✅ Thinking Done\n");
+        }
+        if options.markdown {
+            print!("{}", render_markdown(&step.text));
+        } else {
+            print!("{}", step.text);
+        }
+    }
+    Ok(step)
+}
+
+// Converse has the same usage, metrics and trace as the last event of a stream
+pub fn converse_metadata(output: &ConverseOutput) -> ConverseStreamMetadataEvent {
+    let metrics = output.metrics().and_then(|m| {
+        ConverseStreamMetrics::builder()
+            .latency_ms(m.latency_ms())
+            .build()
+            .ok()
+    });
+    let trace = output.trace().map(|t| {
+        ConverseStreamTrace::builder()
+            .set_guardrail(t.guardrail().cloned())
+            .build()
+    });
+    ConverseStreamMetadataEvent::builder()
+        .set_usage(output.usage().cloned())
+        .set_metrics(metrics)
+        .set_trace(trace)
+        .build()
+}
+
+fn build_message(
+    role: ConversationRole,
+    content: Vec<ContentBlock>,
+) -> Result<Message, BedrockError> {
+    Message::builder()
+        .role(role)
+        .set_content(Some(content))
+        .build()
+        .map_err(|_| BedrockError::from("Failed to build message"))
 }
 
 pub async fn call_converse_stream(
-    bc: &aws_sdk_bedrockruntime::Client,
+    backend: &dyn ModelBackend,
     model_id: String,
     conversation_history: &ConversationHistory,
-    inference_parameters: InferenceConfiguration,
-    system_prompt: &str,
-) -> Result<Conversation, BedrockConverseStreamError> {
-    let msg: Vec<Message> = conversation_history
+    options: &ChatOptions,
+    system: &[SystemContentBlock],
+    tools: Option<&ToolRegistry>,
+    interrupt: Option<&Interrupt>,
+) -> Result<Conversation, BedrockError> {
+    // while we are in here, Ctrl-C stops the answer instead of exiting
+    let _streaming = interrupt.map(Interrupt::streaming);
+
+    let mut msg: Vec<Message> = conversation_history
         .messages
         .clone()
-        .unwrap()
+        .unwrap_or_default()
         .into_iter()
         .map(Message::from)
         .collect();
 
-    let response = bc
-        .converse_stream()
-        .model_id(model_id)
-        // FIX: See if I can avoid this clone
-        .system(SystemContentBlock::Text(system_prompt.to_string()))
-        .set_messages(Some(msg))
-        .inference_config(inference_parameters)
-        .send()
-        .await;
-
-    let mut stream = match response {
-        Ok(output) => Ok(output.stream),
-        Err(e) => Err(BedrockConverseStreamError::from(
-            e.as_service_error().unwrap(),
-        )),
-    }?;
+    let history_len = msg.len();
+
+    let tool_config = tools.and_then(|t| t.tool_configuration());
 
     // A string that response the message back
     let mut output = String::new();
 
-    let mut is_reasoning = false;
-
     // return the conversation
     let mut convo = Conversation::new(ConversationEntity::Assistant, String::new());
+    let mut usage = TurnRecord::new(model_id.as_str());
+    let mut guardrail = GuardrailTraceRecord::default();
 
-    // the main printing loop
-    loop {
-        let token = stream.recv().await;
-        match token {
-            Ok(Some(text)) => {
-                let next = get_converse_output_text(text, &mut is_reasoning)?;
-                print!("{}", next);
-                output.push_str(&next);
-                Ok(())
+    // The model can ask for tools a few times before it gives us the final answer. Each time we
+    // run the tools, send the results back, and keep on streaming.
+    for round in 0..=MAX_TOOL_ROUNDS {
+        let compacted = compacted_messages(&msg, conversation_history.compaction.as_ref());
+        let mut messages = sendable_messages(&compacted, options);
+        if let Some(guard) = &options.context {
+            let max_output = options.inference_config.max_tokens().unwrap_or(0).max(0) as usize;
+            let fitted = guard
+                .fit(messages, system, max_output)
+                .map_err(|e| e.with_model(&model_id))?;
+            if fitted.dropped > 0 && round == 0 && options.echo {
+                println!(
+                    "✂️  | Left out the {} oldest messages to fit the context window",
+                    fitted.dropped
+                );
+            }
+            messages = fitted.messages;
+        }
+        let request = ConverseRequest::new(model_id.clone(), messages)
+            .system(Some(system.to_vec()))
+            .inference_config(options.inference_config.clone())
+            .tool_config(tool_config.clone())
+            .additional_fields(options.additional_fields.clone());
+
+        let step = if options.streaming {
+            // NOTE: Only starting the stream is retried, once the text is printed there is no
+            // going back
+            let mut stream = backend
+                .retry_policy()
+                .run("ConverseStream", || {
+                    backend.converse_stream(request.clone())
+                })
+                .await?;
+            receive_stream(&mut stream, options, interrupt).await
+        } else {
+            converse_step(backend, &request, options, interrupt).await
+        }
+        .map_err(|e| e.with_model(&model_id))?;
+        output.push_str(&step.text);
+        if let Some(metadata) = &step.metadata {
+            usage.add_metadata(metadata);
+            if let Some(trace) = metadata.trace().and_then(|t| t.guardrail()) {
+                guardrail.add_trace(trace);
+            }
+        }
+        if step.stop_reason == Some(StopReason::GuardrailIntervened) {
+            guardrail.intervened = true;
+        }
+
+        let tools = match (tools, &step.stop_reason) {
+            (Some(tools), Some(StopReason::ToolUse))
+                if !step.tool_uses.is_empty() && !step.interrupted =>
+            {
+                tools
             }
-            Ok(None) => {
+            _ => {
+                // NOTE: Bedrock does not accept empty text blocks
+                let mut answer = if step.text.trim().is_empty() {
+                    "(no answer)".to_string()
+                } else {
+                    step.text
+                };
+                if step.interrupted && options.echo {
+                    println!();
+                    println!("⏹️  | Stopped, the answer so far is kept in the conversation");
+                }
+                if step.interrupted {
+                    answer = format!("{}\n{}", answer.trim_end(), INTERRUPTED_MARKER);
+                    output.push_str(&format!("\n{}", INTERRUPTED_MARKER));
+                }
+                let mut content = step.reasoning;
+                content.push(ContentBlock::Text(answer));
+                msg.push(build_message(ConversationRole::Assistant, content)?);
                 convo.content.push_str(&output);
-                break;
+                convo.messages = msg
+                    .split_off(history_len)
+                    .into_iter()
+                    .map(SerializableMessage::from)
+                    .collect();
+                if options.echo {
+                    println!();
+                }
+                if !guardrail.is_empty() {
+                    if options.echo {
+                        print_guardrail_trace(&guardrail);
+                    }
+                    usage.guardrail = Some(guardrail);
+                }
+                convo.usage = Some(usage);
+                return Ok(convo);
             }
-            Err(e) => Err(e
-                .as_service_error()
-                .map(BedrockConverseStreamError::from)
-                .unwrap_or(BedrockConverseStreamError(
-                    "Unknown error recieving stream".into(),
-                ))),
-        }?
-    }
+        };
 
-    println!();
+        // the assistant message asking for the tools
+        let mut assistant_content = step.reasoning;
+        if !step.text.trim().is_empty() {
+            assistant_content.push(ContentBlock::Text(step.text));
+        }
+        let mut results = Vec::new();
+        for tool_use in step.tool_uses {
+            if options.echo {
+                println!();
+                println!(
+                    "🔧 | Using tool: {} {}",
+                    tool_use.name(),
+                    document_to_json(tool_use.input())
+                );
+            }
+            let result = tools.run(&tool_use);
+            if options.echo && result.status() == Some(&ToolResultStatus::Error) {
+                println!("🔴 | The tool `{}` failed", tool_use.name());
+            }
+            results.push(ContentBlock::ToolResult(result));
+            assistant_content.push(ContentBlock::ToolUse(tool_use));
+        }
+        msg.push(build_message(
+            ConversationRole::Assistant,
+            assistant_content,
+        )?);
+        msg.push(build_message(ConversationRole::User, results)?);
+    }
 
-    Ok(convo)
+    Err(BedrockError::from(
+        "The model kept asking for tools without giving an answer",
+    ))
 }
@@ -0,0 +1,150 @@
+use anyhow::{anyhow, bail};
+use serde_json::{json, Value};
+
+use crate::models::backend::ModelBackend;
+use crate::models::base_model_id;
+
+// NOTE: Text embeddings through InvokeModel. Titan and Cohere take (and answer with) different
+// JSON, so this is where that difference is hidden. Every vector that comes out of here is
+// normalized, so comparing two of them is just a dot product.
+
+// How many texts Cohere takes in one call, Titan only takes one
+const COHERE_BATCH_SIZE: usize = 96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EmbeddingFamily {
+    Titan,
+    Cohere,
+}
+
+// What the texts are used for. Cohere embeds the documents and the queries differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingPurpose {
+    Document,
+    Query,
+}
+
+fn family(model_id: &str) -> Result<EmbeddingFamily, anyhow::Error> {
+    let model_id = base_model_id(model_id);
+    if model_id.starts_with("amazon.titan-embed-text") {
+        Ok(EmbeddingFamily::Titan)
+    } else if model_id.starts_with("cohere.embed") {
+        Ok(EmbeddingFamily::Cohere)
+    } else {
+        bail!(
+            "`{}` is not a text embedding model, use Amazon Titan Text Embeddings or Cohere Embed",
+            model_id
+        )
+    }
+}
+
+fn request_body(family: EmbeddingFamily, texts: &[String], purpose: EmbeddingPurpose) -> Value {
+    match family {
+        EmbeddingFamily::Titan => json!({ "inputText": texts[0] }),
+        EmbeddingFamily::Cohere => json!({
+            "texts": texts,
+            "input_type": match purpose {
+                EmbeddingPurpose::Document => "search_document",
+                EmbeddingPurpose::Query => "search_query",
+            },
+            "truncate": "END",
+        }),
+    }
+}
+
+// Titan: `{"embedding": [..]}`, Cohere: `{"embeddings": [[..]]}` or `{"embeddings": {"float": [[..]]}}`
+fn parse_vectors(family: EmbeddingFamily, body: &[u8]) -> Result<Vec<Vec<f32>>, anyhow::Error> {
+    let response: Value = serde_json::from_slice(body)?;
+    let vectors = match family {
+        EmbeddingFamily::Titan => Value::Array(vec![response["embedding"].clone()]),
+        EmbeddingFamily::Cohere => match &response["embeddings"] {
+            Value::Object(types) => types["float"].clone(),
+            embeddings => embeddings.clone(),
+        },
+    };
+    let vectors: Vec<Vec<f32>> = serde_json::from_value(vectors)
+        .map_err(|e| anyhow!("Unable to read the embeddings: {}", e))?;
+    if vectors.iter().any(Vec::is_empty) {
+        bail!("The model sent an empty embedding");
+    }
+    Ok(vectors.into_iter().map(normalize).collect())
+}
+
+fn normalize(vector: Vec<f32>) -> Vec<f32> {
+    let length = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if length == 0.0 {
+        return vector;
+    }
+    vector.into_iter().map(|x| x / length).collect()
+}
+
+// How alike two normalized vectors are, 1.0 being the same
+pub fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// One vector per text, in the same order
+pub async fn embed(
+    backend: &dyn ModelBackend,
+    model_id: &str,
+    texts: &[String],
+    purpose: EmbeddingPurpose,
+) -> Result<Vec<Vec<f32>>, anyhow::Error> {
+    let family = family(model_id)?;
+    let batch_size = match family {
+        EmbeddingFamily::Titan => 1,
+        EmbeddingFamily::Cohere => COHERE_BATCH_SIZE,
+    };
+    let mut vectors = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(batch_size) {
+        let body = request_body(family, batch, purpose)
+            .to_string()
+            .into_bytes();
+        let output = backend
+            .retry_policy()
+            .run("Embedding", || backend.invoke_model(model_id, body.clone()))
+            .await?;
+        let batch_vectors = parse_vectors(family, &output)?;
+        if batch_vectors.len() != batch.len() {
+            bail!(
+                "Asked for {} embeddings, the model sent {}",
+                batch.len(),
+                batch_vectors.len()
+            );
+        }
+        vectors.extend(batch_vectors);
+    }
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn titan_and_cohere_embeddings() {
+        let texts = vec!["IAM policies".to_string(), "Rust lifetimes".to_string()];
+        assert_eq!(
+            family("us.cohere.embed-english-v3").unwrap(),
+            EmbeddingFamily::Cohere
+        );
+        assert!(family("amazon.nova-pro-v1:0").is_err());
+        assert_eq!(
+            request_body(EmbeddingFamily::Titan, &texts[..1], EmbeddingPurpose::Query),
+            json!({ "inputText": "IAM policies" })
+        );
+        assert_eq!(
+            request_body(EmbeddingFamily::Cohere, &texts, EmbeddingPurpose::Query)["input_type"],
+            "search_query"
+        );
+
+        let titan = parse_vectors(EmbeddingFamily::Titan, br#"{"embedding": [3.0, 4.0]}"#);
+        assert_eq!(titan.unwrap(), [[0.6, 0.8]]);
+        let cohere = br#"{"embeddings": {"float": [[1.0, 0.0], [0.0, 2.0]]}}"#;
+        let cohere = parse_vectors(EmbeddingFamily::Cohere, cohere).unwrap();
+        assert_eq!(cohere, [[1.0, 0.0], [0.0, 1.0]]);
+        assert_eq!(similarity(&cohere[0], &cohere[1]), 0.0);
+        assert!(parse_vectors(EmbeddingFamily::Titan, br#"{"embedding": []}"#).is_err());
+        assert!(parse_vectors(EmbeddingFamily::Cohere, br#"{"message": "nope"}"#).is_err());
+    }
+}
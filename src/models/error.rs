@@ -0,0 +1,334 @@
+use std::fmt::Display;
+
+use aws_sdk_bedrockruntime::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_bedrockruntime::operation::RequestId;
+
+use crate::models::retry::{ClassifyError, ErrorClass};
+
+// NOTE: The error of every Converse and ConverseStream call. We used to turn the service errors
+// into a short string ("Unknown" for most of them), now we keep what Bedrock told us: what kind
+// of error it was, the message, the request id (handy when talking to AWS support) and the model
+// we were calling. On top of that, the common mistakes get a hint on how to fix them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BedrockErrorKind {
+    // No access to the model, or missing IAM permissions
+    AccessDenied,
+    // The AWS credentials are invalid or have expired
+    Credentials,
+    // The model (or profile) does not exist, at least not in this region
+    ResourceNotFound,
+    Throttling,
+    ServiceQuotaExceeded,
+    ModelTimeout,
+    ModelNotReady,
+    ServiceUnavailable,
+    InternalServer,
+    Validation,
+    // The model itself failed while answering
+    ModelError,
+    ModelStreamError,
+    // The request never got an answer (client side timeout)
+    Timeout,
+    // Unable to reach Bedrock at all
+    Dispatch,
+    // We got an answer, but not one we can use
+    InvalidResponse,
+    // The guardrail blocked the question or the answer
+    GuardrailIntervened,
+    // The conversation does not fit in the context window of the model
+    ContextWindow,
+    Other,
+}
+
+impl BedrockErrorKind {
+    // Service errors carry the name of the exception as their code
+    pub fn from_code(code: Option<&str>) -> Self {
+        match code.unwrap_or_default() {
+            "AccessDeniedException" => BedrockErrorKind::AccessDenied,
+            "UnrecognizedClientException"
+            | "ExpiredTokenException"
+            | "InvalidSignatureException" => BedrockErrorKind::Credentials,
+            "ResourceNotFoundException" => BedrockErrorKind::ResourceNotFound,
+            "ThrottlingException" => BedrockErrorKind::Throttling,
+            "ServiceQuotaExceededException" => BedrockErrorKind::ServiceQuotaExceeded,
+            "ModelTimeoutException" => BedrockErrorKind::ModelTimeout,
+            "ModelNotReadyException" => BedrockErrorKind::ModelNotReady,
+            "ServiceUnavailableException" => BedrockErrorKind::ServiceUnavailable,
+            "InternalServerException" => BedrockErrorKind::InternalServer,
+            "ValidationException" => BedrockErrorKind::Validation,
+            "ModelErrorException" => BedrockErrorKind::ModelError,
+            "ModelStreamErrorException" => BedrockErrorKind::ModelStreamError,
+            _ => BedrockErrorKind::Other,
+        }
+    }
+
+    // Used when Bedrock did not send a message along
+    fn description(&self) -> &'static str {
+        match self {
+            BedrockErrorKind::AccessDenied => "Access denied",
+            BedrockErrorKind::Credentials => "Invalid AWS credentials",
+            BedrockErrorKind::ResourceNotFound => "Model not found",
+            BedrockErrorKind::Throttling => "Your request was throttled",
+            BedrockErrorKind::ServiceQuotaExceeded => "Service quota exceeded",
+            BedrockErrorKind::ModelTimeout => "Model took too long",
+            BedrockErrorKind::ModelNotReady => "Model is not ready",
+            BedrockErrorKind::ServiceUnavailable => "Amazon Bedrock is unavailable",
+            BedrockErrorKind::InternalServer => "Amazon Bedrock had an internal error",
+            BedrockErrorKind::Validation => "Invalid request",
+            BedrockErrorKind::ModelError => "The model failed to answer",
+            BedrockErrorKind::ModelStreamError => "The model failed while streaming",
+            BedrockErrorKind::Timeout => "The request timed out",
+            BedrockErrorKind::Dispatch => "Unable to reach Amazon Bedrock",
+            BedrockErrorKind::InvalidResponse => "Invalid response",
+            BedrockErrorKind::GuardrailIntervened => "Blocked by the guardrail",
+            BedrockErrorKind::ContextWindow => "The conversation does not fit the context window",
+            BedrockErrorKind::Other => "Unknown error",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BedrockError {
+    pub kind: BedrockErrorKind,
+    pub message: String,
+    pub request_id: Option<String>,
+    pub model_id: Option<String>,
+}
+
+impl BedrockError {
+    pub fn new(kind: BedrockErrorKind, message: impl Into<String>) -> Self {
+        BedrockError {
+            kind,
+            message: message.into(),
+            request_id: None,
+            model_id: None,
+        }
+    }
+
+    // Keeps the model id we already have, errors can pass through a few layers
+    pub fn with_model(mut self, model_id: &str) -> Self {
+        if self.model_id.is_none() {
+            self.model_id = Some(model_id.to_string());
+        }
+        self
+    }
+
+    pub fn from_service_error<E: ProvideErrorMetadata>(error: &E) -> Self {
+        let kind = BedrockErrorKind::from_code(error.code());
+        BedrockError {
+            kind,
+            message: error.message().unwrap_or(kind.description()).to_string(),
+            request_id: error.meta().request_id().map(|id| id.to_string()),
+            model_id: None,
+        }
+    }
+
+    pub fn from_sdk_error<E: ProvideErrorMetadata, R>(error: &SdkError<E, R>) -> Self {
+        match error {
+            SdkError::ServiceError(e) => BedrockError::from_service_error(e.err()),
+            SdkError::TimeoutError(_) => BedrockError::new(
+                BedrockErrorKind::Timeout,
+                BedrockErrorKind::Timeout.description(),
+            ),
+            SdkError::DispatchFailure(e) => BedrockError::new(
+                BedrockErrorKind::Dispatch,
+                format!("{}: {:?}", BedrockErrorKind::Dispatch.description(), e),
+            ),
+            SdkError::ResponseError(_) => BedrockError::new(
+                BedrockErrorKind::InvalidResponse,
+                "Unable to read the response from Amazon Bedrock",
+            ),
+            _ => BedrockError::new(BedrockErrorKind::Other, "Unable to build the request"),
+        }
+    }
+
+    // The model can not answer right now, but another one might
+    pub fn calls_for_fallback(&self) -> bool {
+        matches!(
+            self.kind,
+            BedrockErrorKind::Throttling
+                | BedrockErrorKind::ServiceQuotaExceeded
+                | BedrockErrorKind::ModelNotReady
+                | BedrockErrorKind::ServiceUnavailable
+        )
+    }
+
+    // Something the user can do about it
+    pub fn hint(&self) -> Option<String> {
+        let message = self.message.to_lowercase();
+        let model = self.model_id.as_deref().unwrap_or("this model");
+        match self.kind {
+            BedrockErrorKind::AccessDenied => Some(format!(
+                "Model access not granted. Request access to `{}` in the Amazon Bedrock console (Model access) for this region, and check that your IAM role allows `bedrock:InvokeModel*`",
+                model
+            )),
+            BedrockErrorKind::Credentials => Some(
+                "Your AWS credentials are invalid or have expired, refresh them (ie `aws sso login`) and try again".into(),
+            ),
+            BedrockErrorKind::ResourceNotFound => Some(format!(
+                "The region does not host `{}`. Try another region, or a cross region inference profile (`us.`, `eu.`, `apac.` ...)",
+                model
+            )),
+            BedrockErrorKind::Validation if message.contains("on-demand throughput") => Some(format!(
+                "`{}` can not be called directly in this region, use an inference profile instead (ie `us.{}`)",
+                model, model
+            )),
+            BedrockErrorKind::Validation if message.contains("model identifier is invalid") => {
+                Some(format!(
+                    "The region does not host `{}`, or the model id is misspelled. Run with `--refresh-models` to see what is available",
+                    model
+                ))
+            }
+            BedrockErrorKind::Validation
+                if ["too long", "too many tokens", "context length", "context window"]
+                    .iter()
+                    .any(|m| message.contains(m)) =>
+            {
+                Some(format!(
+                    "The conversation is too long for `{}`. Clear it with /c, or set its context window in `context_windows` so Bedrust leaves the oldest messages out",
+                    model
+                ))
+            }
+            BedrockErrorKind::ContextWindow if message.contains("the question alone") => Some(
+                "Ask a shorter question (or attach fewer documents), or pick a model with a larger context window".into(),
+            ),
+            BedrockErrorKind::ContextWindow => Some(
+                "Clear the conversation with /c, or set `context_policy: DropOldest` in bedrust_config.ron to leave the oldest messages out".into(),
+            ),
+            BedrockErrorKind::Throttling | BedrockErrorKind::ServiceQuotaExceeded => Some(
+                "You are sending too many requests or tokens, check the Amazon Bedrock service quotas for this model".into(),
+            ),
+            BedrockErrorKind::GuardrailIntervened => Some(
+                "The guardrail configured for this AWS profile blocked the request, set `trace: true` on it to see why".into(),
+            ),
+            BedrockErrorKind::ModelNotReady => {
+                Some("The model is still being loaded, try again in a moment".into())
+            }
+            BedrockErrorKind::Dispatch => {
+                Some("Check your network connection and the configured AWS region".into())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Display for BedrockError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.model_id {
+            Some(model_id) => write!(f, "Can't invoke '{}'. Reason: {}", model_id, self.message)?,
+            None => write!(f, "Can't invoke. Reason: {}", self.message)?,
+        }
+        if let Some(request_id) = &self.request_id {
+            write!(f, " (request id: {})", request_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BedrockError {}
+
+impl ClassifyError for BedrockError {
+    fn error_class(&self) -> ErrorClass {
+        match self.kind {
+            BedrockErrorKind::Throttling => ErrorClass::Throttling,
+            BedrockErrorKind::ModelTimeout | BedrockErrorKind::Timeout => ErrorClass::Timeout,
+            BedrockErrorKind::ModelNotReady => ErrorClass::NotReady,
+            BedrockErrorKind::ServiceUnavailable
+            | BedrockErrorKind::InternalServer
+            | BedrockErrorKind::Dispatch => ErrorClass::Unavailable,
+            BedrockErrorKind::InvalidResponse => ErrorClass::InvalidResponse,
+            BedrockErrorKind::Validation => ErrorClass::Validation,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+impl From<&str> for BedrockError {
+    fn from(value: &str) -> Self {
+        BedrockError::new(BedrockErrorKind::Other, value)
+    }
+}
+
+// Prints the hint for an error, if we have one
+pub fn print_error_hint(error: &BedrockError) {
+    if let Some(hint) = error.hint() {
+        println!("💡 | {}", hint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_bedrockruntime::error::ErrorMetadata;
+    use aws_sdk_bedrockruntime::operation::converse::ConverseError;
+    use aws_sdk_bedrockruntime::types::error::AccessDeniedException;
+
+    #[test]
+    fn service_errors_keep_their_details() {
+        let error = ConverseError::AccessDeniedException(
+            AccessDeniedException::builder()
+                .message("You don't have access to the model with the specified model ID.")
+                .meta(
+                    ErrorMetadata::builder()
+                        .code("AccessDeniedException")
+                        .message("You don't have access to the model with the specified model ID.")
+                        .custom("aws_request_id", "abc-123")
+                        .build(),
+                )
+                .build(),
+        );
+        let error = BedrockError::from_service_error(&error).with_model("amazon.nova-pro-v1:0");
+        assert_eq!(error.kind, BedrockErrorKind::AccessDenied);
+        assert_eq!(error.request_id.as_deref(), Some("abc-123"));
+        assert_eq!(
+            error.to_string(),
+            "Can't invoke 'amazon.nova-pro-v1:0'. Reason: You don't have access to the model with the specified model ID. (request id: abc-123)"
+        );
+        assert!(error
+            .hint()
+            .unwrap()
+            .starts_with("Model access not granted"));
+        assert_eq!(error.error_class(), ErrorClass::Other);
+
+        // errors the SDK does not know about are still sorted by their code
+        let unhandled = ConverseError::generic(
+            ErrorMetadata::builder()
+                .code("ResourceNotFoundException")
+                .build(),
+        );
+        let error = BedrockError::from_service_error(&unhandled);
+        assert_eq!(error.kind, BedrockErrorKind::ResourceNotFound);
+        assert_eq!(error.message, "Model not found");
+        assert!(error.hint().unwrap().contains("region does not host"));
+    }
+
+    #[test]
+    fn hints_and_classes() {
+        let error = BedrockError::new(
+            BedrockErrorKind::Validation,
+            "Invocation of model ID anthropic.claude-3-7-sonnet-20250219-v1:0 with on-demand throughput isn't supported.",
+        )
+        .with_model("anthropic.claude-3-7-sonnet-20250219-v1:0")
+        .with_model("ignored");
+        assert!(error
+            .hint()
+            .unwrap()
+            .contains("`us.anthropic.claude-3-7-sonnet-20250219-v1:0`"));
+        assert_eq!(error.error_class(), ErrorClass::Validation);
+
+        let error = BedrockError::new(BedrockErrorKind::Validation, "Malformed input request");
+        assert_eq!(error.hint(), None);
+
+        assert!(BedrockError::new(BedrockErrorKind::Throttling, "slow down")
+            .error_class()
+            .is_retryable());
+        assert!(BedrockError::new(BedrockErrorKind::Dispatch, "offline")
+            .error_class()
+            .is_retryable());
+        assert_eq!(
+            BedrockError::from("oops").to_string(),
+            "Can't invoke. Reason: oops"
+        );
+    }
+}
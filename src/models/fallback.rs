@@ -0,0 +1,296 @@
+use std::io::{self, IsTerminal};
+
+use aws_sdk_bedrockruntime::types::SystemContentBlock;
+
+use crate::chat::{Conversation, ConversationHistory, SerializableContentBlock};
+use crate::interrupt::Interrupt;
+use crate::models::backend::ModelBackend;
+use crate::models::catalog::{context_window, resolve_model_id};
+use crate::models::context::ContextGuard;
+use crate::models::converse_stream::{call_converse_stream, system_blocks, ChatOptions};
+use crate::models::error::BedrockError;
+use crate::models::params::ModelParams;
+use crate::models::reasoning::with_reasoning;
+use crate::models::resolver::resolve_foundation_model;
+use crate::models::{
+    check_for_streaming, check_model_features, supports_documents, supports_prompt_caching,
+    supports_reasoning_budget, supports_tool_use, ModelFeatures,
+};
+use crate::tools::ToolRegistry;
+use crate::utils::{print_warning, BedrustConfig};
+
+// NOTE: Fallback models. When the model is throttled, out of quota or unavailable (and retrying
+// did not help), the question is asked again to the next model in `fallback_models` for the AWS
+// profile. Each model gets its own parameters, and models that can not continue the conversation
+// (no tools / images / documents while the conversation has them) are skipped.
+
+// Everything we need to chat with one model
+#[derive(Debug, Clone)]
+pub struct ChatModel {
+    pub model_id: String,
+    // the model behind an inference profile or ARN
+    pub foundation_model: String,
+    pub tools: bool,
+    pub prompt_caching: bool,
+    // the reasoning budget, when the model takes one
+    pub reasoning: Option<i32>,
+    pub images: bool,
+    pub documents: bool,
+    pub system: Vec<SystemContentBlock>,
+    pub options: ChatOptions,
+}
+
+impl ChatModel {
+    pub async fn new(
+        model_id: &str,
+        backend: &dyn ModelBackend,
+        config: &BedrustConfig,
+        system_prompt: &str,
+        reasoning_budget: Option<i32>,
+    ) -> ChatModel {
+        // features depend on the model behind the inference profile / ARN
+        let foundation_model = resolve_foundation_model(model_id, backend)
+            .await
+            .unwrap_or_else(|_| model_id.to_string());
+        let tools = config.enable_tools && supports_tool_use(&foundation_model);
+        let prompt_caching = config.prompt_caching && supports_prompt_caching(&foundation_model);
+        let documents = supports_documents(&foundation_model);
+        let reasoning = reasoning_budget.filter(|_| supports_reasoning_budget(&foundation_model));
+        // checked once per model, the models that can not stream get their answers with Converse
+        let streaming = check_for_streaming(model_id.to_string(), backend)
+            .await
+            .unwrap_or(true);
+
+        // the defaults, with what is configured for this model (or its provider) on top
+        let params = ModelParams::for_model(
+            &config.inference_params,
+            &config.model_params,
+            &foundation_model,
+        );
+        let params = match reasoning {
+            Some(budget) => with_reasoning(params, budget),
+            None => params,
+        };
+        let options = ChatOptions::new(params.inference_config())
+            .additional_fields(params.additional_fields())
+            .show_reasoning(config.show_reasoning)
+            .prompt_caching(prompt_caching)
+            .reasoning(supports_reasoning_budget(&foundation_model))
+            .markdown(config.render_markdown && io::stdout().is_terminal())
+            .streaming(streaming)
+            .context(ContextGuard::new(
+                context_window(&foundation_model, &config.context_windows),
+                config.context_policy,
+            ));
+
+        ChatModel {
+            model_id: model_id.to_string(),
+            foundation_model,
+            tools,
+            prompt_caching,
+            reasoning,
+            images: true,
+            documents,
+            system: system_blocks(system_prompt, prompt_caching),
+            options,
+        }
+    }
+
+    // Tool use, images and documents in the conversation can only be sent to the models that
+    // support them
+    fn can_continue(&self, history: &ConversationHistory) -> bool {
+        history
+            .messages
+            .iter()
+            .flatten()
+            .flat_map(|m| &m.content)
+            .all(|block| match block {
+                SerializableContentBlock::ToolUse { .. }
+                | SerializableContentBlock::ToolResult { .. } => self.tools,
+                SerializableContentBlock::Image { .. } => self.images,
+                SerializableContentBlock::Document { .. } => self.documents,
+                _ => true,
+            })
+    }
+}
+
+// The fallback models for the AWS profile, ready to chat. The ones we can not find are skipped.
+pub async fn fallback_models(
+    model_id: &str,
+    backend: &dyn ModelBackend,
+    config: &BedrustConfig,
+    system_prompt: &str,
+    reasoning_budget: Option<i32>,
+) -> Vec<ChatModel> {
+    let Some(fallbacks) = config.fallback_models.get(&config.aws_profile) else {
+        return Vec::new();
+    };
+    let mut models: Vec<ChatModel> = Vec::new();
+    for fallback in fallbacks {
+        let fallback = resolve_model_id(fallback, &config.model_aliases);
+        if fallback == model_id || models.iter().any(|m| m.model_id == fallback) {
+            continue;
+        }
+        let images = match check_model_features(&fallback, backend, ModelFeatures::Images).await {
+            Ok(images) => images,
+            Err(e) => {
+                print_warning(&format!(
+                    "Unable to use the fallback model `{}`: {}",
+                    fallback, e
+                ));
+                continue;
+            }
+        };
+        let mut model =
+            ChatModel::new(&fallback, backend, config, system_prompt, reasoning_budget).await;
+        model.images = images;
+        models.push(model);
+    }
+    models
+}
+
+// Asks the first model, and the next one whenever a model can not answer right now. The error of
+// the last model we tried is what we return when none of them could.
+pub async fn call_with_fallback(
+    backend: &dyn ModelBackend,
+    models: &[ChatModel],
+    conversation_history: &ConversationHistory,
+    tools: Option<&ToolRegistry>,
+    interrupt: Option<&Interrupt>,
+) -> Result<Conversation, BedrockError> {
+    let mut last_error = BedrockError::from("There is no model to ask");
+    for (i, model) in models.iter().enumerate() {
+        if i > 0 {
+            if !model.can_continue(conversation_history) {
+                println!(
+                    "🪂 | Skipping {}, it can not continue this conversation",
+                    model.model_id
+                );
+                continue;
+            }
+            println!("🪂 | Falling back to: {}", model.model_id);
+        }
+        let result = call_converse_stream(
+            backend,
+            model.model_id.clone(),
+            conversation_history,
+            &model.options,
+            &model.system,
+            tools.filter(|_| model.tools),
+            interrupt,
+        )
+        .await
+        .map_err(|e| e.with_model(&model.model_id));
+        match result {
+            Ok(convo) => {
+                if i > 0 {
+                    println!("☎️  | Answered by: {}", model.model_id);
+                }
+                return Ok(convo);
+            }
+            Err(e) if e.calls_for_fallback() && i + 1 < models.len() => {
+                println!();
+                eprintln!("🔴 | {}", e);
+                last_error = e;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::SerializableMessage;
+    use crate::constants;
+    use crate::models::error::BedrockErrorKind;
+    use crate::models::retry::RetryPolicy;
+    use crate::models::scripted::{ScriptedBackend, ScriptedTurn};
+    use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, Message};
+
+    fn history(question: &str) -> ConversationHistory {
+        let message = Message::builder()
+            .role(ConversationRole::User)
+            .content(ContentBlock::Text(question.to_string()))
+            .build()
+            .unwrap();
+        ConversationHistory::new(
+            None,
+            None,
+            None,
+            Some(vec![SerializableMessage::from(message)]),
+        )
+    }
+
+    #[tokio::test]
+    async fn throttled_models_fall_back_to_the_next_one() {
+        let mut config: BedrustConfig = ron::from_str(constants::BEDRUST_CONFIG_FILE).unwrap();
+        config.fallback_models.insert(
+            config.aws_profile.clone(),
+            vec![
+                "us.amazon.nova-pro-v1:0".into(),
+                "meta.llama3-8b-instruct-v1:0".into(),
+                "nova-lite".into(),
+            ],
+        );
+        let policy = RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        };
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Throttled,
+            ScriptedTurn::Text("From Nova Lite".into()),
+            ScriptedTurn::Error("Invalid request".into()),
+        ])
+        .with_retry_policy(policy);
+
+        let mut models = vec![
+            ChatModel::new(
+                "us.amazon.nova-pro-v1:0",
+                &backend,
+                &config,
+                "You are a test",
+                None,
+            )
+            .await,
+        ];
+        models.extend(fallback_models(&models[0].model_id, &backend, &config, "", None).await);
+        let ids: Vec<&str> = models.iter().map(|m| m.model_id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "us.amazon.nova-pro-v1:0",
+                "meta.llama3-8b-instruct-v1:0",
+                "us.amazon.nova-lite-v1:0"
+            ]
+        );
+
+        // llama 3 can not continue a conversation with tool use in it, so it is skipped
+        let mut history = history("What time is it?");
+        history.messages.as_mut().unwrap()[0]
+            .content
+            .push(SerializableContentBlock::ToolUse {
+                tool_use_id: "1".into(),
+                name: "current_time".into(),
+                input: serde_json::json!({}),
+            });
+        let convo = call_with_fallback(&backend, &models, &history, None, None)
+            .await
+            .unwrap();
+        assert_eq!(convo.content, "From Nova Lite");
+        assert_eq!(convo.usage.unwrap().model_id, "us.amazon.nova-lite-v1:0");
+        let requested: Vec<String> = backend.requests().into_iter().map(|r| r.model_id).collect();
+        assert_eq!(
+            requested,
+            ["us.amazon.nova-pro-v1:0", "us.amazon.nova-lite-v1:0"]
+        );
+
+        // other errors are not for the next model to fix
+        let err = call_with_fallback(&backend, &models, &history, None, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind, BedrockErrorKind::Other);
+        assert_eq!(backend.remaining(), 0);
+    }
+}
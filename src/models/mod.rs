@@ -1,27 +1,93 @@
+pub mod backend;
+pub mod catalog;
+pub mod context;
 pub mod converse;
 pub mod converse_stream;
+pub mod document;
+pub mod embeddings;
+pub mod error;
+pub mod fallback;
+pub mod params;
+pub mod reasoning;
+pub mod resolver;
+pub mod retry;
+pub mod scripted;
 
-use anyhow::{anyhow, Result};
-use aws_sdk_bedrock::{
-    self,
-    types::{FoundationModelDetails, ModelModality},
-};
+use anyhow::Result;
+use aws_sdk_bedrock::types::{FoundationModelDetails, ModelModality};
+use aws_sdk_bedrockruntime::types::{CachePointBlock, CachePointType};
+
+use crate::constants;
+use crate::models::backend::ModelBackend;
+use crate::models::resolver::resolve_foundation_model;
 
 pub enum ModelFeatures {
     Streaming,
     Images,
 }
 
+// Strips the cross region inference prefix (`us.`, `eu.`, ...) from a model id
+pub fn base_model_id(m: &str) -> &str {
+    constants::CROSS_REGION_PREFIXES
+        .iter()
+        .find_map(|prefix| m.strip_prefix(prefix))
+        .unwrap_or(m)
+}
+
+// Can this model use tools? (Not something the Bedrock API can tell us)
+pub fn supports_tool_use(m: &str) -> bool {
+    let model_id = base_model_id(m);
+    constants::TOOL_USE_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+// Can this model cache parts of the prompt? (Not something the Bedrock API can tell us either)
+pub fn supports_prompt_caching(m: &str) -> bool {
+    let model_id = base_model_id(m);
+    constants::PROMPT_CACHING_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+// Can this model be given a reasoning budget (extended thinking)?
+pub fn supports_reasoning_budget(m: &str) -> bool {
+    let model_id = base_model_id(m);
+    constants::REASONING_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+// Can this model read the documents attached to a question?
+pub fn supports_documents(m: &str) -> bool {
+    let model_id = base_model_id(m);
+    constants::DOCUMENT_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+// Can this model generate images (Nova Canvas, Titan Image Generator)?
+pub fn supports_image_generation(m: &str) -> bool {
+    let model_id = base_model_id(m);
+    constants::IMAGE_GENERATION_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+// Everything in the prompt up to a cache point is cached by Bedrock
+pub fn cache_point() -> CachePointBlock {
+    CachePointBlock::builder()
+        .r#type(CachePointType::Default)
+        .build()
+        .expect("type is set")
+}
+
 pub async fn check_for_streaming(
     m: String,
-    c: &aws_sdk_bedrock::Client,
+    backend: &dyn ModelBackend,
 ) -> Result<bool, anyhow::Error> {
-    let call = c.get_foundation_model().model_identifier(m);
-    let res = call.send().await;
-    let model_details: FoundationModelDetails = res?
-        .model_details()
-        .ok_or_else(|| anyhow!("Unable to get model details"))?
-        .clone();
+    let model_id = resolve_foundation_model(&m, backend).await?;
+    let model_details: FoundationModelDetails = backend.model_details(&model_id).await?;
 
     match model_details.response_streaming_supported {
         Some(o) => Ok(o),
@@ -31,29 +97,14 @@ pub async fn check_for_streaming(
 
 pub async fn check_model_features(
     m: &str,
-    c: &aws_sdk_bedrock::Client,
+    backend: &dyn ModelBackend,
     feature: ModelFeatures,
 ) -> Result<bool, anyhow::Error> {
-    // FIX: TEMPORARY SOLUTION DUE TO CROSS REGION INFERENCE
-    // The issue here is that the converse model requires the `us.` in front of the nova models due
-    // to cross-region inference. But the get_foundation_model method needs the actual model id. So
-    // we are just hardcoding this in.
-    // PLEASE FIX THIS FUTURE DARKO
-    let model_id = match m {
-        "us.amazon.nova-micro-v1:0" => "amazon.nova-micro-v1:0",
-        "us.amazon.nova-lite-v1:0" => "amazon.nova-lite-v1:0",
-        "us.amazon.nova-pro-v1:0" => "amazon.nova-pro-v1:0",
-        "us.anthropic.claude-3-7-sonnet-20250219-v1:0" => "anthropic.claude-3-7-sonnet-20250219-v1:0",
-        "us.deepseek.r1-v1:0" => "deepseek.r1-v1:0",
-        _ => m,
-    };
+    // NOTE: The converse API takes inference profiles (`us.amazon.nova-pro-v1:0`, ARNs, ...) but
+    // get_foundation_model needs the actual model id
+    let model_id = resolve_foundation_model(m, backend).await?;
 
-    let call = c.get_foundation_model().model_identifier(model_id);
-    let res = call.send().await;
-    let model_details: FoundationModelDetails = res?
-        .model_details()
-        .ok_or_else(|| anyhow!("Unable to get model details"))?
-        .clone();
+    let model_details: FoundationModelDetails = backend.model_details(&model_id).await?;
 
     match feature {
         ModelFeatures::Images => match model_details.input_modalities {
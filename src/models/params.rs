@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+
+use aws_sdk_bedrockruntime::types::InferenceConfiguration;
+use aws_smithy_types::Document;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::base_model_id;
+use crate::models::document::json_to_document;
+use crate::utils::InferenceParams;
+
+// NOTE: Inference parameters per model. `inference_params` in `bedrust_config.ron` are the
+// defaults for every model, and `model_params` can override them for a provider (`anthropic`,
+// `amazon`, `meta`, ...) or for a single model. Anything the Converse API has no field for (ie
+// `top_k`) goes in `additional_fields`, and is sent as the additional model request fields.
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ModelParams {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub max_tokens: Option<i32>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub stop_sequences: Option<Vec<String>>,
+    // model specific fields, ie `{"top_k": 250}`
+    #[serde(default)]
+    pub additional_fields: Option<Value>,
+}
+
+impl From<&InferenceParams> for ModelParams {
+    fn from(params: &InferenceParams) -> Self {
+        ModelParams {
+            temperature: Some(params.temperature),
+            max_tokens: Some(params.max_tokens),
+            top_p: Some(params.top_p),
+            ..Default::default()
+        }
+    }
+}
+
+impl ModelParams {
+    // The defaults, with the overrides for the provider and then for the model on top
+    pub fn for_model(
+        defaults: &InferenceParams,
+        overrides: &HashMap<String, ModelParams>,
+        model_id: &str,
+    ) -> ModelParams {
+        let base_model = base_model_id(model_id);
+        let provider = base_model.split('.').next().unwrap_or(base_model);
+        let mut keys = vec![provider, base_model, model_id];
+        keys.dedup();
+
+        let mut params = ModelParams::from(defaults);
+        for key in keys {
+            if let Some(model_params) = overrides.get(key) {
+                params.merge(model_params);
+            }
+        }
+        params
+    }
+
+    // Whatever is set in `other` wins
+    pub fn merge(&mut self, other: &ModelParams) {
+        self.temperature = other.temperature.or(self.temperature);
+        self.max_tokens = other.max_tokens.or(self.max_tokens);
+        self.top_p = other.top_p.or(self.top_p);
+        if other.stop_sequences.is_some() {
+            self.stop_sequences = other.stop_sequences.clone();
+        }
+        if let Some(fields) = &other.additional_fields {
+            match self.additional_fields.as_mut() {
+                Some(current) => merge_json(current, fields),
+                None => self.additional_fields = Some(fields.clone()),
+            }
+        }
+    }
+
+    pub fn inference_config(&self) -> InferenceConfiguration {
+        InferenceConfiguration::builder()
+            .set_max_tokens(self.max_tokens)
+            .set_temperature(self.temperature)
+            .set_top_p(self.top_p)
+            .set_stop_sequences(self.stop_sequences.clone())
+            .build()
+    }
+
+    pub fn additional_fields(&self) -> Option<Document> {
+        self.additional_fields.as_ref().map(json_to_document)
+    }
+}
+
+// Objects are merged key by key, anything else is replaced
+fn merge_json(current: &mut Value, other: &Value) {
+    match (current, other) {
+        (Value::Object(current), Value::Object(other)) => {
+            for (key, value) in other {
+                merge_json(current.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (current, other) => *current = other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn model_params_are_merged_over_the_defaults() {
+        let defaults = InferenceParams {
+            temperature: 0.5,
+            max_tokens: 2048,
+            top_p: 0.8,
+        };
+        let overrides: HashMap<String, ModelParams> = ron::from_str(
+            r#"{
+                "anthropic": (top_p: Some(0.9), additional_fields: Some({"top_k": 250, "thinking": {"type": "disabled"}})),
+                "anthropic.claude-3-5-haiku-20241022-v1:0": (
+                    temperature: Some(0.1),
+                    stop_sequences: Some(["</answer>"]),
+                    additional_fields: Some({"thinking": {"budget_tokens": 1024}}),
+                ),
+            }"#,
+        )
+        .unwrap();
+
+        let params = ModelParams::for_model(
+            &defaults,
+            &overrides,
+            "us.anthropic.claude-3-5-haiku-20241022-v1:0",
+        );
+        let config = params.inference_config();
+        assert_eq!(config.temperature(), Some(0.1));
+        assert_eq!(config.top_p(), Some(0.9));
+        assert_eq!(config.max_tokens(), Some(2048));
+        assert_eq!(config.stop_sequences(), ["</answer>"]);
+        assert_eq!(
+            params.additional_fields,
+            Some(json!({"top_k": 250, "thinking": {"type": "disabled", "budget_tokens": 1024}}))
+        );
+
+        // no overrides for this one
+        let params = ModelParams::for_model(&defaults, &overrides, "amazon.nova-pro-v1:0");
+        assert_eq!(params, ModelParams::from(&defaults));
+        assert_eq!(params.additional_fields(), None);
+    }
+}
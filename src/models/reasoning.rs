@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+use crate::constants::MIN_REASONING_BUDGET;
+use crate::models::params::ModelParams;
+
+// NOTE: Extended thinking. The models that support it (see `supports_reasoning_budget`) only
+// think before answering when asked to, with a budget of tokens they can spend on it. The budget
+// comes from `reasoning_budget` in `bedrust_config.ron`, or from `--reasoning-budget`.
+
+// The budget to use: the command line wins over the config, and 0 turns it off
+pub fn reasoning_budget(config: Option<i32>, argument: Option<i32>) -> Result<Option<i32>> {
+    match argument.or(config) {
+        None | Some(0) => Ok(None),
+        Some(budget) if budget < MIN_REASONING_BUDGET => Err(anyhow!(
+            "The reasoning budget needs to be at least {} tokens (or 0 to turn it off), got {}",
+            MIN_REASONING_BUDGET,
+            budget
+        )),
+        Some(budget) => Ok(Some(budget)),
+    }
+}
+
+// Goes into the additional model request fields
+pub fn reasoning_request_fields(budget: i32) -> Value {
+    json!({
+        "thinking": {
+            "type": "enabled",
+            "budget_tokens": budget,
+        }
+    })
+}
+
+// NOTE: While thinking, the models do not take a temperature, top_p or top_k, and the thinking
+// counts towards max_tokens. So the budget is added on top of `max_tokens`, to leave room for the answer.
+pub fn with_reasoning(mut params: ModelParams, budget: i32) -> ModelParams {
+    params.temperature = None;
+    params.top_p = None;
+    if let Some(Value::Object(fields)) = params.additional_fields.as_mut() {
+        fields.remove("top_k");
+    }
+    params.max_tokens = Some(params.max_tokens.unwrap_or_default() + budget);
+    params.merge(&ModelParams {
+        additional_fields: Some(reasoning_request_fields(budget)),
+        ..Default::default()
+    });
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reasoning_budget_and_request() {
+        assert_eq!(reasoning_budget(None, None).unwrap(), None);
+        assert_eq!(reasoning_budget(Some(2048), None).unwrap(), Some(2048));
+        assert_eq!(
+            reasoning_budget(Some(2048), Some(4096)).unwrap(),
+            Some(4096)
+        );
+        assert_eq!(reasoning_budget(Some(2048), Some(0)).unwrap(), None);
+        assert!(reasoning_budget(Some(100), None).is_err());
+
+        let params = ModelParams {
+            temperature: Some(0.5),
+            max_tokens: Some(1000),
+            top_p: Some(0.8),
+            additional_fields: Some(
+                json!({"top_k": 250, "anthropic_beta": ["interleaved-thinking-2025-05-14"]}),
+            ),
+            ..Default::default()
+        };
+        let params = with_reasoning(params, 2048);
+        let config = params.inference_config();
+        assert_eq!(config.max_tokens(), Some(3048));
+        assert_eq!(config.temperature(), None);
+        assert_eq!(config.top_p(), None);
+        // top_k goes, the other fields stay
+        let fields = params.additional_fields.unwrap();
+        assert_eq!(fields.get("top_k"), None);
+        assert_eq!(
+            fields["anthropic_beta"],
+            json!(["interleaved-thinking-2025-05-14"])
+        );
+        assert_eq!(
+            fields["thinking"],
+            json!({"type": "enabled", "budget_tokens": 2048})
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{anyhow, bail};
+
+use crate::constants;
+use crate::models::backend::ModelBackend;
+
+// NOTE: We call Bedrock with whatever model identifier the user gave us, but to figure out what
+// the model can do (images, streaming, tools) we need the id of the foundation model behind it.
+// The identifier can be:
+// - a foundation model id: `anthropic.claude-3-haiku-20240307-v1:0`
+// - a cross region inference profile: `us.anthropic.claude-3-7-sonnet-20250219-v1:0`
+// - an inference profile with a prefix we do not know yet: `jp.`, `global.`, ...
+// - an application inference profile ARN: `arn:aws:bedrock:us-east-1:123456789012:application-inference-profile/abc123`
+// - a provisioned throughput ARN: `arn:aws:bedrock:us-east-1:123456789012:provisioned-model/abc123`
+// - the ARN of any of the above
+// The profiles and provisioned throughputs are looked up in Bedrock.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelIdentifier<'a> {
+    FoundationModel(&'a str),
+    CrossRegionProfile { prefix: &'a str, model_id: &'a str },
+    InferenceProfile(&'a str),
+    ApplicationProfile(&'a str),
+    ProvisionedThroughput(&'a str),
+    CustomModel(&'a str),
+}
+
+// Splits a Bedrock ARN into the resource type and the resource id.
+// arn:aws:bedrock:<region>:<account>:<type>/<id> - the id can contain `:` (ie `v1:0`)
+fn arn_resource(arn: &str) -> Option<(&str, &str)> {
+    let mut parts = arn.splitn(6, ':');
+    if parts.next()? != "arn" {
+        return None;
+    }
+    let _partition = parts.next()?;
+    if parts.next()? != "bedrock" {
+        return None;
+    }
+    let _region = parts.next()?;
+    let _account = parts.next()?;
+    parts.next()?.split_once('/')
+}
+
+// The region in a Bedrock ARN, if there is one
+pub fn arn_region(arn: &str) -> Option<&str> {
+    arn.strip_prefix("arn:")?;
+    arn.split(':').nth(3).filter(|region| !region.is_empty())
+}
+
+// A foundation model id is `<provider>.<model>`, anything with another dotted part in front of it
+// (ie `global.anthropic.claude-sonnet-4-20250514-v1:0`) is an inference profile
+fn has_profile_prefix(m: &str) -> bool {
+    let name = m.split(':').next().unwrap_or(m);
+    name.split_once('.')
+        .is_some_and(|(_, model)| model.contains('.'))
+}
+
+fn profile_or_model(m: &str) -> ModelIdentifier<'_> {
+    constants::CROSS_REGION_PREFIXES
+        .iter()
+        .find_map(|prefix| {
+            m.strip_prefix(prefix)
+                .map(|model_id| ModelIdentifier::CrossRegionProfile { prefix, model_id })
+        })
+        .unwrap_or(if has_profile_prefix(m) {
+            ModelIdentifier::InferenceProfile(m)
+        } else {
+            ModelIdentifier::FoundationModel(m)
+        })
+}
+
+pub fn parse_model_identifier(m: &str) -> Result<ModelIdentifier<'_>, anyhow::Error> {
+    if !m.starts_with("arn:") {
+        return Ok(profile_or_model(m));
+    }
+    let (resource_type, id) =
+        arn_resource(m).ok_or_else(|| anyhow!("`{}` is not an Amazon Bedrock ARN", m))?;
+    Ok(match resource_type {
+        "foundation-model" => ModelIdentifier::FoundationModel(id),
+        "inference-profile" => profile_or_model(id),
+        "application-inference-profile" => ModelIdentifier::ApplicationProfile(m),
+        "provisioned-model" => ModelIdentifier::ProvisionedThroughput(m),
+        "custom-model" => ModelIdentifier::CustomModel(m),
+        other => bail!("Unsupported Amazon Bedrock resource `{}` in `{}`", other, m),
+    })
+}
+
+// The foundation model id behind a model id, inference profile or provisioned throughput
+pub async fn resolve_foundation_model(
+    m: &str,
+    backend: &dyn ModelBackend,
+) -> Result<String, anyhow::Error> {
+    match parse_model_identifier(m)? {
+        ModelIdentifier::FoundationModel(model_id) => Ok(model_id.to_string()),
+        ModelIdentifier::CrossRegionProfile { model_id, .. } => Ok(model_id.to_string()),
+        ModelIdentifier::InferenceProfile(profile)
+        | ModelIdentifier::ApplicationProfile(profile) => {
+            // a profile can route to multiple regions, but it is always the same model
+            let models = backend.inference_profile_models(profile).await?;
+            let model_arn = models
+                .first()
+                .ok_or_else(|| anyhow!("The inference profile `{}` has no models", profile))?;
+            foundation_model_from_arn(model_arn)
+        }
+        ModelIdentifier::ProvisionedThroughput(arn) => {
+            foundation_model_from_arn(&backend.provisioned_model(arn).await?)
+        }
+        ModelIdentifier::CustomModel(arn) => {
+            bail!("Unable to check the features of the custom model `{}`", arn)
+        }
+    }
+}
+
+fn foundation_model_from_arn(arn: &str) -> Result<String, anyhow::Error> {
+    match parse_model_identifier(arn)? {
+        ModelIdentifier::FoundationModel(model_id) => Ok(model_id.to_string()),
+        _ => bail!("`{}` is not a foundation model", arn),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::scripted::ScriptedBackend;
+
+    #[test]
+    fn parse_identifiers() {
+        assert_eq!(
+            parse_model_identifier("amazon.nova-pro-v1:0").unwrap(),
+            ModelIdentifier::FoundationModel("amazon.nova-pro-v1:0")
+        );
+        assert_eq!(
+            parse_model_identifier("apac.amazon.nova-pro-v1:0").unwrap(),
+            ModelIdentifier::CrossRegionProfile {
+                prefix: "apac.",
+                model_id: "amazon.nova-pro-v1:0"
+            }
+        );
+        assert_eq!(
+            parse_model_identifier(
+                "arn:aws:bedrock:eu-west-1:123456789012:inference-profile/eu.amazon.nova-pro-v1:0"
+            )
+            .unwrap(),
+            ModelIdentifier::CrossRegionProfile {
+                prefix: "eu.",
+                model_id: "amazon.nova-pro-v1:0"
+            }
+        );
+        assert_eq!(
+            parse_model_identifier(
+                "arn:aws:bedrock:us-east-1::foundation-model/amazon.nova-pro-v1:0"
+            )
+            .unwrap(),
+            ModelIdentifier::FoundationModel("amazon.nova-pro-v1:0")
+        );
+        let arn = "arn:aws:bedrock:us-east-1:123456789012:provisioned-model/abc123";
+        assert_eq!(
+            parse_model_identifier(arn).unwrap(),
+            ModelIdentifier::ProvisionedThroughput(arn)
+        );
+        assert_eq!(
+            parse_model_identifier("global.anthropic.claude-sonnet-4-20250514-v1:0").unwrap(),
+            ModelIdentifier::InferenceProfile("global.anthropic.claude-sonnet-4-20250514-v1:0")
+        );
+        assert!(parse_model_identifier("arn:aws:s3:::my-bucket/model").is_err());
+
+        assert_eq!(arn_region(arn), Some("us-east-1"));
+        assert_eq!(arn_region("arn:aws:s3:::my-bucket/model"), None);
+        assert_eq!(arn_region("eu.amazon.nova-pro-v1:0"), None);
+    }
+
+    #[tokio::test]
+    async fn resolve_profiles_and_provisioned_models() {
+        let profile = "arn:aws:bedrock:us-east-1:123456789012:application-inference-profile/abc";
+        let provisioned = "arn:aws:bedrock:us-east-1:123456789012:provisioned-model/xyz";
+        let backend = ScriptedBackend::new(vec![])
+            .with_underlying_model(
+                "jp.amazon.nova-pro-v1:0",
+                "arn:aws:bedrock:ap-northeast-1::foundation-model/amazon.nova-pro-v1:0",
+            )
+            .with_underlying_model(
+                profile,
+                "arn:aws:bedrock:us-east-1::foundation-model/amazon.nova-lite-v1:0",
+            )
+            .with_underlying_model(
+                provisioned,
+                "arn:aws:bedrock:us-east-1::foundation-model/anthropic.claude-3-haiku-20240307-v1:0",
+            );
+
+        assert_eq!(
+            resolve_foundation_model("us.amazon.nova-pro-v1:0", &backend)
+                .await
+                .unwrap(),
+            "amazon.nova-pro-v1:0"
+        );
+        // a prefix we do not know is looked up in Bedrock
+        assert_eq!(
+            resolve_foundation_model("jp.amazon.nova-pro-v1:0", &backend)
+                .await
+                .unwrap(),
+            "amazon.nova-pro-v1:0"
+        );
+        assert_eq!(
+            resolve_foundation_model(profile, &backend).await.unwrap(),
+            "amazon.nova-lite-v1:0"
+        );
+        assert_eq!(
+            resolve_foundation_model(provisioned, &backend)
+                .await
+                .unwrap(),
+            "anthropic.claude-3-haiku-20240307-v1:0"
+        );
+        assert!(resolve_foundation_model(
+            "arn:aws:bedrock:us-east-1:123456789012:application-inference-profile/unknown",
+            &backend
+        )
+        .await
+        .is_err());
+    }
+}
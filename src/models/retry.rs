@@ -0,0 +1,238 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// NOTE: Every call to Bedrock goes through `RetryPolicy::run`. Errors are sorted into classes,
+// and only the ones that can go away on their own (throttling, timeouts, a model that is still
+// loading, ...) are retried. Between attempts we wait with an exponential backoff plus some
+// jitter, and we give up once we run out of attempts or time. The policy is configured with
+// `retry` in `bedrust_config.ron`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    // Too many requests, or a service quota was hit
+    Throttling,
+    // The model (or the connection) took too long
+    Timeout,
+    // The model is still being loaded
+    NotReady,
+    // Bedrock had an internal issue, or could not be reached
+    Unavailable,
+    // The model answered, but not with something we can use
+    InvalidResponse,
+    // Something is wrong with the request itself, retrying will not help
+    Validation,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorClass::Throttling
+                | ErrorClass::Timeout
+                | ErrorClass::NotReady
+                | ErrorClass::Unavailable
+                | ErrorClass::InvalidResponse
+        )
+    }
+}
+
+pub trait ClassifyError {
+    fn error_class(&self) -> ErrorClass;
+}
+
+// whatever is left out of the config comes from `RetryPolicy::default()`
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    // 1 means no retries
+    pub max_attempts: u32,
+    // the first wait, it doubles after every attempt
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // we do not start another attempt after this much time has passed
+    pub max_elapsed_secs: u64,
+    // randomize the waits, so parallel calls do not retry in lockstep
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_delay_ms: 1000,
+            max_delay_ms: 20_000,
+            max_elapsed_secs: 60,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Try once, and that is it
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    // How long to wait after the given (1 based) attempt failed
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .initial_delay_ms
+            .saturating_mul(1u64 << exp)
+            .min(self.max_delay_ms);
+        let delay = if self.jitter && delay > 0 {
+            // somewhere between half and the full delay
+            rand::rng().random_range(delay / 2..=delay)
+        } else {
+            delay
+        };
+        Duration::from_millis(delay)
+    }
+
+    pub async fn run<T, E, F, Fut>(&self, label: &str, mut operation: F) -> Result<T, E>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: ClassifyError + Display,
+    {
+        let start = Instant::now();
+        let max_elapsed = Duration::from_secs(self.max_elapsed_secs);
+        let mut attempt = 1;
+        loop {
+            let error = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if !error.error_class().is_retryable() || attempt >= self.max_attempts.max(1) {
+                return Err(error);
+            }
+            let delay = self.delay_for(attempt);
+            if start.elapsed() + delay > max_elapsed {
+                return Err(error);
+            }
+            println!(
+                "🔴 | {} failed: {}. Retrying in {:.1}s ({}/{})",
+                label,
+                error,
+                delay.as_secs_f64(),
+                attempt,
+                self.max_attempts
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Debug)]
+    struct TestError(ErrorClass);
+    impl Display for TestError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.0)
+        }
+    }
+    impl ClassifyError for TestError {
+        fn error_class(&self) -> ErrorClass {
+            self.0
+        }
+    }
+
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay_ms: 1,
+            max_delay_ms: 2,
+            max_elapsed_secs: 10,
+            jitter: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_only_transient_errors() {
+        let policy = quick_policy();
+
+        // throttled twice, then it works
+        let calls = Cell::new(0);
+        let result = policy
+            .run("test", || {
+                calls.set(calls.get() + 1);
+                let n = calls.get();
+                async move {
+                    if n < 3 {
+                        Err(TestError(ErrorClass::Throttling))
+                    } else {
+                        Ok(n)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // validation errors are not retried
+        let calls = Cell::new(0);
+        let result: Result<(), _> = policy
+            .run("test", || {
+                calls.set(calls.get() + 1);
+                async { Err(TestError(ErrorClass::Validation)) }
+            })
+            .await;
+        assert_eq!(result.unwrap_err().0, ErrorClass::Validation);
+        assert_eq!(calls.get(), 1);
+
+        // we give up after max_attempts
+        let calls = Cell::new(0);
+        let result: Result<(), _> = policy
+            .run("test", || {
+                calls.set(calls.get() + 1);
+                async { Err(TestError(ErrorClass::Timeout)) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+
+        // and when we are out of time
+        let calls = Cell::new(0);
+        let out_of_time = RetryPolicy {
+            max_elapsed_secs: 0,
+            ..quick_policy()
+        };
+        let result: Result<(), _> = out_of_time
+            .run("test", || {
+                calls.set(calls.get() + 1);
+                async { Err(TestError(ErrorClass::NotReady)) }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn backoff_with_jitter() {
+        let policy = RetryPolicy {
+            jitter: false,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay_for(1), Duration::from_millis(1000));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(4000));
+        assert_eq!(policy.delay_for(30), Duration::from_millis(20_000));
+
+        let policy = RetryPolicy::default();
+        for _ in 0..20 {
+            let delay = policy.delay_for(2);
+            assert!(delay >= Duration::from_millis(1000) && delay <= Duration::from_millis(2000));
+        }
+    }
+}
@@ -0,0 +1,1171 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use aws_sdk_bedrock::types::{FoundationModelDetails, ModelModality};
+use aws_sdk_bedrockruntime::{
+    operation::converse::ConverseOutput,
+    types::{
+        ContentBlock, ContentBlockDelta, ContentBlockDeltaEvent, ContentBlockStart,
+        ContentBlockStartEvent, ContentBlockStopEvent, ConversationRole, ConverseMetrics,
+        ConverseOutput as ConverseOutputType, ConverseStreamMetadataEvent, ConverseStreamMetrics,
+        ConverseStreamOutput, ConverseStreamTrace, ConverseTrace, GuardrailAssessment,
+        GuardrailTopic, GuardrailTopicPolicyAction, GuardrailTopicPolicyAssessment,
+        GuardrailTopicType, GuardrailTraceAssessment, Message, MessageStartEvent, MessageStopEvent,
+        ReasoningContentBlock, ReasoningContentBlockDelta, ReasoningTextBlock, StopReason,
+        SystemContentBlock, TokenUsage, ToolUseBlock, ToolUseBlockDelta, ToolUseBlockStart,
+    },
+};
+use base64::{engine::general_purpose, Engine as _};
+use image::{ImageFormat, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::models::backend::{ConverseEventStream, ConverseRequest, ModelBackend};
+use crate::models::catalog::{CatalogEntry, CatalogEntryKind};
+use crate::models::document::{document_to_json, json_to_document};
+use crate::models::error::{BedrockError, BedrockErrorKind};
+use crate::models::retry::RetryPolicy;
+
+// NOTE: A stand-in for Amazon Bedrock. It just replays a list of canned answers, one per call, in
+// the order they were given. This allows us to run chat, captioning and code chat without AWS
+// credentials (in CI, on a plane, ...). Every request it receives is recorded so tests can check
+// what Bedrust would have sent.
+//
+// A script file is a RON list of turns, for example:
+// [
+//   Text("[\"rs\", \"toml\"]"),
+//   Text("Hello there! How can I help?"),
+//   ToolUse("current_time", "{}"),
+//   Text("It is late, go to bed."),
+//   Error("Model is not ready"),
+//   Throttled,
+//   Intervened("Sorry, I can not talk about that."),
+//   Reasoning("The user is greeting me.", "Hello!"),
+//   Images,
+// ]
+//
+// Embeddings are made up from the words in the text, and do not need a turn.
+
+const THROTTLED_MESSAGE: &str = "Your request was throttled, please check your service quotas";
+const SCRIPTED_SIGNATURE: &str = "scripted-signature";
+
+// One canned answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ScriptedTurn {
+    // The model answers with this text
+    Text(String),
+    // The model asks for a tool: the tool name and the input as a JSON string
+    ToolUse(String, String),
+    // The call fails with this error message
+    Error(String),
+    // The call is throttled (this one is retried)
+    Throttled,
+    // The guardrail blocks the question, and answers with this text
+    Intervened(String),
+    // The model thinks about it (the first text), and then answers with the second one
+    Reasoning(String, String),
+    // The image model answers with as many (tiny, gray) PNGs as it was asked for
+    Images,
+}
+
+pub struct ScriptedBackend {
+    turns: Mutex<VecDeque<ScriptedTurn>>,
+    requests: Mutex<Vec<ConverseRequest>>,
+    // InvokeModel calls: the model id and the JSON body
+    invocations: Mutex<Vec<(String, Value)>>,
+    models: HashMap<String, FoundationModelDetails>,
+    // inference profile / provisioned throughput ARN -> foundation model ARN
+    underlying_models: HashMap<String, String>,
+    retry_policy: RetryPolicy,
+    // how many tokens sit in the (pretend) prompt cache
+    prompt_cache: Mutex<i32>,
+}
+
+impl ScriptedBackend {
+    pub fn new(turns: impl IntoIterator<Item = ScriptedTurn>) -> Self {
+        ScriptedBackend {
+            turns: Mutex::new(turns.into_iter().collect()),
+            requests: Mutex::new(Vec::new()),
+            invocations: Mutex::new(Vec::new()),
+            models: HashMap::new(),
+            underlying_models: HashMap::new(),
+            retry_policy: RetryPolicy::none(),
+            prompt_cache: Mutex::new(0),
+        }
+    }
+
+    // Load the turns from a RON script file
+    pub fn from_file(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(path)?;
+        let turns: Vec<ScriptedTurn> = ron::de::from_str(&content)
+            .map_err(|e| anyhow!("Unable to parse the script file {:?}: {}", path, e))?;
+        Ok(ScriptedBackend::new(turns))
+    }
+
+    // Override the model details returned for a specific model id. By default every model
+    // supports text, images and streaming.
+    pub fn with_model_details(mut self, details: FoundationModelDetails) -> Self {
+        self.models.insert(details.model_id().to_string(), details);
+        self
+    }
+
+    // No retries by default
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    // Make an inference profile or a provisioned throughput point to a foundation model
+    pub fn with_underlying_model(mut self, identifier: &str, model_arn: &str) -> Self {
+        self.underlying_models
+            .insert(identifier.to_string(), model_arn.to_string());
+        self
+    }
+
+    fn underlying_model(&self, identifier: &str) -> Result<String, anyhow::Error> {
+        self.underlying_models
+            .get(identifier)
+            .cloned()
+            .ok_or_else(|| anyhow!("`{}` not found", identifier))
+    }
+
+    // All the requests received so far
+    pub fn requests(&self) -> Vec<ConverseRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // All the InvokeModel calls received so far
+    pub fn invocations(&self) -> Vec<(String, Value)> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    // How many turns are left in the script
+    pub fn remaining(&self) -> usize {
+        self.turns.lock().unwrap().len()
+    }
+
+    // Everything up to the last cache point is written to the cache the first time, and read
+    // from it after that
+    fn prompt_tokens(&self, request: &ConverseRequest) -> PromptTokens {
+        let cacheable = cacheable_tokens(request);
+        let mut tokens = PromptTokens {
+            input: request_tokens(request) - cacheable,
+            ..Default::default()
+        };
+        let mut cache = self.prompt_cache.lock().unwrap();
+        if cacheable == 0 {
+            // nothing to cache
+        } else if *cache == cacheable {
+            tokens.cache_read = cacheable;
+        } else {
+            *cache = cacheable;
+            tokens.cache_write = cacheable;
+        }
+        tokens
+    }
+
+    fn next_turn(&self, request: ConverseRequest) -> Option<(usize, ScriptedTurn)> {
+        let mut requests = self.requests.lock().unwrap();
+        requests.push(request);
+        self.turns
+            .lock()
+            .unwrap()
+            .pop_front()
+            .map(|turn| (requests.len(), turn))
+    }
+}
+
+fn tool_use_block(
+    request_number: usize,
+    name: &str,
+    input: &str,
+) -> Result<ToolUseBlock, anyhow::Error> {
+    let input: Value = serde_json::from_str(input)?;
+    Ok(ToolUseBlock::builder()
+        .tool_use_id(format!("tooluse_{}", request_number))
+        .name(name)
+        .input(json_to_document(&input))
+        .build()?)
+}
+
+fn assistant_message(text: &str) -> Message {
+    Message::builder()
+        .role(ConversationRole::Assistant)
+        .content(ContentBlock::Text(text.to_string()))
+        .build()
+        .expect("role and content are set")
+}
+
+// Rough token count: one token per word
+fn count_tokens(text: &str) -> i32 {
+    text.split_whitespace().count() as i32
+}
+
+fn request_tokens(request: &ConverseRequest) -> i32 {
+    let messages: i32 = request
+        .messages
+        .iter()
+        .flat_map(|m| m.content())
+        .filter_map(|c| c.as_text().ok())
+        .map(|t| count_tokens(t))
+        .sum();
+    let system: i32 = request
+        .system
+        .iter()
+        .flatten()
+        .filter_map(|s| s.as_text().ok())
+        .map(|t| count_tokens(t))
+        .sum();
+    messages + system
+}
+
+// The tokens in front of the last cache point of the request
+fn cacheable_tokens(request: &ConverseRequest) -> i32 {
+    let system = request.system.iter().flatten().map(|s| match s {
+        SystemContentBlock::Text(t) => (count_tokens(t), false),
+        SystemContentBlock::CachePoint(_) => (0, true),
+        _ => (0, false),
+    });
+    let messages = request
+        .messages
+        .iter()
+        .flat_map(|m| m.content())
+        .map(|c| match c {
+            ContentBlock::Text(t) => (count_tokens(t), false),
+            ContentBlock::CachePoint(_) => (0, true),
+            _ => (0, false),
+        });
+    let mut tokens = 0;
+    let mut cacheable = 0;
+    for (count, cache_point) in system.chain(messages) {
+        tokens += count;
+        if cache_point {
+            cacheable = tokens;
+        }
+    }
+    cacheable
+}
+
+#[derive(Debug, Default)]
+struct PromptTokens {
+    input: i32,
+    cache_read: i32,
+    cache_write: i32,
+}
+
+// What the guardrail reports when it blocks a scripted question
+fn guardrail_trace() -> GuardrailTraceAssessment {
+    let topic = GuardrailTopic::builder()
+        .name("Scripted topic")
+        .r#type(GuardrailTopicType::Deny)
+        .action(GuardrailTopicPolicyAction::Blocked)
+        .build()
+        .expect("name, type and action are set");
+    GuardrailTraceAssessment::builder()
+        .input_assessment(
+            "scripted-guardrail",
+            GuardrailAssessment::builder()
+                .topic_policy(
+                    GuardrailTopicPolicyAssessment::builder()
+                        .topics(topic)
+                        .build()
+                        .expect("topics are set"),
+                )
+                .build(),
+        )
+        .build()
+}
+
+// The last event of every stream, with the (made up) token usage
+fn token_usage(prompt: &PromptTokens, output_tokens: i32) -> TokenUsage {
+    let cached = |tokens: i32| (tokens > 0).then_some(tokens);
+    TokenUsage::builder()
+        .input_tokens(prompt.input)
+        .output_tokens(output_tokens)
+        .total_tokens(prompt.input + prompt.cache_read + prompt.cache_write + output_tokens)
+        .set_cache_read_input_tokens(cached(prompt.cache_read))
+        .set_cache_write_input_tokens(cached(prompt.cache_write))
+        .build()
+        .expect("token counts are set")
+}
+
+fn metadata_event(
+    prompt: &PromptTokens,
+    output_tokens: i32,
+    guardrail: Option<GuardrailTraceAssessment>,
+) -> ConverseStreamOutput {
+    ConverseStreamOutput::Metadata(
+        ConverseStreamMetadataEvent::builder()
+            .usage(token_usage(prompt, output_tokens))
+            .metrics(
+                ConverseStreamMetrics::builder()
+                    .latency_ms(1)
+                    .build()
+                    .expect("latency is set"),
+            )
+            .set_trace(guardrail.map(|g| ConverseStreamTrace::builder().guardrail(g).build()))
+            .build(),
+    )
+}
+
+// Turn a text answer into the events ConverseStream would send, one word at a time
+fn text_events(text: &str, stop_reason: StopReason) -> Vec<ConverseStreamOutput> {
+    let mut events = vec![ConverseStreamOutput::MessageStart(
+        MessageStartEvent::builder()
+            .role(ConversationRole::Assistant)
+            .build()
+            .expect("role is set"),
+    )];
+    for word in text.split_inclusive(' ') {
+        events.push(ConverseStreamOutput::ContentBlockDelta(
+            ContentBlockDeltaEvent::builder()
+                .content_block_index(0)
+                .delta(ContentBlockDelta::Text(word.to_string()))
+                .build()
+                .expect("content_block_index is set"),
+        ));
+    }
+    events.push(ConverseStreamOutput::ContentBlockStop(
+        ContentBlockStopEvent::builder()
+            .content_block_index(0)
+            .build()
+            .expect("content_block_index is set"),
+    ));
+    events.push(ConverseStreamOutput::MessageStop(
+        MessageStopEvent::builder()
+            .stop_reason(stop_reason)
+            .build()
+            .expect("stop_reason is set"),
+    ));
+    events
+}
+
+// The reasoning comes in its own block, before the text, and ends with a signature
+fn reasoning_events(reasoning: &str) -> Vec<ConverseStreamOutput> {
+    let delta = |delta: ReasoningContentBlockDelta| {
+        ConverseStreamOutput::ContentBlockDelta(
+            ContentBlockDeltaEvent::builder()
+                .content_block_index(0)
+                .delta(ContentBlockDelta::ReasoningContent(delta))
+                .build()
+                .expect("content_block_index is set"),
+        )
+    };
+    let mut events: Vec<_> = reasoning
+        .split_inclusive(' ')
+        .map(|word| delta(ReasoningContentBlockDelta::Text(word.to_string())))
+        .collect();
+    events.push(delta(ReasoningContentBlockDelta::Signature(
+        SCRIPTED_SIGNATURE.to_string(),
+    )));
+    events.push(ConverseStreamOutput::ContentBlockStop(
+        ContentBlockStopEvent::builder()
+            .content_block_index(0)
+            .build()
+            .expect("content_block_index is set"),
+    ));
+    events
+}
+
+// The events ConverseStream sends when the model wants to use a tool
+fn tool_use_events(tool_use: &ToolUseBlock) -> Vec<ConverseStreamOutput> {
+    vec![
+        ConverseStreamOutput::MessageStart(
+            MessageStartEvent::builder()
+                .role(ConversationRole::Assistant)
+                .build()
+                .expect("role is set"),
+        ),
+        ConverseStreamOutput::ContentBlockStart(
+            ContentBlockStartEvent::builder()
+                .content_block_index(0)
+                .start(ContentBlockStart::ToolUse(
+                    ToolUseBlockStart::builder()
+                        .tool_use_id(tool_use.tool_use_id())
+                        .name(tool_use.name())
+                        .build()
+                        .expect("tool_use_id and name are set"),
+                ))
+                .build()
+                .expect("content_block_index is set"),
+        ),
+        ConverseStreamOutput::ContentBlockDelta(
+            ContentBlockDeltaEvent::builder()
+                .content_block_index(0)
+                .delta(ContentBlockDelta::ToolUse(
+                    ToolUseBlockDelta::builder()
+                        .input(document_to_json(tool_use.input()).to_string())
+                        .build()
+                        .expect("input is set"),
+                ))
+                .build()
+                .expect("content_block_index is set"),
+        ),
+        ConverseStreamOutput::ContentBlockStop(
+            ContentBlockStopEvent::builder()
+                .content_block_index(0)
+                .build()
+                .expect("content_block_index is set"),
+        ),
+        ConverseStreamOutput::MessageStop(
+            MessageStopEvent::builder()
+                .stop_reason(StopReason::ToolUse)
+                .build()
+                .expect("stop_reason is set"),
+        ),
+    ]
+}
+
+// Every word lands in one of the dimensions, so texts that share words end up close to each other
+fn scripted_embedding(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; 64];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        let hash = word
+            .to_lowercase()
+            .bytes()
+            .fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(b as u32));
+        vector[hash as usize % 64] += 1.0;
+    }
+    vector
+}
+
+// What the scripted image model draws, every time
+fn scripted_png() -> String {
+    let mut png = Cursor::new(Vec::new());
+    RgbImage::from_pixel(8, 8, Rgb([128, 128, 128]))
+        .write_to(&mut png, ImageFormat::Png)
+        .expect("a PNG can be written to memory");
+    general_purpose::STANDARD.encode(png.into_inner())
+}
+
+fn default_model_details(model_id: &str) -> FoundationModelDetails {
+    FoundationModelDetails::builder()
+        .model_id(model_id)
+        .model_arn(format!(
+            "arn:aws:bedrock:us-east-1::foundation-model/{}",
+            model_id
+        ))
+        .input_modalities(ModelModality::Text)
+        .input_modalities(ModelModality::Image)
+        .output_modalities(ModelModality::Text)
+        .response_streaming_supported(true)
+        .build()
+        .expect("model_id and model_arn are set")
+}
+
+#[async_trait]
+impl ModelBackend for ScriptedBackend {
+    fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    async fn converse(&self, request: ConverseRequest) -> Result<ConverseOutput, BedrockError> {
+        let output = |message: Message, stop_reason: StopReason, output_tokens: i32| {
+            ConverseOutput::builder()
+                .output(ConverseOutputType::Message(message))
+                .stop_reason(stop_reason)
+                .usage(token_usage(&self.prompt_tokens(&request), output_tokens))
+                .metrics(
+                    ConverseMetrics::builder()
+                        .latency_ms(1)
+                        .build()
+                        .expect("latency is set"),
+                )
+                .build()
+                .map_err(|_| BedrockError::from("Failed to build scripted output"))
+        };
+        match self.next_turn(request.clone()) {
+            Some((_, ScriptedTurn::Text(text))) => output(
+                assistant_message(&text),
+                StopReason::EndTurn,
+                count_tokens(&text),
+            ),
+            Some((_, ScriptedTurn::Reasoning(reasoning, text))) => {
+                let message = Message::builder()
+                    .role(ConversationRole::Assistant)
+                    .content(ContentBlock::ReasoningContent(
+                        ReasoningContentBlock::ReasoningText(
+                            ReasoningTextBlock::builder()
+                                .text(&reasoning)
+                                .signature(SCRIPTED_SIGNATURE)
+                                .build()
+                                .expect("text is set"),
+                        ),
+                    ))
+                    .content(ContentBlock::Text(text.clone()))
+                    .build()
+                    .expect("role and content are set");
+                let output_tokens = count_tokens(&reasoning) + count_tokens(&text);
+                output(message, StopReason::EndTurn, output_tokens)
+            }
+            Some((n, ScriptedTurn::ToolUse(name, input))) => {
+                let tool_use = tool_use_block(n, &name, &input)
+                    .map_err(|_| BedrockError::from("Invalid scripted tool use"))?;
+                let message = Message::builder()
+                    .role(ConversationRole::Assistant)
+                    .content(ContentBlock::ToolUse(tool_use))
+                    .build()
+                    .map_err(|_| BedrockError::from("Failed to build scripted output"))?;
+                output(message, StopReason::ToolUse, 1)
+            }
+            Some((_, ScriptedTurn::Error(message))) => Err(BedrockError::from(message.as_str())),
+            Some((_, ScriptedTurn::Throttled)) => Err(BedrockError::new(
+                BedrockErrorKind::Throttling,
+                THROTTLED_MESSAGE,
+            )),
+            Some((_, ScriptedTurn::Intervened(text))) => ConverseOutput::builder()
+                .output(ConverseOutputType::Message(assistant_message(&text)))
+                .stop_reason(StopReason::GuardrailIntervened)
+                .trace(
+                    ConverseTrace::builder()
+                        .guardrail(guardrail_trace())
+                        .build(),
+                )
+                .build()
+                .map_err(|_| BedrockError::from("Failed to build scripted output")),
+            Some((_, ScriptedTurn::Images)) => Err(BedrockError::new(
+                BedrockErrorKind::Validation,
+                "The model does not support the Converse API",
+            )),
+            None => Err(BedrockError::from(
+                "The scripted backend has no more responses",
+            )),
+        }
+    }
+
+    async fn converse_stream(
+        &self,
+        request: ConverseRequest,
+    ) -> Result<ConverseEventStream, BedrockError> {
+        // like Bedrock, refuse to stream from models that can not
+        let streaming = self
+            .models
+            .get(&request.model_id)
+            .map(|m| m.response_streaming_supported);
+        if streaming == Some(Some(false)) {
+            return Err(BedrockError::new(
+                BedrockErrorKind::Validation,
+                "The model is unsupported for streaming",
+            ));
+        }
+        match self.next_turn(request.clone()) {
+            Some((_, ScriptedTurn::Text(text))) => {
+                let metadata =
+                    metadata_event(&self.prompt_tokens(&request), count_tokens(&text), None);
+                Ok(ConverseEventStream::from_events(
+                    text_events(&text, StopReason::EndTurn)
+                        .into_iter()
+                        .chain([metadata])
+                        .map(Ok),
+                ))
+            }
+            Some((_, ScriptedTurn::Reasoning(reasoning, text))) => {
+                let output_tokens = count_tokens(&reasoning) + count_tokens(&text);
+                let metadata = metadata_event(&self.prompt_tokens(&request), output_tokens, None);
+                let mut events = text_events(&text, StopReason::EndTurn);
+                // right after the message start
+                events.splice(1..1, reasoning_events(&reasoning));
+                Ok(ConverseEventStream::from_events(
+                    events.into_iter().chain([metadata]).map(Ok),
+                ))
+            }
+            Some((n, ScriptedTurn::ToolUse(name, input))) => {
+                let tool_use = tool_use_block(n, &name, &input)
+                    .map_err(|_| BedrockError::from("Invalid scripted tool use"))?;
+                let metadata = metadata_event(&self.prompt_tokens(&request), 1, None);
+                Ok(ConverseEventStream::from_events(
+                    tool_use_events(&tool_use)
+                        .into_iter()
+                        .chain([metadata])
+                        .map(Ok),
+                ))
+            }
+            Some((_, ScriptedTurn::Error(message))) => Err(BedrockError::from(message.as_str())),
+            Some((_, ScriptedTurn::Throttled)) => Err(BedrockError::new(
+                BedrockErrorKind::Throttling,
+                THROTTLED_MESSAGE,
+            )),
+            Some((_, ScriptedTurn::Intervened(text))) => {
+                // the model never sees the question, so there is no output
+                let metadata =
+                    metadata_event(&self.prompt_tokens(&request), 0, Some(guardrail_trace()));
+                Ok(ConverseEventStream::from_events(
+                    text_events(&text, StopReason::GuardrailIntervened)
+                        .into_iter()
+                        .chain([metadata])
+                        .map(Ok),
+                ))
+            }
+            Some((_, ScriptedTurn::Images)) => Err(BedrockError::new(
+                BedrockErrorKind::Validation,
+                "The model does not support the Converse API",
+            )),
+            None => Err(BedrockError::from(
+                "The scripted backend has no more responses",
+            )),
+        }
+    }
+
+    async fn invoke_model(&self, model_id: &str, body: Vec<u8>) -> Result<Vec<u8>, BedrockError> {
+        let body: Value = serde_json::from_slice(&body)
+            .map_err(|_| BedrockError::new(BedrockErrorKind::Validation, "The body is not JSON"))?;
+        let count = body["imageGenerationConfig"]["numberOfImages"]
+            .as_u64()
+            .unwrap_or(1);
+        self.invocations
+            .lock()
+            .unwrap()
+            .push((model_id.to_string(), body.clone()));
+        // embeddings (Titan and Cohere) do not use up a turn
+        if let Some(text) = body["inputText"].as_str() {
+            return Ok(json!({ "embedding": scripted_embedding(text) })
+                .to_string()
+                .into_bytes());
+        }
+        if let Some(texts) = body["texts"].as_array() {
+            let embeddings: Vec<Vec<f32>> = texts
+                .iter()
+                .map(|text| scripted_embedding(text.as_str().unwrap_or_default()))
+                .collect();
+            return Ok(json!({ "embeddings": embeddings }).to_string().into_bytes());
+        }
+        match self.turns.lock().unwrap().pop_front() {
+            Some(ScriptedTurn::Images) => {
+                let images: Vec<String> = (0..count).map(|_| scripted_png()).collect();
+                Ok(json!({ "images": images, "error": null })
+                    .to_string()
+                    .into_bytes())
+            }
+            Some(ScriptedTurn::Error(message)) => Err(BedrockError::from(message.as_str())),
+            Some(ScriptedTurn::Throttled) => Err(BedrockError::new(
+                BedrockErrorKind::Throttling,
+                THROTTLED_MESSAGE,
+            )),
+            Some(_) => Err(BedrockError::new(
+                BedrockErrorKind::InvalidResponse,
+                "The scripted turn is not an image",
+            )),
+            None => Err(BedrockError::from(
+                "The scripted backend has no more responses",
+            )),
+        }
+    }
+
+    async fn model_details(&self, model_id: &str) -> Result<FoundationModelDetails, anyhow::Error> {
+        Ok(self
+            .models
+            .get(model_id)
+            .cloned()
+            .unwrap_or_else(|| default_model_details(model_id)))
+    }
+
+    // Only the models we were given details for
+    async fn list_models(&self) -> Result<Vec<CatalogEntry>, anyhow::Error> {
+        let mut entries: Vec<CatalogEntry> = self
+            .models
+            .values()
+            .map(|details| CatalogEntry {
+                id: details.model_id().to_string(),
+                name: details
+                    .model_name()
+                    .unwrap_or(details.model_id())
+                    .to_string(),
+                kind: CatalogEntryKind::FoundationModel,
+                arn: Some(details.model_arn().to_string()),
+                provider: details.provider_name().map(|p| p.to_string()),
+                input_modalities: details
+                    .input_modalities()
+                    .iter()
+                    .map(|m| m.as_str().to_string())
+                    .collect(),
+                output_modalities: details
+                    .output_modalities()
+                    .iter()
+                    .map(|m| m.as_str().to_string())
+                    .collect(),
+                streaming: details.response_streaming_supported(),
+                inference_types: Vec::new(),
+                models: Vec::new(),
+            })
+            .collect();
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
+
+    async fn inference_profile_models(&self, profile: &str) -> Result<Vec<String>, anyhow::Error> {
+        Ok(vec![self.underlying_model(profile)?])
+    }
+
+    async fn provisioned_model(&self, arn: &str) -> Result<String, anyhow::Error> {
+        self.underlying_model(arn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{ConversationHistory, SerializableMessage};
+    use crate::interrupt::Interrupt;
+    use crate::models::converse::call_converse;
+    use crate::models::converse_stream::{
+        call_converse_stream, system_blocks, ChatOptions, INTERRUPTED_MARKER,
+    };
+    use crate::models::reasoning::reasoning_request_fields;
+    use crate::models::retry::{ClassifyError, ErrorClass};
+    use crate::models::{check_for_streaming, check_model_features, ModelFeatures};
+    use crate::tools::ToolRegistry;
+    use aws_sdk_bedrockruntime::types::{
+        InferenceConfiguration, ReasoningContentBlock, ReasoningTextBlock, ToolResultStatus,
+    };
+
+    fn user_history(question: &str) -> ConversationHistory {
+        let message = Message::builder()
+            .role(ConversationRole::User)
+            .content(ContentBlock::Text(question.to_string()))
+            .build()
+            .unwrap();
+        ConversationHistory::new(
+            None,
+            None,
+            None,
+            Some(vec![SerializableMessage::from(message)]),
+        )
+    }
+
+    // the streaming path should put the words back together and send the whole history
+    #[tokio::test]
+    async fn stream_scripted_answer() {
+        let backend =
+            ScriptedBackend::new(vec![ScriptedTurn::Text("Hello from the other side".into())]);
+        let history = user_history("Hi!");
+
+        let convo = call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &history,
+            &ChatOptions::new(InferenceConfiguration::builder().build()),
+            &system_blocks("You are a test", false),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(convo.content, "Hello from the other side");
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].model_id, "scripted-model");
+        assert_eq!(requests[0].messages.len(), 1);
+        assert_eq!(backend.remaining(), 0);
+    }
+
+    // Ctrl-C while streaming keeps what we have, marked as interrupted
+    #[tokio::test]
+    async fn interrupted_stream() {
+        let backend = ScriptedBackend::new(vec![ScriptedTurn::Text("A very long answer".into())]);
+        let history = user_history("Hi!");
+        let interrupt = Interrupt::new();
+        interrupt.trigger();
+
+        let convo = call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &history,
+            &ChatOptions::new(InferenceConfiguration::builder().build()),
+            &system_blocks("You are a test", false),
+            None,
+            Some(&interrupt),
+        )
+        .await
+        .unwrap();
+
+        assert!(convo.content.ends_with(INTERRUPTED_MARKER));
+        assert_eq!(convo.messages.len(), 1);
+        assert!(convo.messages[0].text().ends_with(INTERRUPTED_MARKER));
+        // the next answer is not interrupted
+        assert!(!interrupt.is_requested());
+    }
+
+    // when the model asks for a tool, the result goes back to it and the stream continues
+    #[tokio::test]
+    async fn stream_with_tool_use() {
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::ToolUse("current_time".into(), "{}".into()),
+            ScriptedTurn::Text("It is late".into()),
+        ]);
+        let history = user_history("What time is it?");
+        let tools = ToolRegistry::builtin(None);
+
+        let convo = call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &history,
+            &ChatOptions::new(InferenceConfiguration::builder().build()),
+            &system_blocks("You are a test", false),
+            Some(&tools),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(convo.content, "It is late");
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].tool_config.is_some());
+        // user question, assistant tool use, user tool result
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 3);
+        let tool_use = messages[1].content()[0].as_tool_use().unwrap();
+        assert_eq!(tool_use.name(), "current_time");
+        let result = messages[2].content()[0].as_tool_result().unwrap();
+        assert_eq!(result.tool_use_id(), tool_use.tool_use_id());
+        assert_eq!(result.status(), Some(&ToolResultStatus::Success));
+        // the tool exchange and the answer all make it back into the history
+        assert_eq!(convo.messages.len(), 3);
+        assert_eq!(convo.messages[2].text(), "It is late");
+        // the usage of both calls is added up
+        let usage = convo.usage.unwrap();
+        assert_eq!(usage.model_id, "scripted-model");
+        assert_eq!(usage.output_tokens, 1 + 3);
+        assert_eq!(usage.latency_ms, 2);
+    }
+
+    #[tokio::test]
+    async fn throttled_calls_are_retried() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_delay_ms: 1,
+            max_delay_ms: 1,
+            max_elapsed_secs: 10,
+            jitter: false,
+        };
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Throttled,
+            ScriptedTurn::Throttled,
+            ScriptedTurn::Text("Finally".into()),
+            ScriptedTurn::Throttled,
+            ScriptedTurn::Error("Model is not ready".into()),
+        ])
+        .with_retry_policy(policy);
+        let history = user_history("Hello?");
+
+        let convo = call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &history,
+            &ChatOptions::new(InferenceConfiguration::builder().build()),
+            &system_blocks("You are a test", false),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(convo.content, "Finally");
+        assert_eq!(backend.requests().len(), 3);
+
+        // errors that are not transient are not retried
+        let err = call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &history,
+            &ChatOptions::new(InferenceConfiguration::builder().build()),
+            &system_blocks("You are a test", false),
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.error_class(), ErrorClass::Other);
+        assert_eq!(backend.remaining(), 0);
+    }
+
+    // interventions show up in the usage of the turn (and so in saved chats), and the single shot
+    // calls fail instead of returning the blocked message
+    #[tokio::test]
+    async fn guardrail_interventions() {
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Intervened("Sorry, I can not talk about that.".into()),
+            ScriptedTurn::Intervened("Blocked.".into()),
+        ]);
+        let convo = call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &user_history("Which stocks should I buy?"),
+            &ChatOptions::new(InferenceConfiguration::builder().build()),
+            &system_blocks("You are a test", false),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(convo.content, "Sorry, I can not talk about that.");
+        let guardrail = convo.usage.unwrap().guardrail.unwrap();
+        assert!(guardrail.intervened);
+        assert_eq!(guardrail.findings.len(), 1);
+        assert_eq!(guardrail.findings[0].name, "Scripted topic");
+
+        let err = call_converse(
+            &backend,
+            "scripted-model".into(),
+            InferenceConfiguration::builder().build(),
+            None,
+            ContentBlock::Text("Which stocks should I buy?".into()),
+            None,
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind, BedrockErrorKind::GuardrailIntervened);
+        assert_eq!(err.message, "Blocked.");
+        assert_eq!(err.model_id.as_deref(), Some("scripted-model"));
+    }
+
+    // the system prompt is written to the cache on the first call, and read from it after that
+    #[tokio::test]
+    async fn cached_system_prompt() {
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Text("First answer".into()),
+            ScriptedTurn::Text("Second answer".into()),
+        ]);
+        let system = system_blocks("You are a test", true);
+        let mut turns = Vec::new();
+        for _ in 0..2 {
+            let convo = call_converse_stream(
+                &backend,
+                "scripted-model".into(),
+                &user_history("Hi there!"),
+                &ChatOptions::new(InferenceConfiguration::builder().build()),
+                &system,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+            turns.push(convo.usage.unwrap());
+        }
+        assert_eq!(
+            (
+                turns[0].input_tokens,
+                turns[0].cache_write_tokens,
+                turns[0].cache_read_tokens
+            ),
+            (2, 4, 0)
+        );
+        assert_eq!(
+            (
+                turns[1].input_tokens,
+                turns[1].cache_write_tokens,
+                turns[1].cache_read_tokens
+            ),
+            (2, 0, 4)
+        );
+        assert!(backend.requests()[0].system.as_ref().unwrap()[1].is_cache_point());
+    }
+
+    // the reasoning is kept in its own block with the signature, and only sent back when signed
+    #[tokio::test]
+    async fn reasoning_is_kept_with_its_signature() {
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::Reasoning("The user says hi.".into(), "Hi there!".into()),
+            ScriptedTurn::Text("Still here.".into()),
+        ]);
+        let options = ChatOptions::new(InferenceConfiguration::builder().build())
+            .additional_fields(Some(json_to_document(&reasoning_request_fields(2048))))
+            .show_reasoning(false);
+        let system = system_blocks("You are a test", false);
+        let mut history = user_history("Hi!");
+        let convo = call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &history,
+            &options,
+            &system,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(convo.content, "Hi there!");
+        let answer = Message::from(convo.messages[0].clone());
+        let reasoning = answer.content()[0]
+            .as_reasoning_content()
+            .unwrap()
+            .as_reasoning_text()
+            .unwrap();
+        assert_eq!(reasoning.text(), "The user says hi.");
+        assert_eq!(reasoning.signature(), Some(SCRIPTED_SIGNATURE));
+        assert_eq!(answer.content()[1].as_text().unwrap(), "Hi there!");
+        assert!(backend.requests()[0].additional_fields.is_some());
+
+        // an answer with reasoning that was never signed (ie stopped with Ctrl-C)
+        let unsigned = Message::builder()
+            .role(ConversationRole::Assistant)
+            .content(ContentBlock::ReasoningContent(
+                ReasoningContentBlock::ReasoningText(
+                    ReasoningTextBlock::builder().text("Hmm").build().unwrap(),
+                ),
+            ))
+            .content(ContentBlock::Text("Hello?".into()))
+            .build()
+            .unwrap();
+        let messages = history.messages.as_mut().unwrap();
+        messages.extend(convo.messages);
+        messages.push(user_history("Again").messages.unwrap().remove(0));
+        messages.push(unsigned.into());
+        messages.push(user_history("Are you there?").messages.unwrap().remove(0));
+        call_converse_stream(
+            &backend,
+            "scripted-model".into(),
+            &history,
+            &options,
+            &system,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        let sent = &backend.requests()[1].messages;
+        assert_eq!(sent[1].content().len(), 2);
+        assert_eq!(sent[3].content().len(), 1);
+        assert_eq!(sent[3].content()[0].as_text().unwrap(), "Hello?");
+        // the history itself keeps it
+        assert_eq!(history.messages.unwrap()[3].content.len(), 2);
+    }
+
+    // models that can not stream get the same turn (tools, reasoning, usage) through Converse
+    #[tokio::test]
+    async fn models_without_streaming_use_converse() {
+        let details = FoundationModelDetails::builder()
+            .model_id("no-streaming")
+            .model_arn("arn:aws:bedrock:us-east-1::foundation-model/no-streaming")
+            .response_streaming_supported(false)
+            .build()
+            .unwrap();
+        let backend = ScriptedBackend::new(vec![
+            ScriptedTurn::ToolUse("current_time".into(), "{}".into()),
+            ScriptedTurn::Reasoning("It is late.".into(), "Go to bed.".into()),
+        ])
+        .with_model_details(details);
+        assert!(!check_for_streaming("no-streaming".into(), &backend)
+            .await
+            .unwrap());
+
+        let history = user_history("What time is it?");
+        let tools = ToolRegistry::builtin(None);
+        let options = ChatOptions::new(InferenceConfiguration::builder().build());
+        let system = system_blocks("You are a test", false);
+        let err = call_converse_stream(
+            &backend,
+            "no-streaming".into(),
+            &history,
+            &options,
+            &system,
+            Some(&tools),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.error_class(), ErrorClass::Validation);
+
+        let convo = call_converse_stream(
+            &backend,
+            "no-streaming".into(),
+            &history,
+            &options.streaming(false),
+            &system,
+            Some(&tools),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(convo.content, "Go to bed.");
+        // the tool use, its result, and the answer with its reasoning
+        assert_eq!(convo.messages.len(), 3);
+        let answer = Message::from(convo.messages[2].clone());
+        let reasoning = answer.content()[0].as_reasoning_content().unwrap();
+        assert_eq!(
+            reasoning.as_reasoning_text().unwrap().signature(),
+            Some(SCRIPTED_SIGNATURE)
+        );
+        let usage = convo.usage.unwrap();
+        assert_eq!(usage.output_tokens, 1 + 6);
+        assert_eq!(usage.latency_ms, 2);
+        // the tool result went back with the whole history and the system prompt
+        let last = backend.requests().pop().unwrap();
+        assert_eq!(last.messages.len(), 3);
+        assert_eq!(last.system, Some(system));
+    }
+
+    #[tokio::test]
+    async fn scripted_errors_and_exhaustion() {
+        let backend = ScriptedBackend::new(vec![ScriptedTurn::Error("Model is not ready".into())]);
+        let content = ContentBlock::Text("Hi!".into());
+        let params = InferenceConfiguration::builder().build();
+
+        let err = call_converse(
+            &backend,
+            "m".into(),
+            params.clone(),
+            None,
+            content.clone(),
+            None,
+            false,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Model is not ready"));
+
+        let err = call_converse(&backend, "m".into(), params, None, content, None, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no more responses"));
+    }
+
+    #[tokio::test]
+    async fn scripted_model_features() {
+        let text_only = FoundationModelDetails::builder()
+            .model_id("text-only")
+            .model_arn("arn:aws:bedrock:us-east-1::foundation-model/text-only")
+            .input_modalities(ModelModality::Text)
+            .build()
+            .unwrap();
+        let profile = "arn:aws:bedrock:us-east-1:123456789012:application-inference-profile/abc";
+        let backend = ScriptedBackend::new(vec![])
+            .with_model_details(text_only)
+            .with_underlying_model(
+                profile,
+                "arn:aws:bedrock:us-east-1::foundation-model/text-only",
+            );
+
+        assert!(
+            check_model_features("any-model", &backend, ModelFeatures::Images)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !check_model_features("text-only", &backend, ModelFeatures::Images)
+                .await
+                .unwrap()
+        );
+        // profiles are checked against the model behind them
+        assert!(
+            !check_model_features("eu.text-only", &backend, ModelFeatures::Images)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !check_model_features(profile, &backend, ModelFeatures::Images)
+                .await
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use dirs::home_dir;
+use serde::{Deserialize, Serialize};
+
+use crate::chat::ConversationHistory;
+use crate::constants::{self, EMBEDDING_CHUNK_CHARS, MAX_EMBEDDING_CHUNKS};
+use crate::models::backend::ModelBackend;
+use crate::models::embeddings::{embed, similarity, EmbeddingPurpose};
+use crate::utils::print_warning;
+
+// NOTE: Semantic search over the saved chats (`--search-chats` and `/r <query>`). Every saved
+// chat is cut into chunks (the title and summary, then the messages), and every chunk is embedded
+// with the `embedding_model`. The vectors are kept in `~/.config/bedrust/chat_index.json`, so only
+// the chats that are new (or changed) since the last search are embedded. A chat is as close to
+// the query as its closest chunk.
+
+// how many chats `--search-chats` lists
+const SEARCH_RESULTS: usize = 10;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChatIndex {
+    // vectors of different models can not be compared, a new model means a new index
+    pub model_id: String,
+    // chat file name -> its vectors
+    pub chats: HashMap<String, IndexedChat>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedChat {
+    // when the chat file was written, in milliseconds
+    pub modified: u64,
+    pub vectors: Vec<Vec<f32>>,
+}
+
+// A saved chat, and how close it is to the query (1.0 is as close as it gets)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchResult {
+    pub filename: String,
+    pub score: f32,
+}
+
+impl ChatIndex {
+    pub fn path() -> PathBuf {
+        let home_dir = home_dir().expect("Failed to get HOME directory");
+        home_dir.join(format!(
+            ".config/{}/{}",
+            constants::CONFIG_DIR_NAME,
+            constants::CHAT_INDEX_FILE_NAME
+        ))
+    }
+
+    // A missing or broken index (or one made with another model) is started over
+    pub fn load(path: &Path, model_id: &str) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<ChatIndex>(&content).ok())
+            .filter(|index| index.model_id == model_id)
+            .unwrap_or_else(|| ChatIndex {
+                model_id: model_id.to_string(),
+                chats: HashMap::new(),
+            })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    // Embeds the chats that are new or changed, and forgets the ones that are gone. Returns how
+    // many chats were embedded.
+    pub async fn update(
+        &mut self,
+        backend: &dyn ModelBackend,
+        chat_dir: &Path,
+    ) -> Result<usize, anyhow::Error> {
+        let mut files: HashMap<String, u64> = HashMap::new();
+        // nothing was saved yet
+        if !chat_dir.exists() {
+            self.chats.clear();
+            return Ok(0);
+        }
+        for entry in fs::read_dir(chat_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("json") {
+                continue;
+            }
+            let modified = fs::metadata(&path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)?
+                .as_millis() as u64;
+            if let Some(filename) = path.file_name().and_then(|s| s.to_str()) {
+                files.insert(filename.to_string(), modified);
+            }
+        }
+        self.chats
+            .retain(|filename, _| files.contains_key(filename));
+
+        let mut changed: Vec<(String, u64)> = files
+            .into_iter()
+            .filter(|(filename, modified)| {
+                self.chats.get(filename).map(|chat| chat.modified) != Some(*modified)
+            })
+            .collect();
+        if changed.is_empty() {
+            return Ok(0);
+        }
+        changed.sort();
+        println!("⏳ | Indexing {} saved chat(s) for search", changed.len());
+        for (filename, modified) in &changed {
+            let chat = fs::read_to_string(chat_dir.join(filename))
+                .ok()
+                .and_then(|content| serde_json::from_str::<ConversationHistory>(&content).ok());
+            let chunks = match chat {
+                Some(chat) => chat_chunks(&chat),
+                None => {
+                    print_warning(&format!(
+                        "Unable to read the chat {}, skipping it",
+                        filename
+                    ));
+                    Vec::new()
+                }
+            };
+            let vectors = if chunks.is_empty() {
+                Vec::new()
+            } else {
+                embed(backend, &self.model_id, &chunks, EmbeddingPurpose::Document).await?
+            };
+            self.chats.insert(
+                filename.clone(),
+                IndexedChat {
+                    modified: *modified,
+                    vectors,
+                },
+            );
+        }
+        Ok(changed.len())
+    }
+
+    // Closest first. Chats with nothing to compare (ie empty ones) come last.
+    pub fn rank(&self, query: &[f32]) -> Vec<SearchResult> {
+        let mut results: Vec<SearchResult> = self
+            .chats
+            .iter()
+            .map(|(filename, chat)| SearchResult {
+                filename: filename.clone(),
+                score: chat
+                    .vectors
+                    .iter()
+                    .map(|vector| similarity(vector, query))
+                    .fold(-1.0, f32::max),
+            })
+            .collect();
+        results.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| b.filename.cmp(&a.filename))
+        });
+        results
+    }
+}
+
+// The saved chats, closest to the query first
+pub async fn search_chats(
+    backend: &dyn ModelBackend,
+    model_id: &str,
+    query: &str,
+    chat_dir: &Path,
+    index_path: &Path,
+) -> Result<Vec<SearchResult>, anyhow::Error> {
+    let mut index = ChatIndex::load(index_path, model_id);
+    let updated = index.update(backend, chat_dir).await;
+    // what got embedded is kept, even when the update failed half way
+    if !matches!(updated, Ok(0)) {
+        index.save(index_path)?;
+    }
+    updated?;
+    let query = embed(
+        backend,
+        model_id,
+        &[query.to_string()],
+        EmbeddingPurpose::Query,
+    )
+    .await?
+    .pop()
+    .ok_or_else(|| anyhow!("The model did not embed the query"))?;
+    Ok(index.rank(&query))
+}
+
+// Prints the closest chats, with their summary
+pub fn print_search_results(results: &[SearchResult], chat_dir: &Path) {
+    if results.is_empty() {
+        println!("No chat histories found.");
+        return;
+    }
+    for (i, result) in results.iter().take(SEARCH_RESULTS).enumerate() {
+        println!(
+            "{:>2}. {:>3.0}% | {}",
+            i + 1,
+            result.score.max(0.0) * 100.0,
+            result.filename
+        );
+        let summary = fs::read_to_string(chat_dir.join(&result.filename))
+            .ok()
+            .and_then(|content| serde_json::from_str::<ConversationHistory>(&content).ok())
+            .and_then(|chat| chat.summary);
+        if let Some(summary) = summary {
+            let summary: String = summary.chars().take(150).collect();
+            println!("           {}", summary.trim());
+        }
+    }
+}
+
+// The title and summary go in the first chunk, the messages fill up the rest
+fn chat_chunks(chat: &ConversationHistory) -> Vec<String> {
+    let head: Vec<String> = [
+        chat.title.as_ref().map(|title| title.replace('_', " ")),
+        chat.summary.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let mut chunks: Vec<String> = split_chars(&head.join("\n"), EMBEDDING_CHUNK_CHARS)
+        .into_iter()
+        .take(1)
+        .map(str::to_string)
+        .collect();
+
+    let mut chunk = String::new();
+    for message in chat.messages.iter().flatten() {
+        let text = format!("{}: {}", message.role, message.to_display_string());
+        for piece in split_chars(&text, EMBEDDING_CHUNK_CHARS) {
+            if !chunk.is_empty()
+                && chunk.chars().count() + piece.chars().count() + 2 > EMBEDDING_CHUNK_CHARS
+            {
+                chunks.push(std::mem::take(&mut chunk));
+            }
+            if !chunk.is_empty() {
+                chunk.push_str("\n\n");
+            }
+            chunk.push_str(piece);
+        }
+    }
+    chunks.push(chunk);
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    chunks.truncate(MAX_EMBEDDING_CHUNKS);
+    chunks
+}
+
+// Cuts the text in pieces of at most `size` characters
+fn split_chars(text: &str, size: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest
+            .char_indices()
+            .nth(size)
+            .map(|(i, _)| i)
+            .unwrap_or(rest.len());
+        pieces.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::SerializableMessage;
+    use crate::models::scripted::ScriptedBackend;
+    use crate::utils::test_dir;
+    use aws_sdk_bedrockruntime::types::{ContentBlock, ConversationRole, Message};
+
+    fn chat(title: &str, summary: &str, question: &str, answer: &str) -> ConversationHistory {
+        let message = |role: ConversationRole, text: &str| {
+            let message = Message::builder()
+                .role(role)
+                .content(ContentBlock::Text(text.to_string()))
+                .build()
+                .unwrap();
+            SerializableMessage::from(message)
+        };
+        ConversationHistory::new(
+            Some(title.to_string()),
+            None,
+            Some(summary.to_string()),
+            Some(vec![
+                message(ConversationRole::User, question),
+                message(ConversationRole::Assistant, answer),
+            ]),
+        )
+    }
+
+    #[test]
+    fn chats_are_cut_into_chunks() {
+        assert_eq!(split_chars("ab🦀cd", 2), ["ab", "🦀c", "d"]);
+        let long = "word ".repeat(EMBEDDING_CHUNK_CHARS / 2);
+        let chunks = chat_chunks(&chat("rust_lifetimes", "About lifetimes.", &long, "Short."));
+        assert_eq!(chunks[0], "rust lifetimes\nAbout lifetimes.");
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks
+            .iter()
+            .all(|c| c.chars().count() <= EMBEDDING_CHUNK_CHARS));
+        assert!(chunks[3].ends_with("assistant: Short."));
+    }
+
+    #[tokio::test]
+    async fn saved_chats_are_ranked_by_similarity() {
+        let dir = test_dir("search");
+        let chat_dir = dir.join("chats");
+        let index_path = dir.join("chat_index.json");
+        fs::create_dir_all(&chat_dir).unwrap();
+        let save = |filename: &str, chat: &ConversationHistory| {
+            fs::write(
+                chat_dir.join(filename),
+                serde_json::to_string(chat).unwrap(),
+            )
+            .unwrap();
+        };
+        save(
+            "iam_policies-abcde.json",
+            &chat(
+                "iam_policies",
+                "We wrote an IAM policy for S3.",
+                "How do I limit an IAM policy to one bucket?",
+                "Use a Resource with the bucket ARN.",
+            ),
+        );
+        save(
+            "rust_lifetimes-fghij.json",
+            &chat(
+                "rust_lifetimes",
+                "Lifetimes in Rust.",
+                "Why does the borrow checker complain?",
+                "The reference outlives the value.",
+            ),
+        );
+        fs::write(chat_dir.join("broken.json"), "{").unwrap();
+
+        let backend = ScriptedBackend::new(vec![]);
+        let model_id = "amazon.titan-embed-text-v2:0";
+        let results = search_chats(&backend, model_id, "iam policy", &chat_dir, &index_path)
+            .await
+            .unwrap();
+        let ranked: Vec<&str> = results.iter().map(|r| r.filename.as_str()).collect();
+        assert_eq!(
+            ranked,
+            [
+                "iam_policies-abcde.json",
+                "rust_lifetimes-fghij.json",
+                "broken.json"
+            ]
+        );
+        assert!(results[0].score > results[1].score);
+        let embedded = backend.invocations().len();
+
+        // nothing changed, so only the query is embedded
+        let results = search_chats(&backend, model_id, "borrow checker", &chat_dir, &index_path)
+            .await
+            .unwrap();
+        assert_eq!(results[0].filename, "rust_lifetimes-fghij.json");
+        assert_eq!(backend.invocations().len(), embedded + 1);
+
+        // deleted chats are dropped, and another model starts over
+        fs::remove_file(chat_dir.join("broken.json")).unwrap();
+        let cohere = "cohere.embed-english-v3";
+        let results = search_chats(&backend, cohere, "bucket", &chat_dir, &index_path)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        let index = ChatIndex::load(&index_path, cohere);
+        assert_eq!(index.chats.len(), 2);
+        assert!(ChatIndex::load(&index_path, model_id).chats.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
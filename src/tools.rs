@@ -0,0 +1,264 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+use aws_sdk_bedrockruntime::types::{
+    Tool as ToolDefinition, ToolConfiguration, ToolInputSchema, ToolResultBlock,
+    ToolResultContentBlock, ToolResultStatus, ToolSpecification, ToolUseBlock,
+};
+use chrono::Local;
+use serde_json::{json, Value};
+
+use crate::models::document::{document_to_json, json_to_document};
+
+// NOTE: Tools (function calling) the model can use during a chat. The model asks for a tool by
+// name, we run it here locally and send the result back. Every tool just returns text.
+// Tools that touch the file system are confined to the directory passed with `--source`.
+
+// How much of a file we are willing to send back to the model
+const MAX_TOOL_OUTPUT_BYTES: usize = 100_000;
+
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    // JSON schema of the input the tool expects
+    fn input_schema(&self) -> Value;
+    fn run(&self, input: &Value) -> Result<String, anyhow::Error>;
+}
+
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry::default()
+    }
+
+    // The tools Bedrust ships with. File system tools are only available when we have a source
+    // directory to confine them to.
+    pub fn builtin(source: Option<&Path>) -> Self {
+        let mut registry = ToolRegistry::new();
+        registry.register(CurrentTime);
+        if let Some(root) = source {
+            registry.register(ReadFile {
+                root: root.to_path_buf(),
+            });
+            registry.register(ListDirectory {
+                root: root.to_path_buf(),
+            });
+        }
+        registry
+    }
+
+    pub fn register(&mut self, tool: impl Tool + 'static) {
+        self.tools.push(Box::new(tool));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.tools.iter().map(|t| t.name()).collect()
+    }
+
+    // The tool configuration that is sent along with every request
+    pub fn tool_configuration(&self) -> Option<ToolConfiguration> {
+        if self.is_empty() {
+            return None;
+        }
+        let tools = self
+            .tools
+            .iter()
+            .filter_map(|tool| {
+                ToolSpecification::builder()
+                    .name(tool.name())
+                    .description(tool.description())
+                    .input_schema(ToolInputSchema::Json(json_to_document(
+                        &tool.input_schema(),
+                    )))
+                    .build()
+                    .ok()
+                    .map(ToolDefinition::ToolSpec)
+            })
+            .collect();
+        ToolConfiguration::builder()
+            .set_tools(Some(tools))
+            .build()
+            .ok()
+    }
+
+    // Runs the tool the model asked for. Failures are reported back to the model as an error
+    // result, so it can decide what to do next.
+    pub fn run(&self, tool_use: &ToolUseBlock) -> ToolResultBlock {
+        let input = document_to_json(tool_use.input());
+        let result = match self.tools.iter().find(|t| t.name() == tool_use.name()) {
+            Some(tool) => tool.run(&input),
+            None => Err(anyhow!("There is no tool called `{}`", tool_use.name())),
+        };
+        let (status, text) = match result {
+            Ok(text) => (ToolResultStatus::Success, text),
+            Err(e) => (ToolResultStatus::Error, e.to_string()),
+        };
+        ToolResultBlock::builder()
+            .tool_use_id(tool_use.tool_use_id())
+            .content(ToolResultContentBlock::Text(text))
+            .status(status)
+            .build()
+            .expect("tool_use_id and content are set")
+    }
+}
+
+// Resolves a path the model gave us, and makes sure it does not escape the root directory
+fn resolve_under(root: &Path, relative: &str) -> Result<PathBuf, anyhow::Error> {
+    let root = root.canonicalize()?;
+    let path = root
+        .join(relative)
+        .canonicalize()
+        .map_err(|e| anyhow!("Unable to open `{}`: {}", relative, e))?;
+    if !path.starts_with(&root) {
+        bail!("`{}` is outside of the source directory", relative);
+    }
+    Ok(path)
+}
+
+fn path_argument<'a>(input: &'a Value, default: Option<&'a str>) -> Result<&'a str, anyhow::Error> {
+    input
+        .get("path")
+        .and_then(|p| p.as_str())
+        .or(default)
+        .ok_or_else(|| anyhow!("Missing the `path` argument"))
+}
+
+// === BUILT-IN TOOLS ===
+pub struct CurrentTime;
+
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Returns the current local date and time, including the time zone."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn run(&self, _input: &Value) -> Result<String, anyhow::Error> {
+        Ok(Local::now().to_rfc3339())
+    }
+}
+
+pub struct ReadFile {
+    pub root: PathBuf,
+}
+
+impl Tool for ReadFile {
+    fn name(&self) -> &'static str {
+        "read_file"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reads a text file from the source code directory the user is working on. The path is relative to that directory."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "description": "Path of the file, relative to the source directory" }
+            },
+            "required": ["path"]
+        })
+    }
+
+    fn run(&self, input: &Value) -> Result<String, anyhow::Error> {
+        let path = resolve_under(&self.root, path_argument(input, None)?)?;
+        if !path.is_file() {
+            bail!("`{}` is not a file", path.display());
+        }
+        let mut content = fs::read_to_string(&path)?;
+        if content.len() > MAX_TOOL_OUTPUT_BYTES {
+            let mut end = MAX_TOOL_OUTPUT_BYTES;
+            while !content.is_char_boundary(end) {
+                end -= 1;
+            }
+            content.truncate(end);
+            content.push_str("\n... (truncated)");
+        }
+        Ok(content)
+    }
+}
+
+pub struct ListDirectory {
+    pub root: PathBuf,
+}
+
+impl Tool for ListDirectory {
+    fn name(&self) -> &'static str {
+        "list_directory"
+    }
+
+    fn description(&self) -> &'static str {
+        "Lists the files and directories in a directory of the source code the user is working on. Directories end with a `/`. The path is relative to the source directory, use `.` for the root."
+    }
+
+    fn input_schema(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "description": "Path of the directory, relative to the source directory" }
+            }
+        })
+    }
+
+    fn run(&self, input: &Value) -> Result<String, anyhow::Error> {
+        let path = resolve_under(&self.root, path_argument(input, Some("."))?)?;
+        if !path.is_dir() {
+            bail!("`{}` is not a directory", path.display());
+        }
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let mut name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() {
+                name.push('/');
+            }
+            entries.push(name);
+        }
+        entries.sort();
+        Ok(entries.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_dir;
+
+    // the file tools must not be able to read anything outside of the source directory
+    #[test]
+    fn file_tools_stay_in_source_dir() {
+        let root = test_dir("tools");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}").unwrap();
+
+        let read = ReadFile { root: root.clone() };
+        let list = ListDirectory { root: root.clone() };
+
+        assert_eq!(
+            read.run(&json!({ "path": "src/main.rs" })).unwrap(),
+            "fn main() {}"
+        );
+        assert_eq!(list.run(&json!({})).unwrap(), "src/");
+        assert!(read.run(&json!({ "path": "../../etc/passwd" })).is_err());
+        assert!(read.run(&json!({ "path": "/etc/passwd" })).is_err());
+        assert!(list.run(&json!({ "path": ".." })).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
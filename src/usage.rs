@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use aws_sdk_bedrockruntime::types::ConverseStreamMetadataEvent;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::guardrails::GuardrailTraceRecord;
+use crate::models::base_model_id;
+
+// NOTE: Token usage and cost accounting. ConverseStream ends every response with a metadata event
+// that has the token counts and the latency. We keep one `TurnRecord` per question, show it after
+// the answer, and add it up for the whole session. The dollar cost is only an estimate based on
+// the prices in `bedrust_config.ron`.
+
+// Price of a model in USD, per 1000 tokens
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct ModelPrice {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+    // prompt caching, if not set the tokens cost as much as the other input tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_read_per_1k: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_write_per_1k: Option<f64>,
+}
+
+// What a single question (including any tool calls the model made) used
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct TurnRecord {
+    pub model_id: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    // input tokens read from, and written to the prompt cache. Not part of `input_tokens`
+    #[serde(default)]
+    pub cache_read_tokens: u64,
+    #[serde(default)]
+    pub cache_write_tokens: u64,
+    pub latency_ms: u64,
+    pub timestamp: String,
+    // what the guardrail did, if there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guardrail: Option<GuardrailTraceRecord>,
+}
+
+impl TurnRecord {
+    pub fn new(model_id: impl Into<String>) -> Self {
+        TurnRecord {
+            model_id: model_id.into(),
+            timestamp: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            ..Default::default()
+        }
+    }
+
+    // A turn can be made of multiple model calls (tool use), so we keep adding them up
+    pub fn add_metadata(&mut self, metadata: &ConverseStreamMetadataEvent) {
+        if let Some(usage) = metadata.usage() {
+            self.input_tokens += usage.input_tokens().max(0) as u64;
+            self.output_tokens += usage.output_tokens().max(0) as u64;
+            self.cache_read_tokens += usage.cache_read_input_tokens().unwrap_or(0).max(0) as u64;
+            self.cache_write_tokens += usage.cache_write_input_tokens().unwrap_or(0).max(0) as u64;
+        }
+        if let Some(metrics) = metadata.metrics() {
+            self.latency_ms += metrics.latency_ms().max(0) as u64;
+        }
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.input_tokens + self.cache_read_tokens + self.cache_write_tokens + self.output_tokens
+    }
+
+    // None if we do not know the price of the model
+    pub fn cost(&self, prices: &HashMap<String, ModelPrice>) -> Option<f64> {
+        let price = find_price(&self.model_id, prices)?;
+        Some(
+            self.input_tokens as f64 / 1000.0 * price.input_per_1k
+                + self.output_tokens as f64 / 1000.0 * price.output_per_1k
+                + self.cache_read_tokens as f64 / 1000.0
+                    * price.cache_read_per_1k.unwrap_or(price.input_per_1k)
+                + self.cache_write_tokens as f64 / 1000.0
+                    * price.cache_write_per_1k.unwrap_or(price.input_per_1k),
+        )
+    }
+}
+
+// Prices are looked up by the exact model id first, and then by the base model id, so the price
+// of `anthropic.claude-3-haiku...` also covers `us.anthropic.claude-3-haiku...`
+fn find_price<'a>(
+    model_id: &str,
+    prices: &'a HashMap<String, ModelPrice>,
+) -> Option<&'a ModelPrice> {
+    prices
+        .get(model_id)
+        .or_else(|| prices.get(base_model_id(model_id)))
+}
+
+// Running totals for the whole session
+#[derive(Debug, Clone, Default)]
+pub struct SessionUsage {
+    pub turns: Vec<TurnRecord>,
+}
+
+impl SessionUsage {
+    pub fn new() -> Self {
+        SessionUsage::default()
+    }
+
+    pub fn add(&mut self, turn: TurnRecord) {
+        self.turns.push(turn);
+    }
+
+    pub fn input_tokens(&self) -> u64 {
+        self.turns.iter().map(|t| t.input_tokens).sum()
+    }
+
+    pub fn output_tokens(&self) -> u64 {
+        self.turns.iter().map(|t| t.output_tokens).sum()
+    }
+
+    pub fn cache_read_tokens(&self) -> u64 {
+        self.turns.iter().map(|t| t.cache_read_tokens).sum()
+    }
+
+    pub fn cache_write_tokens(&self) -> u64 {
+        self.turns.iter().map(|t| t.cache_write_tokens).sum()
+    }
+
+    // Only the turns we have a price for are counted
+    pub fn cost(&self, prices: &HashMap<String, ModelPrice>) -> Option<f64> {
+        let costs: Vec<f64> = self.turns.iter().filter_map(|t| t.cost(prices)).collect();
+        if costs.is_empty() {
+            None
+        } else {
+            Some(costs.iter().sum())
+        }
+    }
+}
+
+// Helper so we only print the cache usage when the prompt was cached
+struct Cache(u64, u64);
+
+impl Display for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 == 0 && self.1 == 0 {
+            return Ok(());
+        }
+        write!(f, " | cache: {} read / {} written", self.0, self.1)
+    }
+}
+
+// Helper so we can print the usage lines with or without a cost
+struct Cost(Option<f64>);
+
+impl Display for Cost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(cost) => write!(f, " | ~${:.4}", cost),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn print_turn_usage(
+    turn: &TurnRecord,
+    session: &SessionUsage,
+    prices: &HashMap<String, ModelPrice>,
+) {
+    println!(
+        "📊 | Tokens: {} in / {} out{} | {:.2}s{}",
+        turn.input_tokens,
+        turn.output_tokens,
+        Cache(turn.cache_read_tokens, turn.cache_write_tokens),
+        turn.latency_ms as f64 / 1000.0,
+        Cost(turn.cost(prices))
+    );
+    println!(
+        "📊 | Session: {} in / {} out{}{}",
+        session.input_tokens(),
+        session.output_tokens(),
+        Cache(session.cache_read_tokens(), session.cache_write_tokens()),
+        Cost(session.cost(prices))
+    );
+}
+
+pub fn print_session_usage(session: &SessionUsage, prices: &HashMap<String, ModelPrice>) {
+    if session.turns.is_empty() {
+        return;
+    }
+    println!(
+        "📊 | This session used {} input and {} output tokens over {} questions{}{}",
+        session.input_tokens(),
+        session.output_tokens(),
+        session.turns.len(),
+        Cache(session.cache_read_tokens(), session.cache_write_tokens()),
+        Cost(session.cost(prices))
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_bedrockruntime::types::{ConverseStreamMetrics, TokenUsage};
+
+    fn metadata(input: i32, output: i32, latency: i64) -> ConverseStreamMetadataEvent {
+        ConverseStreamMetadataEvent::builder()
+            .usage(
+                TokenUsage::builder()
+                    .input_tokens(input)
+                    .output_tokens(output)
+                    .total_tokens(input + output)
+                    .build()
+                    .unwrap(),
+            )
+            .metrics(
+                ConverseStreamMetrics::builder()
+                    .latency_ms(latency)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+    }
+
+    #[test]
+    fn turn_and_session_cost() {
+        let prices = HashMap::from([(
+            "anthropic.claude-3-haiku-20240307-v1:0".to_string(),
+            ModelPrice {
+                input_per_1k: 0.25,
+                output_per_1k: 1.25,
+                ..Default::default()
+            },
+        )]);
+
+        // two calls in the same turn (the model used a tool)
+        let mut turn = TurnRecord::new("us.anthropic.claude-3-haiku-20240307-v1:0");
+        turn.add_metadata(&metadata(1000, 200, 300));
+        turn.add_metadata(&metadata(1500, 200, 400));
+        assert_eq!(turn.input_tokens, 2500);
+        assert_eq!(turn.output_tokens, 400);
+        assert_eq!(turn.total_tokens(), 2900);
+        assert_eq!(turn.latency_ms, 700);
+        assert!((turn.cost(&prices).unwrap() - 1.125).abs() < 1e-9);
+
+        let mut session = SessionUsage::new();
+        session.add(turn.clone());
+        session.add(turn);
+        // no price for this one, it does not count towards the cost
+        let mut unknown = TurnRecord::new("some.other-model");
+        unknown.add_metadata(&metadata(10, 10, 10));
+        assert_eq!(unknown.cost(&prices), None);
+        session.add(unknown);
+
+        assert_eq!(session.input_tokens(), 5010);
+        assert_eq!(session.output_tokens(), 810);
+        assert!((session.cost(&prices).unwrap() - 2.25).abs() < 1e-9);
+        assert_eq!(SessionUsage::new().cost(&prices), None);
+    }
+
+    #[test]
+    fn prompt_cache_usage() {
+        let usage = ConverseStreamMetadataEvent::builder()
+            .usage(
+                TokenUsage::builder()
+                    .input_tokens(100)
+                    .output_tokens(100)
+                    .total_tokens(5200)
+                    .cache_read_input_tokens(4000)
+                    .cache_write_input_tokens(1000)
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+        let mut turn = TurnRecord::new("amazon.nova-pro-v1:0");
+        turn.add_metadata(&usage);
+        assert_eq!(turn.cache_read_tokens, 4000);
+        assert_eq!(turn.cache_write_tokens, 1000);
+        assert_eq!(turn.total_tokens(), 5200);
+
+        // without cache prices, cached tokens cost as much as any other input token
+        let mut prices = HashMap::from([(
+            "amazon.nova-pro-v1:0".to_string(),
+            ModelPrice {
+                input_per_1k: 1.0,
+                output_per_1k: 2.0,
+                ..Default::default()
+            },
+        )]);
+        assert!((turn.cost(&prices).unwrap() - 5.3).abs() < 1e-9);
+        prices
+            .get_mut("amazon.nova-pro-v1:0")
+            .unwrap()
+            .cache_read_per_1k = Some(0.1);
+        assert!((turn.cost(&prices).unwrap() - 1.7).abs() < 1e-9);
+
+        let mut session = SessionUsage::new();
+        session.add(turn.clone());
+        session.add(turn);
+        assert_eq!(session.cache_read_tokens(), 8000);
+        assert_eq!(session.cache_write_tokens(), 2000);
+    }
+}
@@ -1,18 +1,27 @@
 use anyhow::anyhow;
-use clap::{Parser, ValueEnum};
-use dialoguer::{theme::ColorfulTheme, FuzzySelect};
+use clap::Parser;
+use dialoguer::{theme::ColorfulTheme, FuzzySelect, Input};
 use figlet_rs::FIGfont;
 use ron::ser::PrettyConfig;
 
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Display, fs, path::PathBuf};
+use std::{borrow::Cow, collections::HashMap, fmt::Display, fs, path::PathBuf};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
 use colored::*;
 
 use dirs::home_dir;
 
+use crate::compaction::CompactionConfig;
 use crate::constants;
+use crate::guardrails::GuardrailConfig;
+use crate::image_generation::{parse_size, ImageGenerationConfig};
+use crate::models::catalog::{ModelCatalog, ModelChoice};
+use crate::models::context::ContextPolicy;
+use crate::models::params::ModelParams;
+use crate::models::retry::RetryPolicy;
+use crate::usage::ModelPrice;
 
 // ######################################## ARGUMENT PARSING
 #[derive(Parser)]
@@ -21,9 +30,13 @@ pub struct Args {
     #[arg(long, conflicts_with("model_id"))]
     pub init: bool,
 
-    #[clap(value_enum)]
+    /// Model id, inference profile ARN, or an alias (see `model_aliases` in the config)
     #[arg(short, long)]
-    pub model_id: Option<ArgModels>,
+    pub model_id: Option<String>,
+
+    /// Fetch the list of available models from Amazon Bedrock, even if it is cached
+    #[arg(long)]
+    pub refresh_models: bool,
 
     #[arg(short, long)]
     pub caption: Option<PathBuf>,
@@ -33,20 +46,153 @@ pub struct Args {
 
     #[arg(short)]
     pub xml: bool,
+
+    /// Generate images from this prompt (with Amazon Nova Canvas or Titan Image Generator)
+    #[arg(long, value_name = "PROMPT", conflicts_with_all = ["caption", "source", "compare"])]
+    pub generate_image: Option<String>,
+
+    /// What the generated images should not have
+    #[arg(long, value_name = "PROMPT", requires = "generate_image")]
+    pub negative_prompt: Option<String>,
+
+    /// Size of the generated images, ie 1280x720
+    #[arg(
+        long,
+        value_name = "WIDTHxHEIGHT",
+        value_parser = parse_size,
+        requires = "generate_image"
+    )]
+    pub size: Option<(u32, u32)>,
+
+    /// Seed for the generated images, the same seed and prompt make the same images
+    #[arg(long, requires = "generate_image")]
+    pub seed: Option<u32>,
+
+    /// How many images to generate (1 to 5)
+    #[arg(long, requires = "generate_image")]
+    pub count: Option<u32>,
+
+    /// Where the generated images (and their manifest.json) are saved
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Search the saved chats for the ones closest to this query
+    #[arg(long, value_name = "QUERY", conflicts_with_all = ["caption", "generate_image"])]
+    pub search_chats: Option<String>,
+
+    /// Run the prompts of a JSONL file (one JSON object per line), and write the answers as JSONL
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["caption", "source", "generate_image", "search_chats", "compare"]
+    )]
+    pub batch: Option<PathBuf>,
+
+    /// Where the batch results go (<FILE>.results.jsonl), prompts answered there are skipped
+    #[arg(long, value_name = "FILE", requires = "batch")]
+    pub batch_output: Option<PathBuf>,
+
+    /// How many batch prompts are asked at the same time
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "batch",
+        default_value_t = constants::DEFAULT_BATCH_CONCURRENCY
+    )]
+    pub concurrency: usize,
+
+    /// AWS region to use, instead of the one from the config or the AWS profile
+    #[arg(long)]
+    pub region: Option<String>,
+
+    /// Tokens the model can spend thinking before it answers (0 turns it off)
+    #[arg(long, value_name = "TOKENS")]
+    pub reasoning_budget: Option<i32>,
+
+    /// Also ask these models every question, and compare the answers (comma separated)
+    #[arg(long, value_name = "MODELS", value_delimiter = ',')]
+    pub compare: Vec<String>,
+
+    /// Show the compared answers next to each other, instead of one after the other
+    #[arg(long, requires = "compare")]
+    pub side_by_side: bool,
+
+    /// Attach a document (pdf, csv, docx, md, ...) to the first question, can be repeated
+    #[arg(long, value_name = "FILE", conflicts_with = "caption")]
+    pub attach: Vec<PathBuf>,
+
+    /// Print the answers as they are, without rendering the markdown (ie when piping the output)
+    #[arg(long)]
+    pub raw: bool,
+
+    /// Run against a scripted local backend (RON file with canned answers) instead of Amazon Bedrock
+    #[arg(long)]
+    pub script: Option<PathBuf>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct BedrustConfig {
     pub aws_profile: String,
+    // the AWS region, when not set the one from the AWS profile is used
+    #[serde(default)]
+    pub region: Option<String>,
+    // model id -> the region to call it in
+    #[serde(default)]
+    pub model_regions: HashMap<String, String>,
     pub supported_images: Vec<String>,
     pub caption_prompt: String,
-    pub default_model: Option<ArgModels>,
+    pub default_model: Option<String>,
+    // short names for model ids
+    #[serde(default)]
+    pub model_aliases: HashMap<String, String>,
     // FIX: Implement a better way for configuration defaults
     // for now if there is no configuration line use true
     #[serde(default = "_default_true")]
     pub show_banner: bool,
     pub inference_params: InferenceParams,
+    // overrides of the inference parameters, per provider or per model
+    #[serde(default)]
+    pub model_params: HashMap<String, ModelParams>,
     pub system_prompt: Option<String>,
+    #[serde(default = "_default_true")]
+    pub enable_tools: bool,
+    #[serde(default = "_default_true")]
+    pub prompt_caching: bool,
+    // tokens the model can spend thinking, for the models that support it
+    #[serde(default)]
+    pub reasoning_budget: Option<i32>,
+    #[serde(default = "_default_true")]
+    pub show_reasoning: bool,
+    // render the markdown in the answers (headers, lists, tables, highlighted code)
+    #[serde(default = "_default_true")]
+    pub render_markdown: bool,
+    // USD per 1000 tokens, used to estimate what a chat costs
+    #[serde(default)]
+    pub model_prices: HashMap<String, ModelPrice>,
+    // how calls to Bedrock are retried
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    // AWS profile -> the guardrail to use with it
+    #[serde(default)]
+    pub guardrails: HashMap<String, GuardrailConfig>,
+    // AWS profile -> the models to try, in order, when the chosen one can not answer
+    #[serde(default)]
+    pub fallback_models: HashMap<String, Vec<String>>,
+    // model id -> how many tokens fit in its context window, for the models we get wrong
+    #[serde(default)]
+    pub context_windows: HashMap<String, usize>,
+    // what to do when the conversation no longer fits in the context window
+    #[serde(default)]
+    pub context_policy: ContextPolicy,
+    // summarize the older messages once the conversation gets close to the context window
+    #[serde(default)]
+    pub compaction: Option<CompactionConfig>,
+    // the model that embeds the saved chats, to search them with `--search-chats` and `/r <query>`
+    #[serde(default = "_default_embedding_model")]
+    pub embedding_model: String,
+    // the model, size, count and output directory for `--generate-image` and `/imagine`
+    #[serde(default)]
+    pub image_generation: ImageGenerationConfig,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -56,6 +202,8 @@ pub struct InferenceParams {
     pub top_p: f32,
 }
 
+// NOTE: This used to be the list of models `--model-id` accepted. Any model id works now, these
+// names are kept around as built-in aliases (see `models::catalog`).
 #[derive(clap::ValueEnum, Clone, Serialize, Deserialize, Debug, Copy)]
 pub enum ArgModels {
     Llama270b,
@@ -126,6 +274,10 @@ impl ArgModels {
 const fn _default_true() -> bool {
     true
 }
+
+fn _default_embedding_model() -> String {
+    constants::DEFAULT_EMBEDDING_MODEL_ID.to_string()
+}
 // ######################################## END CONST FUNCTIONS
 
 pub fn hello_header(s: &str) -> Result<(), anyhow::Error> {
@@ -189,12 +341,23 @@ pub fn load_bedrust_config() -> Result<BedrustConfig, anyhow::Error> {
     let config_dir = home_dir.join(format!(".config/{}", constants::CONFIG_DIR_NAME));
     let bedrust_config_file_path = config_dir.join(constants::BEDRUST_CONFIG_FILE_NAME);
 
-    let file = fs::File::open(bedrust_config_file_path)?;
-    //let config: BedrustConfig = ron::de::from_reader(file)?;
-    let config: BedrustConfig = ron::de::from_reader(file)?;
+    let content = fs::read_to_string(bedrust_config_file_path)?;
+    let config: BedrustConfig = ron::de::from_str(&migrate_default_model(&content))?;
     Ok(config)
 }
 
+// Older configs store the default model as one of the `ArgModels` variants, ie
+// `default_model: Some(ClaudeV37Sonnet)`. We swap that for the model id it stands for.
+fn migrate_default_model(content: &str) -> Cow<'_, str> {
+    let legacy = Regex::new(r"default_model:\s*Some\(\s*([A-Za-z0-9]+)\s*\)").unwrap();
+    legacy.replace(content, |caps: &regex::Captures| {
+        match ron::de::from_str::<ArgModels>(&caps[1]) {
+            Ok(model) => format!("default_model: Some(\"{}\")", model.to_str()),
+            Err(_) => caps[0].to_string(),
+        }
+    })
+}
+
 pub fn print_warning(s: &str) {
     println!("{}", s.yellow());
 }
@@ -213,22 +376,46 @@ pub fn check_for_config() -> Result<bool, anyhow::Error> {
     }
 }
 
-pub fn prompt_for_model_selection() -> Result<ArgModels, anyhow::Error> {
-    let model_list = ArgModels::value_variants();
+// The last item of the model picker lets you type in any model id or ARN
+const ENTER_MODEL_ID: &str = "✏️  Enter a model ID or ARN";
+
+fn model_picker_items(choices: &[ModelChoice]) -> Vec<String> {
+    choices
+        .iter()
+        .map(|c| c.label.clone())
+        .chain([ENTER_MODEL_ID.to_string()])
+        .collect()
+}
+
+fn picked_model(choices: &[ModelChoice], idx: usize) -> Result<String, anyhow::Error> {
+    match choices.get(idx) {
+        Some(choice) => Ok(choice.id.clone()),
+        None => Ok(Input::<String>::with_theme(&ColorfulTheme::default())
+            .with_prompt("Model ID or ARN")
+            .interact_text()?
+            .trim()
+            .to_string()),
+    }
+}
+
+pub fn prompt_for_model_selection(choices: &[ModelChoice]) -> Result<String, anyhow::Error> {
+    let items = model_picker_items(choices);
     let idx = FuzzySelect::with_theme(&ColorfulTheme::default())
         .with_prompt("Select a model to use:")
-        .items(model_list)
+        .items(&items)
         .interact()?;
-    Ok(model_list[idx])
+    picked_model(choices, idx)
 }
 
-pub fn prompt_for_model_selection_opt() -> Result<Option<ArgModels>, anyhow::Error> {
-    let model_list = ArgModels::value_variants();
+pub fn prompt_for_model_selection_opt(
+    choices: &[ModelChoice],
+) -> Result<Option<String>, anyhow::Error> {
+    let items = model_picker_items(choices);
     let idx = FuzzySelect::with_theme(&ColorfulTheme::default())
         .with_prompt("Select a default model to use press <enter> to skip")
-        .items(model_list)
+        .items(&items)
         .interact_opt()?;
-    Ok(idx.map(|idx| model_list[idx]))
+    idx.map(|idx| picked_model(choices, idx)).transpose()
 }
 
 // function that creates the configuration files during the `init` command
@@ -242,7 +429,11 @@ pub fn initialize_config() -> Result<(), anyhow::Error> {
 
     let mut default_config: BedrustConfig =
         ron::de::from_str(&bedrust_config_content).expect("default config must be valid");
-    default_config.default_model = prompt_for_model_selection_opt()?;
+    // we have no AWS config at this point, so we offer the cached models (if any)
+    let choices = ModelCatalog::load_cached(&ModelCatalog::cache_path())
+        .unwrap_or_default()
+        .choices(&default_config.model_aliases);
+    default_config.default_model = prompt_for_model_selection_opt(&choices)?;
 
     fs::write(
         &bedrust_config_file_path,
@@ -265,3 +456,64 @@ pub fn initialize_config() -> Result<(), anyhow::Error> {
     println!("✅ | Bedrust configuration has been initialized in ~/.config/bedrust. You may now use it as normal.");
     Ok(())
 }
+
+// A fresh directory for a test, in the temp directory of the OS
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bedrust-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_template_and_legacy_default_model() {
+        let config: BedrustConfig = ron::de::from_str(constants::BEDRUST_CONFIG_FILE).unwrap();
+        assert_eq!(config.default_model, None);
+        assert!(config.model_aliases.contains_key("sonnet"));
+
+        let legacy = constants::BEDRUST_CONFIG_FILE.replace(
+            "  show_banner: true,",
+            "  show_banner: true,\n  default_model: Some(NovaPro),",
+        );
+        let config: BedrustConfig = ron::de::from_str(&migrate_default_model(&legacy)).unwrap();
+        assert_eq!(
+            config.default_model.as_deref(),
+            Some("us.amazon.nova-pro-v1:0")
+        );
+
+        let current = constants::BEDRUST_CONFIG_FILE.replace(
+            "  show_banner: true,",
+            "  show_banner: true,\n  default_model: Some(\"eu.amazon.nova-pro-v1:0\"),",
+        );
+        let config: BedrustConfig = ron::de::from_str(&migrate_default_model(&current)).unwrap();
+        assert_eq!(
+            config.default_model.as_deref(),
+            Some("eu.amazon.nova-pro-v1:0")
+        );
+
+        // a partial retry policy takes the rest from the defaults
+        let retry_start = constants::BEDRUST_CONFIG_FILE.find("  retry: (").unwrap();
+        let retry_end = retry_start
+            + constants::BEDRUST_CONFIG_FILE[retry_start..]
+                .find("),")
+                .unwrap();
+        let partial = format!(
+            "{}  retry: (max_attempts: 2{}",
+            &constants::BEDRUST_CONFIG_FILE[..retry_start],
+            &constants::BEDRUST_CONFIG_FILE[retry_end..]
+        );
+        let config: BedrustConfig = ron::de::from_str(&partial).unwrap();
+        assert_eq!(
+            config.retry,
+            RetryPolicy {
+                max_attempts: 2,
+                ..RetryPolicy::default()
+            }
+        );
+    }
+}